@@ -0,0 +1,33 @@
+use quantaxis_rs::qaaccount::QA_Account;
+use quantaxis_rs::qaactor::{AccountEvent, QAAccountActor};
+
+#[tokio::main]
+async fn main() {
+    let code = "RB2005".to_string();
+    let acc = QA_Account::new("RustT01B2_RBL8", "test", "admin", 100000.0, false, "backtest");
+    let (mut handle, join) = QAAccountActor::spawn(acc, 1024);
+
+    // 日志/风控各自订阅事件
+    let mut logger = handle.subscribe();
+    let log_task = tokio::spawn(async move {
+        while let Ok(event) = logger.recv().await {
+            match event {
+                AccountEvent::Trade(trade) => println!("成交 {:#?}", trade),
+                AccountEvent::Account(account) => println!("权益 {:#?}", account.balance),
+                _ => {}
+            }
+        }
+    });
+
+    let order = handle
+        .send_order(&code, 10.0, "2020-01-20 22:10:00", 2, 3500.0, "")
+        .await;
+    println!("{:#?}", order.unwrap());
+    handle.on_price_change(&code, 3520.0, "2020-01-20 22:11:00").await.unwrap();
+    handle.stop().await.unwrap();
+
+    let acc = join.await.unwrap();
+    drop(handle);
+    log_task.await.unwrap();
+    println!("{:#?}", acc.money);
+}
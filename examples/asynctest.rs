@@ -21,7 +21,7 @@ async fn main() {
 
 
 
-    let order = acc.send_order_async(&code, 10.0, "2020-01-20 22:10:00", 2, 3500.0, "BUY_OPEN").await;
+    let order = acc.send_order_async(&code, 10.0, "2020-01-20 22:10:00", 2, 3500.0, "").await;
     println!("{:#?}", order.unwrap());
 
 
@@ -23,7 +23,7 @@ async fn main() {
     let mut ac1= ac.clone();
     let join  = task::spawn(async move {
         let mut acc_mut = ac1.lock().unwrap();
-        let order = acc_mut.send_order_async(&code, 10.0, "2020-01-20 22:10:00", 2, 3500.0, "BUY_OPEN");
+        let order = acc_mut.send_order_async(&code, 10.0, "2020-01-20 22:10:00", 2, 3500.0, "");
         // ac1.as_ref().borrow_mut().get_mut().unwrap().
         println!("下单完成");
 
@@ -7,11 +7,29 @@ pub use crate::traits::*;
 pub mod indicators;
 pub mod market_preset;
 pub mod qaaccount;
+pub mod qaactor;
+pub mod qaalgo;
+pub mod qaattribution;
+pub mod qaallocator;
+pub mod qacapacity;
+pub mod qaconditional;
+pub mod qaexecution;
+pub mod qagateway;
 pub mod qadata;
+pub mod qafeed;
 pub mod qafetch;
+pub mod qaid;
 pub mod qaindicator;
+pub mod qamontecarlo;
+pub mod qaoptimizer;
 pub mod qaorder;
 pub mod qaposition;
+pub mod qaprotocol;
+pub mod qareport;
+pub mod qarisk;
+pub mod qaroll;
+pub mod qarolling;
+pub mod qawalkforward;
 pub mod transaction;
 pub mod qaperformance;
 pub mod trade_date;
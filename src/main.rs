@@ -14,15 +14,7 @@ use ndarray::prelude::*;
 use serde_json;
 use stopwatch::Stopwatch;
 
-pub mod market_preset;
-pub mod qaaccount;
-pub mod qadata;
-pub mod qafetch;
-pub mod qaindicator;
-pub mod qaorder;
-pub mod qaposition;
-pub mod transaction;
-pub mod trade_date;
+use quantaxis_rs::qafetch;
 
 pub struct QABacktest {}
 
@@ -49,6 +49,15 @@ impl CodePreset {
         self.buy_frozen_coeff.clone() * self.unit_table.clone() as f64
     }
 
+    /// 最小下单数量, 股票一手 100 股, 期货一手
+    pub fn lot_size(&self) -> f64 {
+        if self.exchange == "STOCK" {
+            100.0
+        } else {
+            1.0
+        }
+    }
+
     pub fn print(&mut self) {
         println!(
             "name {} / buy_frozen {} / sell_frozen {}",
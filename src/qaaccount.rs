@@ -2,6 +2,7 @@ use std::borrow::Borrow;
 use std::collections::{BTreeMap, HashMap};
 use std::error::Error;
 use std::io;
+use std::sync::{Arc, Mutex};
 
 use chrono::format::ParseError;
 use chrono::{DateTime, FixedOffset, Local, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
@@ -9,13 +10,16 @@ use csv;
 use qifi_rs::{Account, Order, Position, Trade, QIFI};
 use serde::{Deserialize, Serialize};
 use serde_json::to_string;
-use uuid::v1::{Context, Timestamp};
-use uuid::Uuid;
 use log::{info,error,warn};
 use crate::market_preset::{CodePreset, MarketPreset};
+use crate::qaconditional::{QAConditionalBook, QAConditionalKind, QAConditionalOrder, QATrailDistance};
+use crate::qafetch::BAR;
+use crate::qagateway::{Gateway, GatewayEvent};
+use crate::qaid::QAIdGenerator;
 use crate::qaorder::QAOrder;
 use crate::qaposition;
 use crate::qaposition::{QA_Frozen, QA_Postions};
+use crate::qaprotocol::{merge_patch, RtnData, RtnDataItem, TradeDiff};
 use crate::trade_date::QATradeDate;
 use crate::transaction;
 use crate::transaction::QATransaction;
@@ -132,6 +136,12 @@ pub struct QA_Account {
     commission_ratio: f64,
     // 手续费率
     tax_ratio: f64,        // tax for qaaccount
+    gateway: Option<Arc<Mutex<dyn Gateway>>>,
+    // 实盘未完成委托
+    live_orders: HashMap<String, QAOrder>,
+    id_generator: QAIdGenerator,
+    // 条件单
+    pub conditional: QAConditionalBook,
 }
 
 impl QA_Account {
@@ -190,6 +200,10 @@ impl QA_Account {
             event_id: 0,
             commission_ratio: 0.00025,
             tax_ratio: 0.001, // only in stock model
+            gateway: None,
+            live_orders: HashMap::new(),
+            id_generator: QAIdGenerator::sequential(account_cookie),
+            conditional: QAConditionalBook::new(),
         };
 
         if auto_reload {
@@ -313,10 +327,42 @@ impl QA_Account {
             event_id: 0,
             commission_ratio: 0.00025,
             tax_ratio: 0.001, // only in stock model
+            gateway: None,
+            live_orders: HashMap::new(),
+            id_generator: QAIdGenerator::sequential(message.account_cookie.as_ref()),
+            conditional: QAConditionalBook::new(),
         };
         acc
     }
 
+    /// 设置实盘交易网关, 设置后 real 模式的订单通过网关报出, 成交由回报驱动
+    pub fn set_gateway(&mut self, gateway: Arc<Mutex<dyn Gateway>>) {
+        self.gateway = Some(gateway);
+    }
+
+    /// 设置订单号/成交号生成器, 默认为以 account_cookie 为前缀的顺序编号
+    pub fn set_id_generator(&mut self, id_generator: QAIdGenerator) {
+        self.id_generator = id_generator;
+    }
+
+    fn next_order_id(&mut self, datetime: &str) -> String {
+        loop {
+            let order_id = self.id_generator.next_order_id(datetime);
+            if !self.dailyorders.contains_key(&order_id) && !self.frozen.contains_key(&order_id) {
+                return order_id;
+            }
+        }
+    }
+
+    fn next_trade_id(&mut self, datetime: &str) -> String {
+        loop {
+            let trade_id = self.id_generator.next_trade_id(datetime);
+            if !self.dailytrades.contains_key(&trade_id) && !self.trades.contains_key(&trade_id) {
+                return trade_id;
+            }
+        }
+    }
+
     pub fn init_h(&mut self, code: &str) {
         let code: String = code.parse().unwrap();
         self.hold.insert(
@@ -403,6 +449,141 @@ impl QA_Account {
             risk_ratio: self.get_riskratio(),
         }
     }
+    /// 用 QIFI Account 覆盖账户资金字段
+    pub fn set_accountmessage(&mut self, message: Account) {
+        self.accounts = account {
+            user_id: message.user_id.clone(),
+            currency: message.currency.clone(),
+            pre_balance: message.pre_balance,
+            deposit: message.deposit,
+            withdraw: message.withdraw,
+            WithdrawQuota: message.WithdrawQuota,
+            close_profit: message.close_profit,
+            commission: message.commission as f64,
+            premium: message.premium as f64,
+            static_balance: message.static_balance,
+            position_profit: message.position_profit,
+            float_profit: message.float_profit,
+            balance: message.balance,
+            margin: message.margin,
+            frozen_margin: message.frozen_margin,
+            frozen_commission: message.frozen_commission,
+            frozen_premium: message.frozen_premium,
+            available: message.available,
+            risk_ratio: message.risk_ratio,
+        };
+        self.money = message.available;
+    }
+
+    /// 以 rtn_data 全量快照的形式输出账户 (otg 协议)
+    pub fn get_rtn_data(&mut self) -> RtnData {
+        let mut diff = TradeDiff {
+            user_id: Some(self.account_cookie.clone()),
+            ..TradeDiff::default()
+        };
+        diff.accounts.insert(
+            "CNY".to_string(),
+            serde_json::to_value(self.get_accountmessage()).unwrap(),
+        );
+        for pos in self.hold.values_mut() {
+            diff.positions.insert(
+                pos.instrument_id.clone(),
+                serde_json::to_value(pos.get_qifi_position()).unwrap(),
+            );
+        }
+        for (order_id, order) in self.dailyorders.iter() {
+            diff.orders
+                .insert(order_id.clone(), serde_json::to_value(order).unwrap());
+        }
+        for (trade_id, trade) in self.dailytrades.iter() {
+            diff.trades
+                .insert(trade_id.clone(), serde_json::to_value(trade).unwrap());
+        }
+        let mut item = RtnDataItem::default();
+        item.trade.insert(self.account_cookie.clone(), diff);
+        RtnData::new(vec![item])
+    }
+
+    /// 应用 rtn_data 增量数据, 只处理本账户的部分
+    pub fn apply_rtn_data(&mut self, rtn: &RtnData) {
+        for item in rtn.data.iter() {
+            for (user_id, diff) in item.trade.iter() {
+                if user_id != &self.account_cookie {
+                    continue;
+                }
+                for patch in diff.accounts.values() {
+                    let mut value = serde_json::to_value(self.get_accountmessage()).unwrap();
+                    merge_patch(&mut value, patch);
+                    match serde_json::from_value::<Account>(value) {
+                        Ok(message) => self.set_accountmessage(message),
+                        Err(e) => error!("BAD ACCOUNT PATCH {}", e),
+                    }
+                }
+                for (key, patch) in diff.positions.iter() {
+                    // otg 的持仓 key 为 EXCHANGE.code
+                    let code = key.split('.').next_back().unwrap().to_string();
+                    if patch.is_null() {
+                        self.hold.remove(&code);
+                        continue;
+                    }
+                    // 在原持仓上合并, 未出现在 patch 中的字段 (冻结/保证金/成本) 保持不变
+                    let mut pos = match self.hold.get(&code) {
+                        Some(pos) => pos.clone(),
+                        None => QA_Postions::new(
+                            code.clone(),
+                            self.account_cookie.clone(),
+                            self.account_cookie.clone(),
+                            self.account_cookie.clone(),
+                            self.portfolio_cookie.clone(),
+                        ),
+                    };
+                    let mut value = serde_json::to_value(pos.get_qifi_position()).unwrap();
+                    merge_patch(&mut value, patch);
+                    match serde_json::from_value::<Position>(value) {
+                        Ok(p) => {
+                            pos.apply_qifi_position(&p);
+                            if p.last_price > 0.0 {
+                                pos.on_price_change(p.last_price, self.time.clone());
+                            }
+                            self.hold.insert(code, pos);
+                        }
+                        Err(e) => error!("BAD POSITION PATCH {}", e),
+                    }
+                }
+                for (order_id, patch) in diff.orders.iter() {
+                    if patch.is_null() {
+                        self.dailyorders.remove(order_id);
+                        continue;
+                    }
+                    let current = self.dailyorders.get(order_id).cloned().unwrap_or_default();
+                    let mut value = serde_json::to_value(current).unwrap();
+                    merge_patch(&mut value, patch);
+                    match serde_json::from_value::<Order>(value) {
+                        Ok(order) => {
+                            self.dailyorders.insert(order_id.clone(), order);
+                        }
+                        Err(e) => error!("BAD ORDER PATCH {}", e),
+                    }
+                }
+                for (trade_id, patch) in diff.trades.iter() {
+                    if patch.is_null() {
+                        self.dailytrades.remove(trade_id);
+                        continue;
+                    }
+                    let current = self.dailytrades.get(trade_id).cloned().unwrap_or_default();
+                    let mut value = serde_json::to_value(current).unwrap();
+                    merge_patch(&mut value, patch);
+                    match serde_json::from_value::<Trade>(value) {
+                        Ok(trade) => {
+                            self.dailytrades.insert(trade_id.clone(), trade);
+                        }
+                        Err(e) => error!("BAD TRADE PATCH {}", e),
+                    }
+                }
+            }
+        }
+    }
+
     /// positions about
     ///
     /// a fast way to get the realtime price/cost/volume/history
@@ -463,11 +644,38 @@ impl QA_Account {
             + self.accounts.close_profit
     }
 
+    /// cash transfer about
+    ///
+    /// 入金: 资金直接进入可用, 并记入当日 deposit
+    pub fn deposit(&mut self, amount: f64) {
+        self.money += amount;
+        self.accounts.deposit += amount;
+        self.cash.push(self.money);
+    }
+
+    /// 出金: 只能转出当前可用资金
+    pub fn withdraw(&mut self, amount: f64) -> Result<f64, ()> {
+        if amount <= self.money {
+            self.money -= amount;
+            self.accounts.withdraw += amount;
+            self.cash.push(self.money);
+            Ok(amount)
+        } else {
+            warn!("可用资金不足,当前可用money {:#?}, 需要出金 {:#?}", self.money, amount);
+            Err(())
+        }
+    }
+
     pub async fn settle_async(&mut self) {
         self.settle();
     }
 
     pub fn settle(&mut self) {
+        self.accounts.position_profit = self.get_positionprofit();
+        self.accounts.float_profit = self.get_floatprofit();
+        self.accounts.balance = self.get_balance();
+        self.accounts.margin = self.get_margin();
+        self.accounts.available = self.money;
         self.dailyassets.insert(
             self.time.clone(),
             QAAccountSlice {
@@ -490,8 +698,11 @@ impl QA_Account {
             pos.settle();
         }
         // init the next day cash
-        let balance_settle =
-            self.accounts.pre_balance + self.accounts.close_profit - self.accounts.commission;
+        let balance_settle = self.accounts.pre_balance
+            + self.accounts.deposit
+            - self.accounts.withdraw
+            + self.accounts.close_profit
+            - self.accounts.commission;
         self.accounts = account {
             user_id: self.account_cookie.to_string(),
             currency: "CNY".to_string(),
@@ -574,30 +785,140 @@ impl QA_Account {
     /// order about
     /// buy| sell| buy_open| sell_open| buy_close| sell_close|
     /// send_order
+    ///
+    /// send_order 的 order_id 为空时由 id_generator 生成, 否则使用传入的订单号
     pub fn buy(&mut self, code: &str, amount: f64, time: &str, price: f64) -> Result<QAOrder, ()> {
-        self.send_order(code, amount, time, 1, price, "BUY")
+        self.send_order(code, amount, time, 1, price, "")
     }
     pub fn sell(&mut self, code: &str, amount: f64, time: &str, price: f64) -> Result<QAOrder, ()> {
-        self.send_order(code, amount, time, -1, price, "SELL")
+        self.send_order(code, amount, time, -1, price, "")
     }
     pub fn buy_open(&mut self, code: &str, amount: f64, time: &str, price: f64) -> Result<QAOrder, ()> {
-        self.send_order(code, amount, time, 2, price, "BUY_OPEN")
+        self.send_order(code, amount, time, 2, price, "")
     }
     pub fn sell_open(&mut self, code: &str, amount: f64, time: &str, price: f64) -> Result<QAOrder, ()> {
-        self.send_order(code, amount, time, -2, price, "SELL_OPEN")
+        self.send_order(code, amount, time, -2, price, "")
     }
     pub fn buy_close(&mut self, code: &str, amount: f64, time: &str, price: f64) -> Result<QAOrder, ()> {
-        self.send_order(code, amount, time, 3, price, "BUY_CLOSE")
+        self.send_order(code, amount, time, 3, price, "")
     }
     pub fn sell_close(&mut self, code: &str, amount: f64, time: &str, price: f64) -> Result<QAOrder, ()> {
-        self.send_order(code, amount, time, -3, price, "SELL_CLOSE")
+        self.send_order(code, amount, time, -3, price, "")
     }
     pub fn buy_closetoday(&mut self, code: &str, amount: f64, time: &str, price: f64) -> Result<QAOrder, ()> {
-        self.send_order(code, amount, time, 4, price, "BUY_CLOSETODAY")
+        self.send_order(code, amount, time, 4, price, "")
     }
     pub fn sell_closetoday(&mut self, code: &str, amount: f64, time: &str, price: f64) -> Result<QAOrder, ()> {
-        self.send_order(code, amount, time, -4, price, "SELL_CLOSETODAY")
+        self.send_order(code, amount, time, -4, price, "")
     }
+    /// 调整到目标净持仓, 正数为多头, 负数为空头, 按 lot_size 向零取整
+    ///
+    /// 先平反向仓位, 平仓时先平昨再平今, 股票只能卖出昨仓且不能做空;
+    /// 返回已发出的订单, 任一订单被拒绝时返回 Err, 此前的订单已经发出
+    pub fn order_target_volume(
+        &mut self,
+        code: &str,
+        target: f64,
+        time: &str,
+        price: f64,
+    ) -> Result<Vec<QAOrder>, ()> {
+        if !self.hold.contains_key(code) {
+            self.init_h(code);
+        }
+        let preset = self.market_preset.get(code);
+        let lot = preset.lot_size();
+        let target = (target / lot).trunc() * lot;
+        let is_stock = preset.exchange == "STOCK";
+        let pos = self.get_position(code).unwrap();
+        let (long_his, long_today) = (pos.volume_long_his, pos.volume_long_today);
+        let (short_his, short_today) = (pos.volume_short_his, pos.volume_short_today);
+
+        // (towards, amount) in the order they are sent
+        let mut plan: Vec<(i32, f64)> = vec![];
+        if is_stock {
+            if target < 0.0 {
+                warn!("{} 股票不能做空", code);
+                return Err(());
+            }
+            let diff = target - (long_his + long_today);
+            if diff > 0.0 {
+                plan.push((1, (diff / lot).floor() * lot));
+            } else if diff < 0.0 {
+                // T+1, only the volume bought before today can be sold
+                let sell = if target == 0.0 {
+                    long_his
+                } else {
+                    ((-diff).min(long_his) / lot).floor() * lot
+                };
+                plan.push((-1, sell));
+            }
+        } else {
+            let long = long_his + long_today;
+            let short = short_his + short_today;
+            // close his before today, reduce long (-3, -4) or short (3, 4)
+            let close = |plan: &mut Vec<(i32, f64)>, sign: i32, his: f64, today: f64, amount: f64| {
+                let from_his = amount.min(his);
+                plan.push((3 * sign, from_his));
+                plan.push((4 * sign, (amount - from_his).min(today)));
+            };
+            if target <= 0.0 && long > 0.0 {
+                close(&mut plan, -1, long_his, long_today, long);
+            }
+            if target >= 0.0 && short > 0.0 {
+                close(&mut plan, 1, short_his, short_today, short);
+            }
+            if target > 0.0 {
+                if target > long {
+                    plan.push((2, target - long));
+                } else if target < long {
+                    close(&mut plan, -1, long_his, long_today, long - target);
+                }
+            } else if target < 0.0 {
+                if -target > short {
+                    plan.push((-2, -target - short));
+                } else if -target < short {
+                    close(&mut plan, 1, short_his, short_today, short + target);
+                }
+            }
+        }
+
+        let mut orders = vec![];
+        for (towards, amount) in plan {
+            if amount <= 0.0 {
+                continue;
+            }
+            orders.push(self.send_order(code, amount, time, towards, price, "")?);
+        }
+        Ok(orders)
+    }
+
+    /// 目标市值, volume = value / (price * unit_table)
+    pub fn order_target_value(
+        &mut self,
+        code: &str,
+        value: f64,
+        time: &str,
+        price: f64,
+    ) -> Result<Vec<QAOrder>, ()> {
+        let unit = self.market_preset.get(code).unit_table as f64;
+        if price <= 0.0 {
+            return Err(());
+        }
+        self.order_target_volume(code, value / (price * unit), time, price)
+    }
+
+    /// 目标市值占当前权益的比例, 负数为空头
+    pub fn order_target_percent(
+        &mut self,
+        code: &str,
+        percent: f64,
+        time: &str,
+        price: f64,
+    ) -> Result<Vec<QAOrder>, ()> {
+        let value = self.get_balance() * percent;
+        self.order_target_value(code, value, time, price)
+    }
+
     pub fn get_tradingday(&mut self) -> String {
         let mut u = QATradeDate::new();
         u.get_trade_day(self.time.clone())
@@ -620,8 +941,12 @@ impl QA_Account {
         match towards {
             3 => {
                 if (qapos.volume_short() - qapos.volume_short_frozen()) >= amount {
-                    qapos.volume_short_frozen_today += amount;
-                    qapos.volume_short_today -= amount;
+                    // 先平昨仓
+                    let his = amount.min(qapos.volume_short_his).max(0.0);
+                    qapos.volume_short_frozen_his += his;
+                    qapos.volume_short_his -= his;
+                    qapos.volume_short_frozen_today += amount - his;
+                    qapos.volume_short_today -= amount - his;
                     res = true;
                 } else {
                     warn!("仓位不足");
@@ -649,8 +974,12 @@ impl QA_Account {
 
             -3 => {
                 if (qapos.volume_long() - qapos.volume_long_frozen()) >= amount {
-                    qapos.volume_long_frozen_today += amount;
-                    qapos.volume_long_today -= amount;
+                    // 先平昨仓
+                    let his = amount.min(qapos.volume_long_his).max(0.0);
+                    qapos.volume_long_frozen_his += his;
+                    qapos.volume_long_his -= his;
+                    qapos.volume_long_frozen_today += amount - his;
+                    qapos.volume_long_today -= amount - his;
                     res = true;
                 } else {
                     warn!("SELL CLOSE 仓位不足");
@@ -730,14 +1059,20 @@ impl QA_Account {
         };
         let datetime = datetimer.as_str();
 
-        let context = Context::new(self.event_id as u16);
-        let ts = Timestamp::from_unix(&context, 1497624119, 1234);
-        let uuid = Uuid::new_v1(ts, &[1, 2, 3, 4, 5, 6]).expect("failed to generate UUID");
-
-        let order_id: String = uuid.to_string();
+        let order_id: String = if order_id.is_empty() {
+            self.next_order_id(datetime)
+        } else if self.dailyorders.contains_key(order_id)
+            || self.frozen.contains_key(order_id)
+            || self.live_orders.contains_key(order_id)
+        {
+            warn!("订单号重复 {}", order_id);
+            return Err(());
+        } else {
+            order_id.to_string()
+        };
 
         if self.order_check(code, amount, price, towards, order_id.clone()) {
-            let order = QAOrder::new(
+            let mut order = QAOrder::new(
                 self.account_cookie.clone(),
                 code.clone().to_string(),
                 towards,
@@ -747,21 +1082,24 @@ impl QA_Account {
                 price,
                 order_id.clone(),
             );
+            order.exchange_order_id = self.id_generator.next_exchange_order_id(datetime);
             match self.environment.as_ref() {
                 "backtest" => {
+                    let trade_id = self.next_trade_id(datetime);
                     self.receive_deal(
                         code.parse().unwrap(),
                         amount,
                         price,
                         datetime.parse().unwrap(),
                         order_id.clone(),
-                        order_id.clone(),
-                        order_id.clone(),
+                        trade_id,
+                        order.exchange_order_id.clone(),
                         towards,
                     );
                 }
                 "real" => {
                     let (direction, offset) = self.get_direction_or_offset(towards);
+                    let alive = self.gateway.is_some();
                     self.dailyorders.insert(
                         order_id.clone(),
                         Order {
@@ -782,24 +1120,38 @@ impl QA_Account {
                                 .unwrap()
                                 .timestamp_nanos()
                                 - 28800000000000,
-                            exchange_order_id: Uuid::new_v4().to_string(),
-                            status: "FINISHED".to_string(),
-                            volume_left: 0.0,
+                            exchange_order_id: order.exchange_order_id.clone(),
+                            status: if alive { "ALIVE" } else { "FINISHED" }.to_string(),
+                            volume_left: if alive { amount } else { 0.0 },
                             last_msg: "".to_string(),
                         },
                     );
 
-                    self.receive_deal_real(
-                        code.parse().unwrap(),
-                        amount,
-                        price,
-                        datetime.parse().unwrap(),
-                        order_id.clone(),
-                        order_id.clone(),
-                        order_id.clone(),
-                        towards,
-                        self.event_id.clone(),
-                    )
+                    if let Some(gateway) = self.gateway.clone() {
+                        self.live_orders.insert(order_id.clone(), order.clone());
+                        let res = gateway.lock().unwrap().submit(&order);
+                        self.sync_gateway();
+                        if let Err(reason) = res {
+                            warn!("ORDER REJECTED {} {}", order_id, reason);
+                            if let Some(order) = self.live_orders.remove(&order_id) {
+                                self.release_order(&order, reason.as_str());
+                            }
+                            return Err(());
+                        }
+                    } else {
+                        let trade_id = self.next_trade_id(datetime);
+                        self.receive_deal_real(
+                            code.parse().unwrap(),
+                            amount,
+                            price,
+                            datetime.parse().unwrap(),
+                            order_id.clone(),
+                            trade_id,
+                            order.exchange_order_id.clone(),
+                            towards,
+                            self.event_id.clone(),
+                        )
+                    }
                     // self.events.insert(self.datetime.clone(), "order insert".to_string());
                 }
                 _ => {
@@ -815,10 +1167,257 @@ impl QA_Account {
 
 
     pub fn on_price_change(&mut self, code: String, price: f64, datetime: String) {
+        self.mark_price(&code, price, &datetime);
+        let triggered = self.conditional.on_price(code.as_ref(), price, datetime.as_ref());
+        self.send_conditional(triggered, datetime.as_ref());
+    }
+
+    fn mark_price(&mut self, code: &str, price: f64, datetime: &str) {
         // 当行情变化时候 要更新计算持仓
-        let pos = self.get_position(code.as_ref()).unwrap();
-        pos.on_price_change(price, datetime.clone());
-        self.change_datetime(datetime);
+        let pos = self.get_position(code).unwrap();
+        pos.on_price_change(price, datetime.to_string());
+        self.change_datetime(datetime.to_string());
+        if let Some(gateway) = self.gateway.clone() {
+            gateway.lock().unwrap().on_price_change(code, price, datetime);
+            self.sync_gateway();
+        }
+    }
+
+    /// 用 bar 的最高/最低价检查条件单, 再按收盘价更新持仓
+    ///
+    /// the close is not checked again, the bar's high/low already covered it
+    pub fn on_bar(&mut self, bar: &BAR) {
+        self.change_datetime(bar.datetime.clone());
+        let triggered = self.conditional.on_bar(bar);
+        self.send_conditional(triggered, bar.datetime.as_ref());
+        if self.get_position(bar.code.as_ref()).is_some() {
+            self.mark_price(&bar.code, bar.close, &bar.datetime);
+        }
+    }
+
+    fn send_conditional(&mut self, triggered: Vec<QAConditionalOrder>, datetime: &str) {
+        for order in triggered {
+            let res = self.send_order(
+                order.code.as_ref(),
+                order.amount,
+                datetime,
+                order.towards,
+                order.fill_price,
+                "",
+            );
+            self.conditional.finish(order, res.is_ok());
+        }
+    }
+
+    /// 止损单, towards 为触发后报出的方向, 如 -3 保护多头
+    pub fn stop_market(&mut self, code: &str, towards: i32, amount: f64, trigger: f64) -> String {
+        let time = self.time.clone();
+        self.conditional
+            .place(code, towards, amount, QAConditionalKind::StopMarket { trigger }, &time)
+    }
+
+    pub fn stop_limit(&mut self, code: &str, towards: i32, amount: f64, trigger: f64, limit: f64) -> String {
+        let time = self.time.clone();
+        self.conditional.place(
+            code,
+            towards,
+            amount,
+            QAConditionalKind::StopLimit { trigger, limit },
+            &time,
+        )
+    }
+
+    pub fn take_profit(&mut self, code: &str, towards: i32, amount: f64, trigger: f64) -> String {
+        let time = self.time.clone();
+        self.conditional
+            .place(code, towards, amount, QAConditionalKind::TakeProfit { trigger }, &time)
+    }
+
+    /// 跟踪止损, 按跳数时使用品种的 price_tick
+    pub fn trailing_stop(
+        &mut self,
+        code: &str,
+        towards: i32,
+        amount: f64,
+        distance: QATrailDistance,
+        activation: Option<f64>,
+    ) -> String {
+        let time = self.time.clone();
+        let price_tick = self.market_preset.get(code).price_tick;
+        self.conditional.place(
+            code,
+            towards,
+            amount,
+            QAConditionalKind::TrailingStop {
+                distance,
+                activation,
+                price_tick,
+                best: None,
+            },
+            &time,
+        )
+    }
+
+    /// 对当前持仓挂 OCO 止损止盈, 返回 (止损单号, 止盈单号), 无持仓时返回 Err
+    pub fn bracket(&mut self, code: &str, stop: f64, take_profit: f64) -> Result<(String, String), ()> {
+        if self.get_position(code).is_none() {
+            return Err(());
+        }
+        let is_stock = self.market_preset.get(code).exchange == "STOCK";
+        let long = self.get_volume_long(code);
+        let short = self.get_volume_short(code);
+        let (towards, amount) = if long > 0.0 {
+            (if is_stock { -1 } else { -3 }, long)
+        } else if short > 0.0 {
+            (3, short)
+        } else {
+            return Err(());
+        };
+        let stop_id = self.stop_market(code, towards, amount, stop);
+        let take_id = self.take_profit(code, towards, amount, take_profit);
+        self.conditional.link_oco(&[stop_id.clone(), take_id.clone()]);
+        Ok((stop_id, take_id))
+    }
+
+    pub fn cancel_conditional(&mut self, id: &str) -> Result<(), ()> {
+        self.conditional.cancel(id).map(|_| ()).ok_or(())
+    }
+
+    /// 撤单, 仅对通过网关报出的订单有效
+    pub fn cancel_order(&mut self, order_id: &str) -> Result<(), ()> {
+        match self.gateway.clone() {
+            Some(gateway) => {
+                let res = gateway.lock().unwrap().cancel(order_id);
+                self.sync_gateway();
+                res.map_err(|reason| warn!("CANCEL FAILED {} {}", order_id, reason))
+            }
+            None => Err(()),
+        }
+    }
+
+    /// 拉取网关回报并更新订单/成交/持仓
+    pub fn sync_gateway(&mut self) {
+        let events = match &self.gateway {
+            Some(gateway) => gateway.lock().unwrap().poll(),
+            None => return,
+        };
+        for event in events {
+            self.on_gateway_event(event);
+        }
+    }
+
+    fn on_gateway_event(&mut self, event: GatewayEvent) {
+        match event {
+            GatewayEvent::Accepted {
+                order_id,
+                exchange_order_id,
+                ..
+            } => {
+                if let Some(order) = self.dailyorders.get_mut(&order_id) {
+                    order.exchange_order_id = exchange_order_id;
+                }
+            }
+            GatewayEvent::Filled {
+                order_id,
+                trade_id,
+                price,
+                volume,
+                datetime,
+            } => {
+                let (code, towards, realorder_id, finished) = match self.live_orders.get_mut(&order_id) {
+                    Some(order) => {
+                        order.volume_left -= volume;
+                        (
+                            order.instrument_id.clone(),
+                            order.towards,
+                            order.exchange_order_id.clone(),
+                            order.volume_left <= 0.0,
+                        )
+                    }
+                    None => {
+                        error!("NOT IN LIVE ORDER {}", order_id);
+                        return;
+                    }
+                };
+                if finished {
+                    self.live_orders.remove(&order_id);
+                }
+                if let Some(order) = self.dailyorders.get_mut(&order_id) {
+                    order.volume_left -= volume;
+                    if finished {
+                        order.status = "FINISHED".to_string();
+                    }
+                }
+                self.event_id += 1;
+                self.receive_deal_real(
+                    code,
+                    volume,
+                    price,
+                    datetime,
+                    order_id,
+                    trade_id,
+                    realorder_id,
+                    towards,
+                    self.event_id,
+                );
+            }
+            GatewayEvent::Rejected {
+                order_id, reason, ..
+            } => {
+                if let Some(order) = self.live_orders.remove(&order_id) {
+                    self.release_order(&order, reason.as_str());
+                }
+            }
+            GatewayEvent::Cancelled { order_id, .. } => {
+                if let Some(order) = self.live_orders.remove(&order_id) {
+                    self.release_order(&order, "cancelled");
+                }
+            }
+        }
+    }
+
+    /// 释放未成交部分的冻结资金/冻结仓位
+    fn release_order(&mut self, order: &QAOrder, msg: &str) {
+        let left = order.volume_left;
+        match order.towards {
+            1 | 2 | -2 => {
+                if let Some(frozen) = self.frozen.remove(&order.order_id) {
+                    self.money += frozen.money;
+                }
+            }
+            3 | 4 => {
+                if let Some(qapos) = self.hold.get_mut(&order.instrument_id) {
+                    // 平仓 (3) 先冻结的昨仓
+                    let his = if order.towards == 3 {
+                        left.min(qapos.volume_short_frozen_his)
+                    } else {
+                        0.0
+                    };
+                    qapos.volume_short_frozen_his -= his;
+                    qapos.volume_short_his += his;
+                    qapos.volume_short_frozen_today -= left - his;
+                    qapos.volume_short_today += left - his;
+                }
+            }
+            -1 | -3 | -4 => {
+                if let Some(qapos) = self.hold.get_mut(&order.instrument_id) {
+                    let his = if order.towards == -3 {
+                        left.min(qapos.volume_long_frozen_his)
+                    } else {
+                        0.0
+                    };
+                    qapos.volume_long_frozen_his -= his;
+                    qapos.volume_long_his += his;
+                    qapos.volume_long_frozen_today -= left - his;
+                    qapos.volume_long_today += left - his;
+                }
+            }
+            _ => {}
+        }
+        if let Some(o) = self.dailyorders.get_mut(&order.order_id) {
+            o.status = "FINISHED".to_string();
+            o.last_msg = msg.to_string();
+        }
     }
 
     pub fn change_datetime(&mut self, datetime: String) {
@@ -859,9 +1458,19 @@ impl QA_Account {
     ) {
         self.time = datetime.clone();
         if self.frozen.contains_key(&order_id) {
+            // 部分成交时按成交量释放冻结资金
             let frozen = self.frozen.get_mut(&order_id).unwrap();
-            self.money += frozen.money;
-            self.frozen.remove(&order_id);
+            let release = if amount >= frozen.amount {
+                frozen.money
+            } else {
+                frozen.coeff * amount
+            };
+            frozen.amount -= amount;
+            frozen.money -= release;
+            self.money += release;
+            if frozen.amount <= 0.0 {
+                self.frozen.remove(&order_id);
+            }
         } else {
             if towards == -1 | 1 | 2 | -2 {
                 error!("NOT IN DAY ORDER {}", order_id)
@@ -983,6 +1592,7 @@ impl QA_Account {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_helper::bar;
 
     #[test]
     fn test_new() {
@@ -1436,6 +2046,81 @@ mod tests {
         //acc.history_table();
     }
 
+    #[test]
+    fn test_gateway_fill() {
+        use crate::qagateway::MockExchange;
+        let code = "RB2005";
+        let mut acc = QA_Account::new("RustT01B2_RBL8", "test", "admin", 100000.0, false, "real");
+        let ex = Arc::new(Mutex::new(MockExchange::new().with_latency(500).with_fill_ratio(0.5)));
+        acc.set_gateway(ex.clone());
+        acc.init_h(code);
+        let order = acc
+            .buy_open(code, 4.0, "2020-01-20 21:00:00", 3500.0)
+            .unwrap();
+        assert_eq!(acc.get_volume_long(code), 0.0);
+        assert_eq!(acc.dailyorders[&order.order_id].status, "ALIVE");
+
+        acc.on_price_change(code.to_string(), 3490.0, "2020-01-20 21:00:01".to_string());
+        assert_eq!(acc.get_volume_long(code), 2.0);
+        acc.on_price_change(code.to_string(), 3490.0, "2020-01-20 21:00:02".to_string());
+        acc.on_price_change(code.to_string(), 3490.0, "2020-01-20 21:00:03".to_string());
+        assert_eq!(acc.get_volume_long(code), 4.0);
+        assert_eq!(acc.dailytrades.len(), 3);
+        assert_eq!(acc.dailyorders[&order.order_id].status, "FINISHED");
+        assert!(acc.frozen.is_empty());
+
+        let order = acc
+            .sell_close(code, 4.0, "2020-01-20 21:01:00", 3600.0)
+            .unwrap();
+        acc.on_price_change(code.to_string(), 3550.0, "2020-01-20 21:01:01".to_string());
+        assert_eq!(acc.get_volume_long(code), 4.0);
+        acc.cancel_order(&order.order_id).unwrap();
+        assert_eq!(acc.get_position(code).unwrap().volume_long_frozen(), 0.0);
+        assert!(acc.cancel_order(&order.order_id).is_err());
+    }
+
+    #[test]
+    fn test_gateway_reject() {
+        use crate::qagateway::MockExchange;
+        let code = "RB2005";
+        let mut acc = QA_Account::new("RustT01B2_RBL8", "test", "admin", 100000.0, false, "real");
+        acc.set_gateway(Arc::new(Mutex::new(MockExchange::new().with_max_volume(2.0))));
+        let money = acc.money;
+        assert!(acc.buy_open(code, 4.0, "2020-01-20 21:00:00", 3500.0).is_err());
+        assert_eq!(acc.money, money);
+        assert!(acc.frozen.is_empty());
+        assert_eq!(acc.dailyorders.values().next().unwrap().status, "FINISHED");
+    }
+
+    #[test]
+    fn test_order_id() {
+        let code = "RB2005";
+        let mut acc = QA_Account::new("acc_a", "test", "admin", 1000000.0, false, "backtest");
+        let o1 = acc.buy_open(code, 1.0, "2020-01-20 09:30:00", 3500.0).unwrap();
+        let o2 = acc.buy_open(code, 1.0, "2020-01-20 09:31:00", 3500.0).unwrap();
+        assert_eq!(o1.order_id, "acc_a_O00000001");
+        assert_eq!(o2.order_id, "acc_a_O00000002");
+        assert_eq!(acc.history[0].trade_id, "acc_a_T00000001");
+        assert_ne!(acc.history[0].trade_id, acc.history[0].order_id);
+
+        let o3 = acc
+            .send_order(code, 1.0, "2020-01-20 09:32:00", -3, 3510.0, "my_order")
+            .unwrap();
+        assert_eq!(o3.order_id, "my_order");
+        assert_eq!(acc.history[2].order_id, "my_order");
+
+        let mut real = QA_Account::new("acc_b", "test", "admin", 1000000.0, false, "real");
+        real.set_id_generator(QAIdGenerator::seeded_random(1));
+        real.send_order(code, 1.0, "2020-01-20 09:30:00", 2, 3500.0, "dup").unwrap();
+        real.buy_open(code, 1.0, "2020-01-20 09:31:00", 3500.0).unwrap();
+        assert!(real
+            .send_order(code, 1.0, "2020-01-20 09:32:00", 2, 3500.0, "dup")
+            .is_err());
+        assert_eq!(real.dailyorders.len(), 2);
+        assert_eq!(real.dailytrades.len(), 2);
+        assert!(!real.dailytrades.contains_key("dup"));
+    }
+
     #[test]
     fn test_get_info() {
         let mut acc = QA_Account::new(
@@ -1455,4 +2140,94 @@ mod tests {
         println!("{:#?}", r);
         assert_eq!(110000.0, acc.get_balance());
     }
+
+    #[test]
+    fn test_conditional() {
+        let code = "rb2005";
+        let mut acc = QA_Account::new("acc_c", "test", "admin", 1000000.0, false, "backtest");
+        assert!(acc.bracket(code, 3480.0, 3550.0).is_err());
+        acc.buy_open(code, 10.0, "2020-01-20 09:30:00", 3500.0).unwrap();
+        let (stop, take) = acc.bracket(code, 3480.0, 3550.0).unwrap();
+        acc.on_price_change(code.to_string(), 3490.0, "2020-01-20 09:31:00".to_string());
+        assert_eq!(acc.conditional.orders.len(), 2);
+        acc.on_price_change(code.to_string(), 3478.0, "2020-01-20 09:32:00".to_string());
+        assert_eq!(acc.history.len(), 2);
+        assert_eq!(acc.history[1].price, 3478.0);
+        assert_eq!(acc.get_volume_long(code), 0.0);
+        assert!(acc.conditional.orders.is_empty());
+        assert_eq!(acc.conditional.history[0].id, take);
+        assert_eq!(acc.conditional.history[1].id, stop);
+        assert!(acc.cancel_conditional(&take).is_err());
+
+        acc.sell_open(code, 10.0, "2020-01-20 09:33:00", 3480.0).unwrap();
+        acc.trailing_stop(code, 3, 10.0, QATrailDistance::Ticks(5.0), None);
+        acc.on_bar(
+            &bar(code, "2020-01-20 09:34:00", 3480.0)
+                .high(3481.0)
+                .low(3460.0)
+                .close(3466.0)
+                .build(),
+        );
+        // the close is above the level the bar's low just moved, the stop waits for the next bar
+        assert_eq!(acc.get_volume_short(code), 10.0);
+        acc.on_bar(
+            &bar(code, "2020-01-20 09:35:00", 3466.0)
+                .high(3475.0)
+                .low(3462.0)
+                .close(3470.0)
+                .build(),
+        );
+        // the lowest price was 3460, 5 ticks of 1.0
+        assert_eq!(acc.history.last().unwrap().price, 3466.0);
+        assert_eq!(acc.get_volume_short(code), 0.0);
+    }
+
+    #[test]
+    fn test_order_target() {
+        let code = "rb2005";
+        let mut acc = QA_Account::new("acc_t", "test", "admin", 1000000.0, false, "backtest");
+        let orders = acc.order_target_volume(code, 10.0, "2020-01-20 09:30:00", 3500.0).unwrap();
+        assert_eq!(orders.len(), 1);
+        acc.settle();
+        acc.order_target_volume(code, 15.0, "2020-01-21 09:30:00", 3500.0).unwrap();
+        assert_eq!(acc.get_volume_long(code), 15.0);
+
+        // 10 his first, then 2 today
+        let towards: Vec<(i32, f64)> = acc
+            .order_target_volume(code, 3.0, "2020-01-21 09:31:00", 3510.0)
+            .unwrap()
+            .iter()
+            .map(|o| (o.towards, o.volume))
+            .collect();
+        assert_eq!(towards, vec![(-3, 10.0), (-4, 2.0)]);
+        assert_eq!(acc.get_volume_long(code), 3.0);
+
+        // flip, close the long then open the short
+        let towards: Vec<i32> = acc
+            .order_target_volume(code, -2.0, "2020-01-21 09:32:00", 3510.0)
+            .unwrap()
+            .iter()
+            .map(|o| o.towards)
+            .collect();
+        assert_eq!(towards, vec![-4, -2]);
+        assert_eq!(acc.get_volume_long(code), 0.0);
+        assert_eq!(acc.get_volume_short(code), 2.0);
+
+        // 10% of the balance, 3500 * 10 per lot
+        let balance = acc.get_balance();
+        acc.order_target_percent(code, 0.1, "2020-01-21 09:33:00", 3500.0).unwrap();
+        assert_eq!(acc.get_volume_short(code), 0.0);
+        assert_eq!(acc.get_volume_long(code), (balance * 0.1 / 35000.0).trunc());
+
+        let stock = "000001";
+        assert!(acc.order_target_volume(stock, -100.0, "2020-01-21 09:34:00", 12.0).is_err());
+        acc.order_target_value(stock, 12500.0, "2020-01-21 09:34:00", 12.0).unwrap();
+        assert_eq!(acc.get_volume_long(stock), 1000.0);
+        // T+1
+        assert!(acc.order_target_volume(stock, 0.0, "2020-01-21 09:35:00", 12.0).unwrap().is_empty());
+        acc.settle();
+        let orders = acc.order_target_volume(stock, 0.0, "2020-01-22 09:35:00", 12.0).unwrap();
+        assert_eq!((orders[0].towards, orders[0].volume), (-1, 1000.0));
+        assert_eq!(acc.get_volume_long(stock), 0.0);
+    }
 }
@@ -0,0 +1,340 @@
+use std::collections::{HashMap, HashSet};
+
+use log::warn;
+use qifi_rs::{Account, Order, Trade, QIFI};
+use tokio::sync::{broadcast, mpsc, oneshot};
+use tokio::task::JoinHandle;
+
+use crate::qaaccount::QA_Account;
+use crate::qaorder::QAOrder;
+
+/// commands accepted by the account actor
+#[derive(Debug)]
+pub enum AccountCommand {
+    SendOrder {
+        code: String,
+        amount: f64,
+        time: String,
+        towards: i32,
+        price: f64,
+        order_id: String,
+        reply: Option<oneshot::Sender<Result<QAOrder, ()>>>,
+    },
+    CancelOrder {
+        order_id: String,
+        reply: Option<oneshot::Sender<Result<(), ()>>>,
+    },
+    PriceChange {
+        code: String,
+        price: f64,
+        datetime: String,
+    },
+    Settle,
+    QueryQIFI {
+        reply: oneshot::Sender<QIFI>,
+    },
+    Stop,
+}
+
+/// events published by the account actor
+#[derive(Debug, Clone)]
+pub enum AccountEvent {
+    Order(Order),
+    Trade(Trade),
+    Account(Account),
+    /// the trading day that has been settled
+    Settled(String),
+}
+
+/// 账户 actor: 独占 QA_Account, 通过 mpsc 接收指令, 通过 broadcast 推送订单/成交/账户事件
+///
+/// 策略/风控/日志可以各自 subscribe, 不再共享 Arc<Mutex<QA_Account>>
+pub struct QAAccountActor {
+    account: QA_Account,
+    events: broadcast::Sender<AccountEvent>,
+    seen_trades: HashSet<String>,
+    seen_history: usize,
+    order_state: HashMap<String, (String, f64)>,
+}
+
+/// cloneable handle of a running actor
+#[derive(Debug, Clone)]
+pub struct QAAccountHandle {
+    commands: mpsc::Sender<AccountCommand>,
+    events: broadcast::Sender<AccountEvent>,
+}
+
+impl QAAccountActor {
+    pub fn new(account: QA_Account, capacity: usize) -> Self {
+        let (events, _) = broadcast::channel(capacity);
+        QAAccountActor {
+            seen_history: account.history.len(),
+            seen_trades: account.dailytrades.keys().cloned().collect(),
+            order_state: account
+                .dailyorders
+                .iter()
+                .map(|(k, o)| (k.clone(), (o.status.clone(), o.volume_left)))
+                .collect(),
+            account,
+            events,
+        }
+    }
+
+    /// 在 tokio 中启动 actor, 收到 Stop 或所有 handle 被 drop 后返回账户
+    pub fn spawn(account: QA_Account, capacity: usize) -> (QAAccountHandle, JoinHandle<QA_Account>) {
+        let actor = QAAccountActor::new(account, capacity);
+        let (tx, rx) = mpsc::channel(capacity);
+        let handle = QAAccountHandle {
+            commands: tx,
+            events: actor.events.clone(),
+        };
+        (handle, tokio::spawn(actor.run(rx)))
+    }
+
+    pub async fn run(mut self, mut commands: mpsc::Receiver<AccountCommand>) -> QA_Account {
+        while let Some(command) = commands.recv().await {
+            match command {
+                AccountCommand::SendOrder {
+                    code,
+                    amount,
+                    time,
+                    towards,
+                    price,
+                    order_id,
+                    reply,
+                } => {
+                    let res = self
+                        .account
+                        .send_order(&code, amount, &time, towards, price, &order_id);
+                    if let Ok(order) = &res {
+                        // 回测模式下不记录 dailyorders, 直接推送已完成的订单
+                        if !self.account.dailyorders.contains_key(&order.order_id) {
+                            let mut qifi_order = order.to_qifi_order();
+                            qifi_order.status = "FINISHED".to_string();
+                            qifi_order.volume_left = 0.0;
+                            self.publish(AccountEvent::Order(qifi_order));
+                        }
+                    }
+                    self.publish_changes();
+                    if let Some(reply) = reply {
+                        let _ = reply.send(res);
+                    }
+                }
+                AccountCommand::CancelOrder { order_id, reply } => {
+                    let res = self.account.cancel_order(&order_id);
+                    self.publish_changes();
+                    if let Some(reply) = reply {
+                        let _ = reply.send(res);
+                    }
+                }
+                AccountCommand::PriceChange {
+                    code,
+                    price,
+                    datetime,
+                } => {
+                    if self.account.get_position(&code).is_some() {
+                        self.account.on_price_change(code, price, datetime);
+                        self.publish_changes();
+                    }
+                }
+                AccountCommand::Settle => {
+                    let trading_day = self.account.get_tradingday();
+                    self.account.settle();
+                    self.seen_trades.clear();
+                    self.order_state.clear();
+                    self.publish(AccountEvent::Settled(trading_day));
+                    let message = self.account.get_accountmessage();
+                    self.publish(AccountEvent::Account(message));
+                }
+                AccountCommand::QueryQIFI { reply } => {
+                    let _ = reply.send(self.account.get_qifi_slice());
+                }
+                AccountCommand::Stop => break,
+            }
+        }
+        self.account
+    }
+
+    fn publish(&mut self, event: AccountEvent) {
+        // no subscriber is not an error
+        let _ = self.events.send(event);
+    }
+
+    fn publish_changes(&mut self) {
+        let mut events = vec![];
+        for (order_id, order) in self.account.dailyorders.iter() {
+            let state = (order.status.clone(), order.volume_left);
+            if self.order_state.get(order_id) != Some(&state) {
+                self.order_state.insert(order_id.clone(), state);
+                events.push(AccountEvent::Order(order.clone()));
+            }
+        }
+        for (trade_id, trade) in self.account.dailytrades.iter() {
+            if self.seen_trades.insert(trade_id.clone()) {
+                events.push(AccountEvent::Trade(trade.clone()));
+            }
+        }
+        for transaction in self.account.history[self.seen_history..].iter_mut() {
+            events.push(AccountEvent::Trade(transaction.to_qifitrade()));
+        }
+        self.seen_history = self.account.history.len();
+        events.push(AccountEvent::Account(self.account.get_accountmessage()));
+        for event in events {
+            self.publish(event);
+        }
+    }
+}
+
+impl QAAccountHandle {
+    pub fn subscribe(&self) -> broadcast::Receiver<AccountEvent> {
+        self.events.subscribe()
+    }
+
+    async fn command(&mut self, command: AccountCommand) -> Result<(), ()> {
+        self.commands
+            .send(command)
+            .await
+            .map_err(|_| warn!("account actor is stopped"))
+    }
+
+    pub async fn send_order(
+        &mut self,
+        code: &str,
+        amount: f64,
+        time: &str,
+        towards: i32,
+        price: f64,
+        order_id: &str,
+    ) -> Result<QAOrder, ()> {
+        let (tx, rx) = oneshot::channel();
+        self.command(AccountCommand::SendOrder {
+            code: code.to_string(),
+            amount,
+            time: time.to_string(),
+            towards,
+            price,
+            order_id: order_id.to_string(),
+            reply: Some(tx),
+        })
+        .await?;
+        rx.await.map_err(|_| ())?
+    }
+
+    pub async fn cancel_order(&mut self, order_id: &str) -> Result<(), ()> {
+        let (tx, rx) = oneshot::channel();
+        self.command(AccountCommand::CancelOrder {
+            order_id: order_id.to_string(),
+            reply: Some(tx),
+        })
+        .await?;
+        rx.await.map_err(|_| ())?
+    }
+
+    pub async fn on_price_change(&mut self, code: &str, price: f64, datetime: &str) -> Result<(), ()> {
+        self.command(AccountCommand::PriceChange {
+            code: code.to_string(),
+            price,
+            datetime: datetime.to_string(),
+        })
+        .await
+    }
+
+    pub async fn settle(&mut self) -> Result<(), ()> {
+        self.command(AccountCommand::Settle).await
+    }
+
+    pub async fn get_qifi_slice(&mut self) -> Result<QIFI, ()> {
+        let (tx, rx) = oneshot::channel();
+        self.command(AccountCommand::QueryQIFI { reply: tx }).await?;
+        rx.await.map_err(|_| ())
+    }
+
+    pub async fn stop(&mut self) -> Result<(), ()> {
+        self.command(AccountCommand::Stop).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::qagateway::MockExchange;
+
+    #[tokio::test]
+    async fn test_backtest_events() {
+        let acc = QA_Account::new("RustT01B2_RBL8", "test", "admin", 100000.0, false, "backtest");
+        let (mut handle, join) = QAAccountActor::spawn(acc, 64);
+        let mut strategy = handle.subscribe();
+        let mut risk = handle.subscribe();
+
+        let order = handle
+            .send_order("RB2005", 2.0, "2020-01-20 09:30:00", 2, 3500.0, "")
+            .await
+            .unwrap();
+        handle.stop().await.unwrap();
+        let acc = join.await.unwrap();
+        assert_eq!(acc.history.len(), 1);
+
+        for rx in [&mut strategy, &mut risk].iter_mut() {
+            match rx.recv().await.unwrap() {
+                AccountEvent::Order(o) => assert_eq!(o.order_id, order.order_id),
+                e => panic!("unexpected {:?}", e),
+            }
+            match rx.recv().await.unwrap() {
+                AccountEvent::Trade(t) => assert_eq!(t.volume, 2.0),
+                e => panic!("unexpected {:?}", e),
+            }
+            match rx.recv().await.unwrap() {
+                AccountEvent::Account(a) => assert!(a.available < 100000.0),
+                e => panic!("unexpected {:?}", e),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_real_events_from_gateway() {
+        let mut acc = QA_Account::new("RustT01B2_RBL8", "test", "admin", 100000.0, false, "real");
+        acc.set_gateway(Arc::new(Mutex::new(MockExchange::new().with_latency(1000))));
+        acc.init_h("RB2005");
+        let (mut handle, join) = QAAccountActor::spawn(acc, 64);
+        let mut rx = handle.subscribe();
+
+        handle
+            .send_order("RB2005", 2.0, "2020-01-20 21:00:00", 2, 3500.0, "")
+            .await
+            .unwrap();
+        handle
+            .on_price_change("RB2005", 3490.0, "2020-01-20 21:00:05")
+            .await
+            .unwrap();
+        let qifi = handle.get_qifi_slice().await.unwrap();
+        assert_eq!(qifi.trades.len(), 1);
+        handle.settle().await.unwrap();
+        handle.stop().await.unwrap();
+        join.await.unwrap();
+
+        let mut kinds = vec![];
+        while let Ok(event) = rx.try_recv() {
+            kinds.push(match event {
+                AccountEvent::Order(o) => format!("order:{}", o.status),
+                AccountEvent::Trade(_) => "trade".to_string(),
+                AccountEvent::Account(_) => "account".to_string(),
+                AccountEvent::Settled(day) => format!("settled:{}", day),
+            });
+        }
+        println!("{:#?}", kinds);
+        assert_eq!(
+            kinds,
+            vec![
+                "order:ALIVE",
+                "account",
+                "order:FINISHED",
+                "trade",
+                "account",
+                "settled:2020-01-21",
+                "account"
+            ]
+        );
+    }
+}
@@ -0,0 +1,584 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use chrono::{Duration, NaiveDateTime, Timelike};
+use serde::{Deserialize, Serialize};
+
+use crate::market_preset::MarketPreset;
+use crate::qaaccount::QA_Account;
+use crate::qafetch::BAR;
+
+const FORMAT: &str = "%Y-%m-%d %H:%M:%S";
+
+/// share of the daily volume traded in every intraday bucket
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct QAVolumeProfile {
+    pub bucket_minutes: u32,
+    /// "HH:MM" bucket start -> share of the volume, the shares sum to 1
+    pub buckets: BTreeMap<String, f64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum QAAlgoKind {
+    /// even slices between start and end
+    TWAP { slices: usize },
+    /// slices follow a historical volume profile
+    VWAP { profile: QAVolumeProfile },
+    /// only `display` is working at any time, the next child is sent once it is filled
+    Iceberg { display: f64 },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum QAAlgoStatus {
+    Pending,
+    Working,
+    Finished,
+    Cancelled,
+}
+
+/// parent order, its children are normal QAOrder sent through the account
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QAAlgoOrder {
+    pub id: String,
+    pub code: String,
+    pub towards: i32,
+    pub volume: f64,
+    pub start: String,
+    pub end: String,
+    pub kind: QAAlgoKind,
+    /// children are not sent beyond this price
+    pub limit_price: Option<f64>,
+    /// (release datetime, cumulative volume released)
+    pub schedule: Vec<(String, f64)>,
+    pub children: Vec<String>,
+    pub filled: f64,
+    pub fill_value: f64,
+    pub last_fill: String,
+    pub status: QAAlgoStatus,
+    /// first price seen after start
+    pub arrival_price: f64,
+    /// close * volume and volume of the bars from start to the last fill, for the vwap benchmark
+    pub market_value: f64,
+    pub market_volume: f64,
+}
+
+/// 算法单执行结果, 滑点为正表示成本, 单位 bp
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QAAlgoReport {
+    pub id: String,
+    pub code: String,
+    pub volume: f64,
+    pub filled: f64,
+    pub fill_ratio: f64,
+    pub children: usize,
+    pub average_price: f64,
+    pub arrival_price: f64,
+    pub market_vwap: f64,
+    pub slippage_arrival: f64,
+    pub slippage_vwap: f64,
+    pub start: String,
+    pub last_fill: String,
+}
+
+/// 算法交易, 将母单拆分为子单 (TWAP/VWAP/冰山), 子单通过账户的 send_order 报出
+///
+/// 账户带 MockExchange 网关时子单由撮合引擎成交, 可以用历史 bar 回测算法的执行效果
+#[derive(Debug, Clone)]
+pub struct QAAlgoEngine {
+    pub algos: BTreeMap<String, QAAlgoOrder>,
+    /// child order id -> algo id
+    owner: HashMap<String, String>,
+    seen_trades: HashSet<String>,
+    history_cursor: usize,
+    next_id: u64,
+    preset: MarketPreset,
+}
+
+fn parse(datetime: &str) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(datetime, FORMAT).ok()
+}
+
+impl QAVolumeProfile {
+    /// bars of several days, the profile is their total volume per bucket
+    pub fn from_bars(bars: &[BAR], bucket_minutes: u32) -> Self {
+        let bucket_minutes = bucket_minutes.max(1);
+        let mut buckets: BTreeMap<String, f64> = BTreeMap::new();
+        for bar in bars {
+            if bar.datetime.len() < 16 {
+                continue;
+            }
+            let hour: u32 = bar.datetime[11..13].parse().unwrap_or(0);
+            let minute: u32 = bar.datetime[14..16].parse().unwrap_or(0);
+            let m = (hour * 60 + minute) / bucket_minutes * bucket_minutes;
+            *buckets
+                .entry(format!("{:02}:{:02}", m / 60, m % 60))
+                .or_default() += bar.volume;
+        }
+        let total: f64 = buckets.values().sum();
+        if total > 0.0 {
+            for v in buckets.values_mut() {
+                *v /= total;
+            }
+        }
+        QAVolumeProfile {
+            bucket_minutes,
+            buckets,
+        }
+    }
+}
+
+impl QAAlgoOrder {
+    pub fn remaining(&self) -> f64 {
+        self.volume - self.filled
+    }
+
+    pub fn average_price(&self) -> f64 {
+        if self.filled > 0.0 {
+            self.fill_value / self.filled
+        } else {
+            0.0
+        }
+    }
+
+    /// volume released by the schedule at datetime, everything after end
+    pub fn due(&self, datetime: &str) -> f64 {
+        if datetime >= self.end.as_str() {
+            return self.volume;
+        }
+        self.schedule
+            .iter()
+            .filter(|(t, _)| t.as_str() <= datetime)
+            .map(|(_, v)| *v)
+            .next_back()
+            .unwrap_or(0.0)
+    }
+
+    fn build_schedule(&mut self) {
+        let (start, end) = match (parse(&self.start), parse(&self.end)) {
+            (Some(s), Some(e)) => (s, e),
+            _ => {
+                self.schedule = vec![(self.start.clone(), self.volume)];
+                return;
+            }
+        };
+        let weights: Vec<(String, f64)> = match &self.kind {
+            QAAlgoKind::TWAP { slices } => {
+                let n = (*slices).max(1) as i64;
+                let step = (end - start).num_seconds() / n;
+                (0..n)
+                    .map(|i| {
+                        let t = start + Duration::seconds(step * i);
+                        (t.format(FORMAT).to_string(), 1.0 / n as f64)
+                    })
+                    .collect()
+            }
+            QAAlgoKind::VWAP { profile } => {
+                // walk the buckets by datetime, so a night session crossing midnight keeps
+                // the buckets after 00:00 on the next day
+                let step = profile.bucket_minutes.max(1) as i64;
+                let bucket_start = |t: NaiveDateTime| {
+                    let minute = (t.num_seconds_from_midnight() / 60) as i64;
+                    let midnight = t - Duration::seconds(t.num_seconds_from_midnight() as i64);
+                    (midnight, minute / step * step)
+                };
+                let mut res = vec![];
+                let (midnight, minute) = bucket_start(start);
+                let mut t = midnight + Duration::minutes(minute);
+                while t < end {
+                    let (midnight, minute) = bucket_start(t);
+                    let key = format!("{:02}:{:02}", minute / 60, minute % 60);
+                    if let Some(w) = profile.buckets.get(&key) {
+                        // a bucket that started before start is released at start
+                        res.push((t.max(start).format(FORMAT).to_string(), *w));
+                    }
+                    t = (midnight + Duration::minutes(minute + step)).min(midnight + Duration::days(1));
+                }
+                res
+            }
+            QAAlgoKind::Iceberg { .. } => vec![],
+        };
+        let total: f64 = weights.iter().map(|(_, w)| w).sum();
+        if total <= 0.0 {
+            self.schedule = vec![(self.start.clone(), self.volume)];
+            return;
+        }
+        let mut cumulative = 0.0;
+        self.schedule = weights
+            .into_iter()
+            .map(|(t, w)| {
+                cumulative += w / total;
+                (t, (self.volume * cumulative).round().min(self.volume))
+            })
+            .collect();
+    }
+}
+
+impl Default for QAAlgoEngine {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl QAAlgoEngine {
+    pub fn new() -> Self {
+        QAAlgoEngine {
+            algos: BTreeMap::new(),
+            owner: HashMap::new(),
+            seen_trades: HashSet::new(),
+            history_cursor: 0,
+            next_id: 0,
+            preset: MarketPreset::new(),
+        }
+    }
+
+    fn submit(
+        &mut self,
+        code: &str,
+        towards: i32,
+        volume: f64,
+        start: &str,
+        end: &str,
+        kind: QAAlgoKind,
+    ) -> String {
+        self.next_id += 1;
+        let id = format!("ALGO_{}", self.next_id);
+        let mut algo = QAAlgoOrder {
+            id: id.clone(),
+            code: code.to_string(),
+            towards,
+            volume,
+            start: start.to_string(),
+            end: end.to_string(),
+            kind,
+            limit_price: None,
+            schedule: vec![],
+            children: vec![],
+            filled: 0.0,
+            fill_value: 0.0,
+            last_fill: String::new(),
+            status: QAAlgoStatus::Pending,
+            arrival_price: 0.0,
+            market_value: 0.0,
+            market_volume: 0.0,
+        };
+        algo.build_schedule();
+        self.algos.insert(id.clone(), algo);
+        id
+    }
+
+    pub fn twap(&mut self, code: &str, towards: i32, volume: f64, start: &str, end: &str, slices: usize) -> String {
+        self.submit(code, towards, volume, start, end, QAAlgoKind::TWAP { slices })
+    }
+
+    pub fn vwap(
+        &mut self,
+        code: &str,
+        towards: i32,
+        volume: f64,
+        start: &str,
+        end: &str,
+        profile: QAVolumeProfile,
+    ) -> String {
+        self.submit(code, towards, volume, start, end, QAAlgoKind::VWAP { profile })
+    }
+
+    /// children are limit orders at limit_price
+    pub fn iceberg(
+        &mut self,
+        code: &str,
+        towards: i32,
+        volume: f64,
+        display: f64,
+        limit_price: f64,
+        start: &str,
+    ) -> String {
+        let id = self.submit(code, towards, volume, start, start, QAAlgoKind::Iceberg { display });
+        self.set_limit_price(&id, Some(limit_price));
+        id
+    }
+
+    pub fn set_limit_price(&mut self, id: &str, limit_price: Option<f64>) {
+        if let Some(algo) = self.algos.get_mut(id) {
+            algo.limit_price = limit_price;
+        }
+    }
+
+    /// cancel the working children and stop the algo
+    pub fn cancel(&mut self, acc: &mut QA_Account, id: &str) {
+        let children = match self.algos.get_mut(id) {
+            Some(algo) if algo.status != QAAlgoStatus::Finished => {
+                algo.status = QAAlgoStatus::Cancelled;
+                algo.children.clone()
+            }
+            _ => return,
+        };
+        for child in children {
+            if acc.dailyorders.get(&child).map(|o| o.status == "ALIVE").unwrap_or(false) {
+                let _ = acc.cancel_order(&child);
+            }
+        }
+        self.sync_fills(acc);
+    }
+
+    /// collect the new fills of the children from dailytrades (real) and history (backtest)
+    fn sync_fills(&mut self, acc: &QA_Account) {
+        let mut fills = vec![];
+        for trade in acc.dailytrades.values() {
+            if self.owner.contains_key(&trade.order_id) && self.seen_trades.insert(trade.trade_id.clone()) {
+                fills.push((trade.order_id.clone(), trade.price, trade.volume.abs(), trade.trade_date_time));
+            }
+        }
+        let mut backtest = vec![];
+        for t in acc.history[self.history_cursor..].iter() {
+            if self.owner.contains_key(&t.order_id) && self.seen_trades.insert(t.trade_id.clone()) {
+                backtest.push((t.order_id.clone(), t.price, t.amount.abs(), t.datetime.clone()));
+            }
+        }
+        self.history_cursor = acc.history.len();
+
+        for (order_id, price, volume, nanos) in fills {
+            // trade_date_time is utc nanos, the datetimes here are china time
+            let datetime = NaiveDateTime::from_timestamp((nanos + 28800000000000) / 1_000_000_000, 0)
+                .format(FORMAT)
+                .to_string();
+            backtest.push((order_id, price, volume, datetime));
+        }
+        for (order_id, price, volume, datetime) in backtest {
+            if let Some(algo) = self.algos.get_mut(&self.owner[&order_id]) {
+                algo.filled += volume;
+                algo.fill_value += price * volume;
+                algo.last_fill = datetime;
+                if algo.remaining() <= 0.0 {
+                    algo.status = QAAlgoStatus::Finished;
+                }
+            }
+        }
+    }
+
+    /// volume of the children still working in the gateway
+    fn working(acc: &QA_Account, algo: &QAAlgoOrder) -> f64 {
+        algo.children
+            .iter()
+            .filter_map(|c| acc.dailyorders.get(c))
+            .filter(|o| o.status == "ALIVE")
+            .map(|o| o.volume_left)
+            .sum()
+    }
+
+    /// 行情驱动, 账户已经按该价格 on_price_change 之后调用
+    pub fn on_price(&mut self, acc: &mut QA_Account, code: &str, price: f64, datetime: &str) {
+        self.sync_fills(acc);
+        let lot = self.preset.get(code).lot_size();
+        let ids: Vec<String> = self
+            .algos
+            .values()
+            .filter(|a| a.code == code && a.start.as_str() <= datetime)
+            .filter(|a| a.status == QAAlgoStatus::Pending || a.status == QAAlgoStatus::Working)
+            .map(|a| a.id.clone())
+            .collect();
+        for id in ids {
+            let (towards, amount, order_price) = {
+                let algo = self.algos.get_mut(&id).unwrap();
+                if algo.status == QAAlgoStatus::Pending {
+                    algo.status = QAAlgoStatus::Working;
+                    algo.arrival_price = price;
+                }
+                let working = Self::working(acc, algo);
+                let mut amount = match algo.kind {
+                    QAAlgoKind::Iceberg { display } => {
+                        if working > 0.0 {
+                            0.0
+                        } else {
+                            display.min(algo.remaining())
+                        }
+                    }
+                    _ => algo.due(datetime) - algo.filled - working,
+                };
+                // whole lots, unless it is the last piece of the parent
+                if amount < algo.remaining() - working {
+                    amount = (amount / lot).floor() * lot;
+                }
+                let order_price = match (algo.kind.clone(), algo.limit_price) {
+                    (QAAlgoKind::Iceberg { .. }, Some(limit)) => limit,
+                    (_, Some(limit)) if (algo.towards > 0 && price > limit) || (algo.towards < 0 && price < limit) => {
+                        amount = 0.0;
+                        limit
+                    }
+                    _ => price,
+                };
+                (algo.towards, amount, order_price)
+            };
+            if amount <= 0.0 {
+                continue;
+            }
+            if let Ok(order) = acc.send_order(code, amount, datetime, towards, order_price, "") {
+                self.owner.insert(order.order_id.clone(), id.clone());
+                self.algos.get_mut(&id).unwrap().children.push(order.order_id);
+            }
+            self.sync_fills(acc);
+        }
+    }
+
+    /// mark the account to the bar close, then drive the algos of the code, the bar volume
+    /// is used for the market vwap of the working algos
+    pub fn on_bar(&mut self, acc: &mut QA_Account, bar: &BAR) {
+        if acc.get_position(&bar.code).is_none() {
+            acc.init_h(&bar.code);
+        }
+        acc.on_price_change(bar.code.clone(), bar.close, bar.datetime.clone());
+        for algo in self.algos.values_mut() {
+            if algo.code == bar.code
+                && algo.start.as_str() <= bar.datetime.as_str()
+                && algo.status != QAAlgoStatus::Cancelled
+                && (algo.status != QAAlgoStatus::Finished || algo.last_fill.as_str() >= bar.datetime.as_str())
+            {
+                algo.market_value += bar.close * bar.volume;
+                algo.market_volume += bar.volume;
+            }
+        }
+        self.on_price(acc, &bar.code.clone(), bar.close, &bar.datetime.clone());
+    }
+
+    pub fn report(&self, id: &str) -> Option<QAAlgoReport> {
+        self.algos.get(id).map(|algo| {
+            let average_price = algo.average_price();
+            let market_vwap = if algo.market_volume > 0.0 {
+                algo.market_value / algo.market_volume
+            } else {
+                0.0
+            };
+            let side = if algo.towards > 0 { 1.0 } else { -1.0 };
+            let bps = |benchmark: f64| {
+                if benchmark > 0.0 && algo.filled > 0.0 {
+                    side * (average_price - benchmark) / benchmark * 10000.0
+                } else {
+                    0.0
+                }
+            };
+            QAAlgoReport {
+                id: algo.id.clone(),
+                code: algo.code.clone(),
+                volume: algo.volume,
+                filled: algo.filled,
+                fill_ratio: if algo.volume > 0.0 { algo.filled / algo.volume } else { 0.0 },
+                children: algo.children.len(),
+                average_price,
+                arrival_price: algo.arrival_price,
+                market_vwap,
+                slippage_arrival: bps(algo.arrival_price),
+                slippage_vwap: bps(market_vwap),
+                start: algo.start.clone(),
+                last_fill: algo.last_fill.clone(),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::qagateway::MockExchange;
+    use crate::test_helper::bar;
+
+    fn bars(prices: &[f64], volumes: &[f64]) -> Vec<BAR> {
+        prices
+            .iter()
+            .zip(volumes.iter())
+            .enumerate()
+            .map(|(i, (price, volume))| {
+                bar("rb2005", &format!("2020-01-20 09:{:02}:00", i), *price)
+                    .volume(*volume)
+                    .build()
+            })
+            .collect()
+    }
+
+    fn account(ex: MockExchange) -> QA_Account {
+        let mut acc = QA_Account::new("algo", "test", "admin", 1000000.0, false, "real");
+        acc.set_gateway(Arc::new(Mutex::new(ex)));
+        acc
+    }
+
+    #[test]
+    fn test_twap() {
+        let mut acc = account(MockExchange::new());
+        let mut engine = QAAlgoEngine::new();
+        let id = engine.twap("rb2005", 2, 10.0, "2020-01-20 09:00:00", "2020-01-20 09:05:00", 5);
+        assert_eq!(engine.algos[&id].schedule.len(), 5);
+        assert_eq!(engine.algos[&id].due("2020-01-20 09:01:30"), 4.0);
+        for bar in bars(&[3500.0, 3502.0, 3504.0, 3506.0, 3508.0, 3510.0, 3512.0], &[100.0; 7]).iter() {
+            engine.on_bar(&mut acc, bar);
+        }
+        let report = engine.report(&id).unwrap();
+        println!("{:#?}", report);
+        assert_eq!(report.filled, 10.0);
+        assert_eq!(report.children, 5);
+        assert_eq!(report.average_price, 3504.0);
+        assert_eq!(report.arrival_price, 3500.0);
+        assert!(report.slippage_arrival > 0.0);
+        assert_eq!(report.market_vwap, 3504.0);
+        assert_eq!(engine.algos[&id].status, QAAlgoStatus::Finished);
+        assert_eq!(acc.get_volume_long("rb2005"), 10.0);
+    }
+
+    #[test]
+    fn test_vwap() {
+        let history = bars(&[3500.0; 4], &[600.0, 200.0, 100.0, 100.0]);
+        let profile = QAVolumeProfile::from_bars(&history, 1);
+        assert_eq!(profile.buckets["09:00"], 0.6);
+
+        let mut acc = account(MockExchange::new());
+        let mut engine = QAAlgoEngine::new();
+        let id = engine.vwap("rb2005", -2, 10.0, "2020-01-20 09:00:00", "2020-01-20 09:04:00", profile);
+        let today = bars(&[3500.0; 5], &[100.0; 5]);
+        engine.on_bar(&mut acc, &today[0]);
+        assert_eq!(engine.algos[&id].filled, 6.0);
+        for bar in today[1..].iter() {
+            engine.on_bar(&mut acc, bar);
+        }
+        assert_eq!(engine.algos[&id].filled, 10.0);
+        assert_eq!(acc.get_volume_short("rb2005"), 10.0);
+    }
+
+    #[test]
+    fn test_vwap_night_session() {
+        let history: Vec<BAR> = [
+            ("2020-01-20 21:00:00", 300.0),
+            ("2020-01-20 23:30:00", 100.0),
+            ("2020-01-21 00:10:00", 100.0),
+        ]
+        .iter()
+        .map(|(datetime, volume)| bar("rb2005", datetime, 3500.0).volume(*volume).build())
+        .collect();
+        let profile = QAVolumeProfile::from_bars(&history, 60);
+        let mut engine = QAAlgoEngine::new();
+        let id = engine.vwap("rb2005", 2, 10.0, "2020-01-20 21:30:00", "2020-01-21 01:00:00", profile);
+        assert_eq!(
+            engine.algos[&id].schedule,
+            vec![
+                ("2020-01-20 21:30:00".to_string(), 6.0),
+                ("2020-01-20 23:00:00".to_string(), 8.0),
+                ("2020-01-21 00:00:00".to_string(), 10.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_iceberg() {
+        let mut acc = account(MockExchange::new().with_fill_ratio(0.5));
+        let mut engine = QAAlgoEngine::new();
+        let id = engine.iceberg("rb2005", 2, 10.0, 4.0, 3500.0, "2020-01-20 09:00:00");
+        let prices = [3510.0, 3500.0, 3499.0, 3498.0, 3497.0, 3496.0, 3495.0, 3494.0, 3493.0, 3492.0];
+        for bar in bars(&prices, &[100.0; 10]).iter() {
+            engine.on_bar(&mut acc, bar);
+            let algo = &engine.algos[&id];
+            assert!(QAAlgoEngine::working(&acc, algo) <= 4.0);
+        }
+        let report = engine.report(&id).unwrap();
+        assert_eq!(report.filled, 10.0);
+        assert_eq!(report.children, 3);
+        // filled at the market prices that crossed the limit
+        assert!(report.average_price <= 3500.0);
+    }
+}
@@ -0,0 +1,364 @@
+use std::collections::HashMap;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::qaaccount::QA_Account;
+use crate::qarisk::{std, QAEquityCurve};
+use crate::trade_date::QATradeDate;
+
+/// how the capital of a portfolio is split between its sub accounts
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum AllocationMethod {
+    /// account_cookie -> weight, the unallocated part stays in the allocator cash
+    FixedWeight(HashMap<String, f64>),
+    /// inverse volatility weights, every account contributes the same risk
+    EqualRisk,
+    /// every account is sized to reach the annualized target volatility
+    VolatilityTarget(f64),
+}
+
+/// when the allocator moves cash
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum RebalanceRule {
+    Daily,
+    /// every n trading days since the last rebalance
+    EveryNDays(usize),
+    /// explicit rebalance dates, like "2020-01-20"
+    Dates(Vec<String>),
+}
+
+/// one cash movement of an account, towards is DEPOSIT / WITHDRAW
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QATransfer {
+    pub datetime: String,
+    pub account_cookie: String,
+    pub portfolio_cookie: String,
+    pub towards: String,
+    pub amount: f64,
+    pub balance_before: f64,
+    pub balance_after: f64,
+}
+
+/// capital allocator for the accounts sharing one portfolio_cookie
+///
+/// 多账户资金分配: 按调仓日在子账户之间出入金, 并记录每一笔划转
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QAAllocator {
+    pub portfolio_cookie: String,
+    pub method: AllocationMethod,
+    pub rule: RebalanceRule,
+    /// cash of the portfolio that is not allocated to any account
+    pub cash: f64,
+    /// number of daily returns used to estimate volatility
+    pub lookback: usize,
+    pub last_rebalance: String,
+    pub transfers: Vec<QATransfer>,
+    #[serde(skip)]
+    trade_date: QATradeDate,
+}
+
+impl QAAllocator {
+    pub fn new(portfolio_cookie: &str, method: AllocationMethod, rule: RebalanceRule) -> Self {
+        QAAllocator {
+            portfolio_cookie: portfolio_cookie.to_string(),
+            method,
+            rule,
+            cash: 0.0,
+            lookback: 20,
+            last_rebalance: "".to_string(),
+            transfers: vec![],
+            trade_date: QATradeDate::new(),
+        }
+    }
+
+    pub fn set_lookback(&mut self, lookback: usize) {
+        self.lookback = lookback;
+    }
+
+    /// 判断是否为调仓日, datetime 会先转换为交易日
+    pub fn if_rebalance_day(&mut self, datetime: &str) -> bool {
+        let date = self.trade_date.get_trade_day(datetime.to_string());
+        if self.last_rebalance == date {
+            return false;
+        }
+        match &self.rule {
+            RebalanceRule::Daily => true,
+            RebalanceRule::EveryNDays(n) => {
+                if self.last_rebalance.is_empty() {
+                    true
+                } else {
+                    // the range includes the last rebalance day itself
+                    self.trade_date.get_trade_range(&self.last_rebalance, &date).len() > *n
+                }
+            }
+            RebalanceRule::Dates(dates) => dates.contains(&date),
+        }
+    }
+
+    /// annualized volatility of the last lookback daily returns of the equity curve
+    ///
+    /// deposit/withdraw of the day are removed, so transfers do not count as returns
+    pub fn get_volatility(&self, acc: &QA_Account) -> Option<f64> {
+        let curve = QAEquityCurve::from_account(acc);
+        let returns = curve.returns();
+        let window = &returns[returns.len().saturating_sub(self.lookback)..];
+        if window.len() < 2 {
+            return None;
+        }
+        let vol = std(window) * curve.days_per_year().sqrt();
+        if vol > 0.0 {
+            Some(vol)
+        } else {
+            None
+        }
+    }
+
+    /// target weights (account_cookie -> weight) of the accounts in this portfolio
+    ///
+    /// accounts without enough history get an equal weight
+    pub fn target_weights(&self, accounts: &[QA_Account]) -> HashMap<String, f64> {
+        let members: Vec<&QA_Account> = accounts
+            .iter()
+            .filter(|acc| acc.portfolio_cookie == self.portfolio_cookie)
+            .collect();
+        let mut weights = HashMap::new();
+        if members.is_empty() {
+            return weights;
+        }
+        let equal = 1.0 / members.len() as f64;
+        match &self.method {
+            AllocationMethod::FixedWeight(fixed) => {
+                for acc in members.iter() {
+                    let w = fixed.get(&acc.account_cookie).cloned().unwrap_or(0.0);
+                    weights.insert(acc.account_cookie.clone(), w);
+                }
+            }
+            AllocationMethod::EqualRisk => {
+                let inv: Vec<(String, Option<f64>)> = members
+                    .iter()
+                    .map(|acc| (acc.account_cookie.clone(), self.get_volatility(acc)))
+                    .collect();
+                if inv.iter().any(|(_, vol)| vol.is_none()) {
+                    for (cookie, _) in inv {
+                        weights.insert(cookie, equal);
+                    }
+                } else {
+                    let total: f64 = inv.iter().map(|(_, vol)| 1.0 / vol.unwrap()).sum();
+                    for (cookie, vol) in inv {
+                        weights.insert(cookie, 1.0 / vol.unwrap() / total);
+                    }
+                }
+            }
+            AllocationMethod::VolatilityTarget(target) => {
+                for acc in members.iter() {
+                    let w = match self.get_volatility(acc) {
+                        Some(vol) => equal * target / vol,
+                        None => equal,
+                    };
+                    weights.insert(acc.account_cookie.clone(), w);
+                }
+                // no leverage on the portfolio level
+                let total: f64 = weights.values().sum();
+                if total > 1.0 {
+                    for w in weights.values_mut() {
+                        *w /= total;
+                    }
+                }
+            }
+        }
+        weights
+    }
+
+    /// 调仓: 先从超配账户出金到分配器, 再从分配器入金到低配账户
+    pub fn rebalance(&mut self, accounts: &mut [QA_Account], datetime: &str) -> Vec<QATransfer> {
+        let weights = self.target_weights(accounts);
+        let mut total = self.cash;
+        for acc in accounts.iter_mut() {
+            if weights.contains_key(&acc.account_cookie) {
+                total += acc.get_balance();
+            }
+        }
+        let mut transfers = vec![];
+
+        for acc in accounts.iter_mut() {
+            if let Some(w) = weights.get(&acc.account_cookie) {
+                let balance = acc.get_balance();
+                let diff = balance - w * total;
+                if diff > 0.0 {
+                    let amount = diff.min(acc.money);
+                    if amount < diff {
+                        warn!("{} 可用资金不足, 只能出金 {:#?}", acc.account_cookie, amount);
+                    }
+                    if amount > 0.0 && acc.withdraw(amount).is_ok() {
+                        self.cash += amount;
+                        transfers.push(self.record(acc, datetime, "WITHDRAW", amount, balance));
+                    }
+                }
+            }
+        }
+        for acc in accounts.iter_mut() {
+            if let Some(w) = weights.get(&acc.account_cookie) {
+                let balance = acc.get_balance();
+                let diff = w * total - balance;
+                if diff > 0.0 {
+                    let amount = diff.min(self.cash);
+                    if amount > 0.0 {
+                        acc.deposit(amount);
+                        self.cash -= amount;
+                        transfers.push(self.record(acc, datetime, "DEPOSIT", amount, balance));
+                    }
+                }
+            }
+        }
+        self.last_rebalance = self.trade_date.get_trade_day(datetime.to_string());
+        self.transfers.extend(transfers.iter().cloned());
+        transfers
+    }
+
+    /// 行情驱动入口: 仅在调仓日执行 rebalance
+    pub fn on_datetime(&mut self, accounts: &mut [QA_Account], datetime: &str) -> Vec<QATransfer> {
+        if self.if_rebalance_day(datetime) {
+            self.rebalance(accounts, datetime)
+        } else {
+            vec![]
+        }
+    }
+
+    /// 某个账户的累计净入金
+    pub fn get_net_transfer(&self, account_cookie: &str) -> f64 {
+        self.transfers
+            .iter()
+            .filter(|t| t.account_cookie == account_cookie)
+            .map(|t| match t.towards.as_str() {
+                "DEPOSIT" => t.amount,
+                _ => -t.amount,
+            })
+            .sum()
+    }
+
+    fn record(
+        &self,
+        acc: &mut QA_Account,
+        datetime: &str,
+        towards: &str,
+        amount: f64,
+        balance_before: f64,
+    ) -> QATransfer {
+        QATransfer {
+            datetime: datetime.to_string(),
+            account_cookie: acc.account_cookie.clone(),
+            portfolio_cookie: self.portfolio_cookie.clone(),
+            towards: towards.to_string(),
+            amount,
+            balance_before,
+            balance_after: acc.get_balance(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn portfolio() -> Vec<QA_Account> {
+        vec![
+            QA_Account::new("acc_a", "pf", "admin", 1000000.0, false, "backtest"),
+            QA_Account::new("acc_b", "pf", "admin", 1000000.0, false, "backtest"),
+            QA_Account::new("acc_other", "other", "admin", 1000000.0, false, "backtest"),
+        ]
+    }
+
+    #[test]
+    fn test_fixed_weight() {
+        let mut accs = portfolio();
+        let mut weights = HashMap::new();
+        weights.insert("acc_a".to_string(), 0.7);
+        weights.insert("acc_b".to_string(), 0.3);
+        let mut alloc = QAAllocator::new(
+            "pf",
+            AllocationMethod::FixedWeight(weights),
+            RebalanceRule::Daily,
+        );
+        let transfers = alloc.rebalance(&mut accs, "2020-01-20 09:00:00");
+        println!("{:#?}", transfers);
+        assert_eq!(transfers.len(), 2);
+        assert_eq!(accs[0].get_balance(), 1400000.0);
+        assert_eq!(accs[1].get_balance(), 600000.0);
+        assert_eq!(accs[2].get_balance(), 1000000.0);
+        assert_eq!(alloc.cash, 0.0);
+        assert_eq!(alloc.get_net_transfer("acc_a"), 400000.0);
+        assert_eq!(alloc.get_net_transfer("acc_b"), -400000.0);
+    }
+
+    #[test]
+    fn test_withdraw_limited_by_money() {
+        let mut accs = portfolio();
+        accs[1].buy_open("000001", 50000.0, "2020-01-20 09:30:00", 19.0);
+        let mut weights = HashMap::new();
+        weights.insert("acc_a".to_string(), 1.0);
+        let mut alloc = QAAllocator::new(
+            "pf",
+            AllocationMethod::FixedWeight(weights),
+            RebalanceRule::Daily,
+        );
+        alloc.rebalance(&mut accs, "2020-01-20 10:00:00");
+        assert!(accs[1].money < 1.0);
+        assert!(accs[0].get_balance() < 2000000.0);
+    }
+
+    #[test]
+    fn test_equal_risk_without_history() {
+        let accs = portfolio();
+        let alloc = QAAllocator::new("pf", AllocationMethod::EqualRisk, RebalanceRule::Daily);
+        let w = alloc.target_weights(&accs);
+        assert_eq!(w.len(), 2);
+        assert_eq!(w["acc_a"], 0.5);
+        assert_eq!(w["acc_b"], 0.5);
+    }
+
+    #[test]
+    fn test_equal_risk_with_history() {
+        let mut accs = portfolio();
+        let prices = [3500.0, 3550.0, 3480.0, 3530.0, 3510.0];
+        let days = ["2020-01-13", "2020-01-14", "2020-01-15", "2020-01-16", "2020-01-17"];
+        accs[0].buy_open("rb2005", 1.0, "2020-01-13", 3500.0);
+        accs[1].buy_open("rb2005", 10.0, "2020-01-13", 3500.0);
+        for (day, price) in days.iter().zip(prices.iter()) {
+            for acc in accs.iter_mut().take(2) {
+                acc.on_price_change("rb2005".to_string(), *price, day.to_string());
+                acc.settle();
+            }
+        }
+        let alloc = QAAllocator::new("pf", AllocationMethod::EqualRisk, RebalanceRule::Daily);
+        // the whole history is inside the lookback, same number as the risk report
+        assert_eq!(
+            alloc.get_volatility(&accs[0]),
+            Some(QAEquityCurve::from_account(&accs[0]).volatility())
+        );
+        let w = alloc.target_weights(&accs);
+        println!("{:#?}", w);
+        assert!(w["acc_a"] > w["acc_b"]);
+        assert!((w["acc_a"] + w["acc_b"] - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_rebalance_rule() {
+        let mut accs = portfolio();
+        let mut alloc =
+            QAAllocator::new("pf", AllocationMethod::EqualRisk, RebalanceRule::EveryNDays(3));
+        assert!(alloc.if_rebalance_day("2020-04-01 09:00:00"));
+        alloc.on_datetime(&mut accs, "2020-04-01 09:00:00");
+        assert!(!alloc.if_rebalance_day("2020-04-01 14:00:00"));
+        assert!(!alloc.if_rebalance_day("2020-04-03 09:00:00"));
+        assert!(alloc.if_rebalance_day("2020-04-07 09:00:00"));
+
+        let mut alloc = QAAllocator::new(
+            "pf",
+            AllocationMethod::EqualRisk,
+            RebalanceRule::Dates(vec!["2020-04-03".to_string()]),
+        );
+        assert!(!alloc.if_rebalance_day("2020-04-02 09:00:00"));
+        assert!(alloc.if_rebalance_day("2020-04-03 09:00:00"));
+    }
+}
@@ -0,0 +1,229 @@
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::market_preset::MarketPreset;
+use crate::qaaccount::{QAAccountSlice, QA_Account};
+use crate::trade_date::QATradeDate;
+
+/// daily pnl of one instrument
+///
+/// holding_pnl 为昨仓按收盘价逐日盯市的盈亏,
+/// trading_pnl 为当日成交按当日收盘价计算的盈亏, 两者之和即当日的毛盈亏
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QAAttribution {
+    pub date: String,
+    pub code: String,
+    pub product: String,
+    pub exchange: String,
+    pub trading_pnl: f64,
+    pub holding_pnl: f64,
+    pub commission: f64,
+    pub tax: f64,
+    /// trading_pnl + holding_pnl - commission - tax
+    pub net_pnl: f64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QAAttributionSummary {
+    pub trading_pnl: f64,
+    pub holding_pnl: f64,
+    pub commission: f64,
+    pub tax: f64,
+    pub net_pnl: f64,
+    /// number of (day, code) rows
+    pub count: usize,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QAAttributionReport {
+    pub daily: Vec<QAAttribution>,
+    pub by_code: BTreeMap<String, QAAttributionSummary>,
+    /// keyed by the MarketPreset name
+    pub by_product: BTreeMap<String, QAAttributionSummary>,
+    pub by_exchange: BTreeMap<String, QAAttributionSummary>,
+    pub total: QAAttributionSummary,
+}
+
+impl QAAttributionSummary {
+    fn add(&mut self, row: &QAAttribution) {
+        self.trading_pnl += row.trading_pnl;
+        self.holding_pnl += row.holding_pnl;
+        self.commission += row.commission;
+        self.tax += row.tax;
+        self.net_pnl += row.net_pnl;
+        self.count += 1;
+    }
+}
+
+/// (signed amount, price, commission, tax) of one fill
+type Fill = (f64, f64, f64, f64);
+
+/// buy towards are positive
+fn signed_amount(towards: i32, amount: f64) -> f64 {
+    if towards > 0 {
+        amount.abs()
+    } else {
+        -amount.abs()
+    }
+}
+
+impl QAAttributionReport {
+    /// 由结算切片(收盘价与持仓)和 history 成交计算, 需要回测模式下逐日 settle 的账户
+    ///
+    /// the split is not read from position_profit_* / float_profit_*: QA_Postions never moves
+    /// position_cost to the settle price, so both are measured from the open price and can not
+    /// tell today's fills from yesterday's holding. Summed over the days they agree with
+    /// float_profit, see test_agree_with_position
+    pub fn from_account(acc: &QA_Account) -> Self {
+        let mut td = QATradeDate::new();
+        let mut preset = MarketPreset::new();
+
+        let mut slices: Vec<&QAAccountSlice> = acc.dailyassets.values().collect();
+        slices.sort_by(|a, b| a.datetime.cmp(&b.datetime));
+        let mut days: BTreeMap<String, &QAAccountSlice> = BTreeMap::new();
+        for slice in slices {
+            days.insert(td.get_trade_day(slice.datetime.clone()), slice);
+        }
+
+        // (day, code) -> transactions of the day
+        let mut fills: HashMap<(String, String), Vec<Fill>> = HashMap::new();
+        for t in acc.history.iter() {
+            fills
+                .entry((td.get_trade_day(t.datetime.clone()), t.code.clone()))
+                .or_default()
+                .push((signed_amount(t.direction, t.amount), t.price, t.commission, t.tax));
+        }
+
+        let mut report = QAAttributionReport::default();
+        // code -> (net volume, close) at the last settle
+        let mut last: HashMap<String, (f64, f64)> = HashMap::new();
+        for (date, slice) in days.iter() {
+            let mut codes: Vec<&String> = slice.positions.keys().collect();
+            codes.sort();
+            for code in codes {
+                let pos = &slice.positions[code];
+                let close = pos.lastest_price;
+                let volume = pos.volume_long_today + pos.volume_long_his
+                    - pos.volume_short_today
+                    - pos.volume_short_his;
+                let unit = pos.preset.unit_table as f64;
+                let code_preset = preset.get(code);
+
+                let mut row = QAAttribution {
+                    date: date.clone(),
+                    code: code.clone(),
+                    product: code_preset.name,
+                    exchange: code_preset.exchange,
+                    ..QAAttribution::default()
+                };
+                if let Some((last_volume, last_close)) = last.get(code) {
+                    row.holding_pnl = last_volume * (close - last_close) * unit;
+                }
+                let empty = vec![];
+                let today = fills.get(&(date.clone(), code.clone())).unwrap_or(&empty);
+                for (amount, price, commission, tax) in today.iter() {
+                    row.trading_pnl += amount * (close - price) * unit;
+                    row.commission += commission;
+                    row.tax += tax;
+                }
+                last.insert(code.clone(), (volume, close));
+                if row.holding_pnl == 0.0 && today.is_empty() {
+                    continue;
+                }
+                row.net_pnl = row.trading_pnl + row.holding_pnl - row.commission - row.tax;
+
+                report.by_code.entry(row.code.clone()).or_default().add(&row);
+                report.by_product.entry(row.product.clone()).or_default().add(&row);
+                report.by_exchange.entry(row.exchange.clone()).or_default().add(&row);
+                report.total.add(&row);
+                report.daily.push(row);
+            }
+        }
+        report
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attribution() {
+        let mut acc = QA_Account::new("RustT01B2_RBL8", "test", "admin", 1000000.0, false, "backtest");
+        let code = "rb2005";
+        acc.buy_open(code, 10.0, "2020-03-31 09:30:00", 3500.0);
+        acc.buy_open("000001", 1000.0, "2020-03-31 09:30:00", 12.0);
+        acc.on_price_change(code.to_string(), 3520.0, "2020-03-31 15:00:00".to_string());
+        acc.on_price_change("000001".to_string(), 12.2, "2020-03-31 15:00:00".to_string());
+        acc.settle();
+        acc.sell_close(code, 5.0, "2020-04-01 10:00:00", 3540.0);
+        acc.on_price_change(code.to_string(), 3550.0, "2020-04-01 15:00:00".to_string());
+        acc.on_price_change("000001".to_string(), 12.1, "2020-04-01 15:00:00".to_string());
+        acc.settle();
+
+        let report = QAAttributionReport::from_account(&acc);
+        println!("{}", report.to_json());
+        assert_eq!(report.daily.len(), 4);
+
+        let rb: Vec<&QAAttribution> = report.daily.iter().filter(|r| r.code == code).collect();
+        assert_eq!(rb[0].trading_pnl, 2000.0);
+        assert_eq!(rb[0].holding_pnl, 0.0);
+        assert_eq!(rb[1].holding_pnl, 3000.0);
+        assert_eq!(rb[1].trading_pnl, -500.0);
+        assert!(rb[1].commission > 0.0);
+        assert_eq!(rb[0].exchange, "SHFE");
+
+        let stock = &report.by_code["000001"];
+        assert!((stock.trading_pnl - 200.0).abs() < 1e-6);
+        assert!((stock.holding_pnl + 100.0).abs() < 1e-6);
+        assert_eq!(report.by_exchange["STOCK"].count, 2);
+
+        let gross = report.total.trading_pnl + report.total.holding_pnl;
+        // 2000 + 2500 on rb, 100 on the stock
+        assert!((gross - 4600.0).abs() < 1e-6);
+        assert!((report.total.net_pnl - (gross - report.total.commission - report.total.tax)).abs() < 1e-6);
+        assert_eq!(report.by_product.len(), 2);
+    }
+
+    #[test]
+    fn test_agree_with_position() {
+        let mut acc = QA_Account::new("attribution", "test", "admin", 1000000.0, false, "backtest");
+        let code = "rb2005";
+        let days = [
+            ("2020-03-31", Some((2, 2.0, 3500.0)), 3510.0),
+            ("2020-04-01", None, 3530.0),
+            ("2020-04-02", Some((-3, 1.0, 3520.0)), 3490.0),
+            ("2020-04-03", None, 3540.0),
+        ];
+        for (day, order, close) in days.iter() {
+            if let Some((towards, amount, price)) = order {
+                acc.send_order(code, *amount, &format!("{} 10:00:00", day), *towards, *price, "")
+                    .unwrap();
+            }
+            acc.on_price_change(code.to_string(), *close, format!("{} 15:00:00", day));
+            acc.settle();
+        }
+
+        let report = QAAttributionReport::from_account(&acc);
+        let mut slices: Vec<&QAAccountSlice> = acc.dailyassets.values().collect();
+        slices.sort_by(|a, b| a.datetime.cmp(&b.datetime));
+        let mut cumulative = 0.0;
+        let mut realized = 0.0;
+        for (row, slice) in report.daily.iter().zip(slices.iter()) {
+            cumulative += row.trading_pnl + row.holding_pnl;
+            realized += slice.accounts.close_profit;
+            // the running sum is what was closed plus the floating pnl of what is held
+            let mut pos = slice.positions[code].clone();
+            assert!((cumulative - realized - pos.float_profit()).abs() < 1e-6);
+        }
+        assert!((realized - 200.0).abs() < 1e-6);
+        // 1 lot 3500 -> 3520 closed, 1 lot 3500 -> 3540 held
+        assert_eq!(report.daily.len(), 4);
+        assert!((cumulative - (20.0 * 10.0 + 40.0 * 10.0)).abs() < 1e-6);
+    }
+}
@@ -0,0 +1,223 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::market_preset::MarketPreset;
+use crate::qaaccount::{QAAccountSlice, QA_Account};
+use crate::qafetch::BAR;
+use crate::qarisk::mean;
+use crate::trade_date::QATradeDate;
+
+/// turnover/exposure/margin of one settled trading day, ratios are relative to the balance
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QAExposurePoint {
+    pub date: String,
+    pub balance: f64,
+    /// notional of the fills of the day
+    pub traded_value: f64,
+    pub turnover: f64,
+    pub long_value: f64,
+    pub short_value: f64,
+    /// (long + short) / balance, also used as the leverage
+    pub gross_exposure: f64,
+    /// (long - short) / balance
+    pub net_exposure: f64,
+    pub margin: f64,
+    pub margin_utilization: f64,
+}
+
+/// fill volume vs the volume of the bar it was filled in
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QAFillParticipation {
+    pub datetime: String,
+    pub code: String,
+    pub trade_id: String,
+    pub amount: f64,
+    pub bar_datetime: String,
+    pub bar_volume: f64,
+    pub participation: f64,
+}
+
+/// 资金使用与容量分析
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QACapacityReport {
+    pub daily: Vec<QAExposurePoint>,
+    pub fills: Vec<QAFillParticipation>,
+    pub average_turnover: f64,
+    pub average_gross_exposure: f64,
+    pub average_net_exposure: f64,
+    pub average_margin_utilization: f64,
+    pub max_margin_utilization: f64,
+    pub average_leverage: f64,
+    pub max_leverage: f64,
+    pub average_participation: f64,
+    pub max_participation: f64,
+    /// the highest participation rate we accept, like 0.1 for 10% of the bar volume
+    pub participation_limit: f64,
+    /// the capital can be scaled by this before the largest fill hits the limit
+    pub capacity_multiplier: f64,
+    /// average balance * capacity_multiplier
+    pub capacity: f64,
+}
+
+fn max(data: &[f64]) -> f64 {
+    data.iter().cloned().fold(0.0, f64::max)
+}
+
+impl QACapacityReport {
+    /// bars are used for the participation rate, a fill is matched with the last bar of its code
+    /// whose datetime is not later than the fill, fills without a bar are skipped
+    pub fn from_account(acc: &QA_Account, bars: &[BAR], participation_limit: f64) -> Self {
+        let mut td = QATradeDate::new();
+        let mut preset = MarketPreset::new();
+
+        let mut traded: HashMap<String, f64> = HashMap::new();
+        for t in acc.history.iter() {
+            let unit = preset.get(&t.code).unit_table as f64;
+            *traded.entry(td.get_trade_day(t.datetime.clone())).or_default() +=
+                t.amount.abs() * t.price * unit;
+        }
+
+        // the last slice of every trading day, the same as QAEquityCurve
+        let mut slices: Vec<&QAAccountSlice> = acc.dailyassets.values().collect();
+        slices.sort_by(|a, b| a.datetime.cmp(&b.datetime));
+        let mut days: BTreeMap<String, &QAAccountSlice> = BTreeMap::new();
+        for slice in slices {
+            days.insert(td.get_trade_day(slice.datetime.clone()), slice);
+        }
+
+        let mut report = QACapacityReport {
+            participation_limit,
+            ..QACapacityReport::default()
+        };
+        for (date, slice) in days.iter() {
+            let balance = slice.accounts.balance;
+            let mut point = QAExposurePoint {
+                date: date.clone(),
+                balance,
+                traded_value: traded.get(date).cloned().unwrap_or(0.0),
+                margin: slice.accounts.margin,
+                ..QAExposurePoint::default()
+            };
+            for pos in slice.positions.values() {
+                let unit = pos.preset.unit_table as f64 * pos.lastest_price;
+                point.long_value += (pos.volume_long_today + pos.volume_long_his) * unit;
+                point.short_value += (pos.volume_short_today + pos.volume_short_his) * unit;
+            }
+            if balance > 0.0 {
+                point.turnover = point.traded_value / balance;
+                point.gross_exposure = (point.long_value + point.short_value) / balance;
+                point.net_exposure = (point.long_value - point.short_value) / balance;
+                point.margin_utilization = point.margin / balance;
+            }
+            report.daily.push(point);
+        }
+
+        let mut bars_by_code: HashMap<&str, Vec<&BAR>> = HashMap::new();
+        for bar in bars {
+            bars_by_code.entry(bar.code.as_str()).or_default().push(bar);
+        }
+        for v in bars_by_code.values_mut() {
+            v.sort_by(|a, b| a.datetime.cmp(&b.datetime));
+        }
+        for t in acc.history.iter() {
+            let bar = bars_by_code.get(t.code.as_str()).and_then(|v| {
+                let i = v
+                    .binary_search_by(|b| if b.datetime <= t.datetime { Ordering::Less } else { Ordering::Greater })
+                    .unwrap_err();
+                if i > 0 {
+                    Some(v[i - 1])
+                } else {
+                    None
+                }
+            });
+            if let Some(bar) = bar {
+                report.fills.push(QAFillParticipation {
+                    datetime: t.datetime.clone(),
+                    code: t.code.clone(),
+                    trade_id: t.trade_id.clone(),
+                    amount: t.amount.abs(),
+                    bar_datetime: bar.datetime.clone(),
+                    bar_volume: bar.volume,
+                    participation: if bar.volume > 0.0 {
+                        t.amount.abs() / bar.volume
+                    } else {
+                        std::f64::INFINITY
+                    },
+                });
+            }
+        }
+
+        let column = |f: fn(&QAExposurePoint) -> f64| -> Vec<f64> { report.daily.iter().map(f).collect() };
+        let turnover = column(|p| p.turnover);
+        let gross = column(|p| p.gross_exposure);
+        let net = column(|p| p.net_exposure);
+        let margin = column(|p| p.margin_utilization);
+        let balance = column(|p| p.balance);
+        let participation: Vec<f64> = report.fills.iter().map(|f| f.participation).collect();
+
+        report.average_turnover = mean(&turnover);
+        report.average_gross_exposure = mean(&gross);
+        report.average_net_exposure = mean(&net);
+        report.average_margin_utilization = mean(&margin);
+        report.max_margin_utilization = max(&margin);
+        report.average_leverage = report.average_gross_exposure;
+        report.max_leverage = max(&gross);
+        report.average_participation = mean(&participation);
+        report.max_participation = max(&participation);
+        report.capacity_multiplier = if report.max_participation > 0.0 {
+            participation_limit / report.max_participation
+        } else {
+            std::f64::INFINITY
+        };
+        report.capacity = mean(&balance) * report.capacity_multiplier;
+        report
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::bar;
+
+    #[test]
+    fn test_capacity() {
+        let mut acc = QA_Account::new("RustT01B2_RBL8", "test", "admin", 1000000.0, false, "backtest");
+        let code = "rb2005";
+        acc.buy_open(code, 10.0, "2020-03-31 09:30:00", 3500.0);
+        acc.on_price_change(code.to_string(), 3500.0, "2020-03-31 15:00:00".to_string());
+        acc.settle();
+        acc.sell_close(code, 10.0, "2020-04-01 10:00:00", 3500.0);
+        acc.settle();
+
+        let bars = vec![
+            bar(code, "2020-03-31 09:29:00", 3500.0).volume(1000.0).build(),
+            bar(code, "2020-03-31 09:30:00", 3500.0).volume(100.0).build(),
+            bar(code, "2020-04-01 10:00:00", 3500.0).volume(50.0).build(),
+        ];
+        let report = QACapacityReport::from_account(&acc, &bars, 0.1);
+        println!("{}", report.to_json());
+
+        assert_eq!(report.daily.len(), 2);
+        let day = &report.daily[0];
+        assert_eq!(day.traded_value, 350000.0);
+        assert!((day.turnover - 350000.0 / day.balance).abs() < 1e-12);
+        assert_eq!(day.long_value, 350000.0);
+        assert_eq!(day.short_value, 0.0);
+        assert_eq!(day.gross_exposure, day.net_exposure);
+        assert!(day.margin_utilization > 0.0 && day.margin_utilization < day.gross_exposure);
+        assert_eq!(report.daily[1].gross_exposure, 0.0);
+        assert_eq!(report.daily[1].margin, 0.0);
+
+        assert_eq!(report.fills.len(), 2);
+        assert_eq!(report.fills[0].bar_volume, 100.0);
+        assert_eq!(report.max_participation, 0.2);
+        assert!((report.capacity_multiplier - 0.5).abs() < 1e-12);
+        assert!((report.average_participation - 0.15).abs() < 1e-12);
+    }
+}
@@ -0,0 +1,397 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::qafetch::BAR;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum QATrailDistance {
+    /// number of price_tick
+    Ticks(f64),
+    /// 0.01 means 1% of the best price
+    Percent(f64),
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum QAConditionalKind {
+    /// market order once the price crosses trigger against the position
+    StopMarket { trigger: f64 },
+    /// limit order at limit once the price crosses trigger
+    StopLimit { trigger: f64, limit: f64 },
+    /// market order once the price crosses trigger in favour of the position
+    TakeProfit { trigger: f64 },
+    /// stop that follows the best price by distance, only after the best price
+    /// has reached activation (TrailingStart in t01b2)
+    TrailingStop {
+        distance: QATrailDistance,
+        activation: Option<f64>,
+        price_tick: f64,
+        /// the highest price for a sell, the lowest for a buy
+        best: Option<f64>,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum QAConditionalStatus {
+    Pending,
+    /// a stop-limit whose limit was not reachable yet
+    Triggered,
+    Filled,
+    Cancelled,
+    /// the account refused the order
+    Rejected,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QAConditionalOrder {
+    pub id: String,
+    pub code: String,
+    /// the towards of the order sent when triggered, like -3 (sell close) to protect a long
+    pub towards: i32,
+    pub amount: f64,
+    pub kind: QAConditionalKind,
+    pub status: QAConditionalStatus,
+    /// orders of the same group cancel each other once one is filled
+    pub oco_group: Option<String>,
+    pub created: String,
+    pub triggered: String,
+    pub fill_price: f64,
+}
+
+/// 条件单, 止损/止盈/跟踪止损/OCO, 由账户在行情变化时检查, 触发后走正常的 send_order
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QAConditionalBook {
+    pub orders: BTreeMap<String, QAConditionalOrder>,
+    /// filled, cancelled and rejected orders
+    pub history: Vec<QAConditionalOrder>,
+    next_id: u64,
+}
+
+impl QAConditionalOrder {
+    fn is_sell(&self) -> bool {
+        self.towards < 0
+    }
+
+    /// the stop level of a trailing stop, None if not active yet
+    pub fn trailing_level(&self) -> Option<f64> {
+        if let QAConditionalKind::TrailingStop {
+            distance,
+            activation,
+            price_tick,
+            best: Some(best),
+        } = &self.kind
+        {
+            let active = match activation {
+                Some(a) if self.is_sell() => best >= a,
+                Some(a) => best <= a,
+                None => true,
+            };
+            if !active {
+                return None;
+            }
+            let d = match distance {
+                QATrailDistance::Ticks(n) => n * price_tick,
+                QATrailDistance::Percent(p) => best * p,
+            };
+            return Some(if self.is_sell() { best - d } else { best + d });
+        }
+        None
+    }
+
+    fn update_best(&mut self, high: f64, low: f64) {
+        let sell = self.is_sell();
+        if let QAConditionalKind::TrailingStop { best, .. } = &mut self.kind {
+            let price = if sell { high } else { low };
+            *best = Some(match best {
+                Some(b) if sell => b.max(price),
+                Some(b) => b.min(price),
+                None => price,
+            });
+        }
+    }
+
+    /// a stop is touched when the price moves against the position, a take-profit when in favour
+    fn stop_touched(&self, level: f64, high: f64, low: f64) -> bool {
+        if self.is_sell() {
+            low <= level
+        } else {
+            high >= level
+        }
+    }
+
+    fn profit_touched(&self, level: f64, high: f64, low: f64) -> bool {
+        if self.is_sell() {
+            high >= level
+        } else {
+            low <= level
+        }
+    }
+
+    /// fill price of a market order at a level, a gap over the level fills at the open
+    fn gap_price(&self, level: f64, open: f64, against: bool) -> f64 {
+        if self.is_sell() == against {
+            level.min(open)
+        } else {
+            level.max(open)
+        }
+    }
+
+    /// check one price range, open/high/low for a bar or the same price three times for a tick,
+    /// returns the fill price if the order should be sent now
+    fn check(&mut self, open: f64, high: f64, low: f64, is_bar: bool) -> Option<f64> {
+        match self.kind.clone() {
+            QAConditionalKind::StopMarket { trigger } => {
+                if self.stop_touched(trigger, high, low) {
+                    return Some(self.gap_price(trigger, open, true));
+                }
+            }
+            QAConditionalKind::TakeProfit { trigger } => {
+                if self.profit_touched(trigger, high, low) {
+                    return Some(self.gap_price(trigger, open, false));
+                }
+            }
+            QAConditionalKind::StopLimit { trigger, limit } => {
+                if self.status == QAConditionalStatus::Pending && self.stop_touched(trigger, high, low) {
+                    self.status = QAConditionalStatus::Triggered;
+                }
+                // a sell limit needs a price at or above the limit
+                if self.status == QAConditionalStatus::Triggered && self.profit_touched(limit, high, low) {
+                    return Some(if is_bar { limit } else { open });
+                }
+            }
+            QAConditionalKind::TrailingStop { .. } => {
+                // the order of high and low inside a bar is unknown, so the stop is checked
+                // with the best price of the previous bars before the bar moves it
+                if let Some(level) = self.trailing_level() {
+                    if self.stop_touched(level, high, low) {
+                        return Some(self.gap_price(level, open, true));
+                    }
+                }
+                self.update_best(high, low);
+                if !is_bar {
+                    if let Some(level) = self.trailing_level() {
+                        if self.stop_touched(level, high, low) {
+                            return Some(open);
+                        }
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+impl QAConditionalBook {
+    pub fn new() -> Self {
+        QAConditionalBook::default()
+    }
+
+    pub fn place(
+        &mut self,
+        code: &str,
+        towards: i32,
+        amount: f64,
+        kind: QAConditionalKind,
+        datetime: &str,
+    ) -> String {
+        self.next_id += 1;
+        let id = format!("COND_{}", self.next_id);
+        self.orders.insert(
+            id.clone(),
+            QAConditionalOrder {
+                id: id.clone(),
+                code: code.to_string(),
+                towards,
+                amount,
+                kind,
+                status: QAConditionalStatus::Pending,
+                oco_group: None,
+                created: datetime.to_string(),
+                triggered: String::new(),
+                fill_price: 0.0,
+            },
+        );
+        id
+    }
+
+    /// put the orders in one OCO group, named after the first id
+    pub fn link_oco(&mut self, ids: &[String]) {
+        if let Some(first) = ids.first() {
+            for id in ids {
+                if let Some(order) = self.orders.get_mut(id) {
+                    order.oco_group = Some(first.clone());
+                }
+            }
+        }
+    }
+
+    pub fn cancel(&mut self, id: &str) -> Option<QAConditionalOrder> {
+        self.orders.remove(id).map(|mut order| {
+            order.status = QAConditionalStatus::Cancelled;
+            self.history.push(order.clone());
+            order
+        })
+    }
+
+    pub fn cancel_code(&mut self, code: &str) {
+        let ids: Vec<String> = self
+            .orders
+            .values()
+            .filter(|o| o.code == code)
+            .map(|o| o.id.clone())
+            .collect();
+        for id in ids {
+            self.cancel(&id);
+        }
+    }
+
+    pub fn get_orders(&self, code: &str) -> Vec<&QAConditionalOrder> {
+        self.orders.values().filter(|o| o.code == code).collect()
+    }
+
+    /// orders to send after a new price, removed from the book
+    pub fn on_price(&mut self, code: &str, price: f64, datetime: &str) -> Vec<QAConditionalOrder> {
+        self.collect(code, datetime, |o| o.check(price, price, price, false))
+    }
+
+    /// orders to send after a bar, triggered by its high/low
+    pub fn on_bar(&mut self, bar: &BAR) -> Vec<QAConditionalOrder> {
+        self.collect(&bar.code, &bar.datetime, |o| o.check(bar.open, bar.high, bar.low, true))
+    }
+
+    fn collect<F>(&mut self, code: &str, datetime: &str, mut check: F) -> Vec<QAConditionalOrder>
+    where
+        F: FnMut(&mut QAConditionalOrder) -> Option<f64>,
+    {
+        let mut res: Vec<QAConditionalOrder> = vec![];
+        for order in self.orders.values_mut().filter(|o| o.code == code) {
+            // one leg of an oco group at most
+            if order.oco_group.is_some() && res.iter().any(|o| o.oco_group == order.oco_group) {
+                continue;
+            }
+            if let Some(price) = check(order) {
+                order.fill_price = price;
+                order.triggered = datetime.to_string();
+                res.push(order.clone());
+            }
+        }
+        for order in res.iter() {
+            self.orders.remove(&order.id);
+            if let Some(group) = &order.oco_group {
+                let others: Vec<String> = self
+                    .orders
+                    .values()
+                    .filter(|o| o.oco_group.as_ref() == Some(group))
+                    .map(|o| o.id.clone())
+                    .collect();
+                for id in others {
+                    self.cancel(&id);
+                }
+            }
+        }
+        res
+    }
+
+    /// record the result of sending a triggered order
+    pub fn finish(&mut self, mut order: QAConditionalOrder, filled: bool) {
+        order.status = if filled {
+            QAConditionalStatus::Filled
+        } else {
+            QAConditionalStatus::Rejected
+        };
+        self.history.push(order);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::bar;
+
+    #[test]
+    fn test_stop_and_take_profit() {
+        let mut book = QAConditionalBook::new();
+        let stop = book.place("rb2005", -3, 1.0, QAConditionalKind::StopMarket { trigger: 3480.0 }, "");
+        let take = book.place("rb2005", -3, 1.0, QAConditionalKind::TakeProfit { trigger: 3550.0 }, "");
+        book.link_oco(&[stop.clone(), take]);
+        assert!(book.on_price("rb2005", 3500.0, "").is_empty());
+        assert!(book.on_price("j2005", 3000.0, "").is_empty());
+        // gap below the stop fills at the open
+        let fired = book.on_bar(
+            &bar("rb2005", "2020-03-31 09:01:00", 3470.0)
+                .high(3490.0)
+                .low(3460.0)
+                .build(),
+        );
+        assert_eq!(fired.len(), 1);
+        assert_eq!(fired[0].id, stop);
+        assert_eq!(fired[0].fill_price, 3470.0);
+        assert!(book.orders.is_empty());
+        assert_eq!(book.history[0].status, QAConditionalStatus::Cancelled);
+    }
+
+    #[test]
+    fn test_stop_limit() {
+        let mut book = QAConditionalBook::new();
+        book.place(
+            "rb2005",
+            3,
+            1.0,
+            QAConditionalKind::StopLimit {
+                trigger: 3520.0,
+                limit: 3525.0,
+            },
+            "",
+        );
+        // triggered, a buy limit needs a price at or below the limit
+        assert!(book.on_price("rb2005", 3530.0, "").is_empty());
+        assert_eq!(book.get_orders("rb2005")[0].status, QAConditionalStatus::Triggered);
+        let fired = book.on_price("rb2005", 3524.0, "");
+        assert_eq!(fired[0].fill_price, 3524.0);
+    }
+
+    #[test]
+    fn test_trailing_stop() {
+        let mut book = QAConditionalBook::new();
+        book.place(
+            "rb2005",
+            -3,
+            1.0,
+            QAConditionalKind::TrailingStop {
+                distance: QATrailDistance::Ticks(10.0),
+                activation: Some(3550.0),
+                price_tick: 1.0,
+                best: None,
+            },
+            "",
+        );
+        // not active below 3550
+        assert!(book.on_price("rb2005", 3540.0, "").is_empty());
+        assert!(book.on_price("rb2005", 3520.0, "").is_empty());
+        assert!(book.on_price("rb2005", 3560.0, "").is_empty());
+        assert_eq!(book.get_orders("rb2005")[0].trailing_level(), Some(3550.0));
+        let fired = book.on_bar(
+            &bar("rb2005", "2020-03-31 09:01:00", 3555.0)
+                .high(3570.0)
+                .low(3549.0)
+                .build(),
+        );
+        assert_eq!(fired[0].fill_price, 3550.0);
+
+        book.place(
+            "rb2005",
+            2,
+            1.0,
+            QAConditionalKind::TrailingStop {
+                distance: QATrailDistance::Percent(0.01),
+                activation: None,
+                price_tick: 1.0,
+                best: None,
+            },
+            "",
+        );
+        assert!(book.on_price("rb2005", 3000.0, "").is_empty());
+        assert!(book.on_price("rb2005", 3029.0, "").is_empty());
+        assert_eq!(book.on_price("rb2005", 3030.0, "").len(), 1);
+    }
+}
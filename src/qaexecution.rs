@@ -0,0 +1,444 @@
+use std::cell::Cell;
+use std::collections::BTreeSet;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+use crate::qaaccount::QA_Account;
+use crate::qaconditional::QATrailDistance;
+use crate::qafetch::BAR;
+use crate::qaposition::QA_Postions;
+use crate::trade_date::QATradeDate;
+
+/// when and at which price the orders of on_bar are filled
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum QAExecutionMode {
+    /// filled at once at the price given by the strategy, the same as calling QA_Account directly
+    SameBar,
+    /// queued and filled at the open of the next bar of the code
+    NextOpen,
+    /// queued and filled at the vwap of the next bar of the code, (high + low + close) / 3
+    /// since a bar does not carry the traded amount
+    NextVwap,
+}
+
+const OPEN: u8 = 1;
+const HIGH: u8 = 2;
+const LOW: u8 = 4;
+const CLOSE: u8 = 8;
+const VOLUME: u8 = 16;
+
+/// read-only bar handed to the strategy, it records which prices were read
+#[derive(Debug)]
+pub struct QABarView<'a> {
+    bar: &'a BAR,
+    read: Cell<u8>,
+}
+
+impl<'a> QABarView<'a> {
+    pub fn new(bar: &'a BAR) -> Self {
+        QABarView {
+            bar,
+            read: Cell::new(0),
+        }
+    }
+
+    fn mark(&self, field: u8) {
+        self.read.set(self.read.get() | field);
+    }
+
+    pub fn code(&self) -> &str {
+        &self.bar.code
+    }
+    pub fn datetime(&self) -> &str {
+        &self.bar.datetime
+    }
+    pub fn open(&self) -> f64 {
+        self.mark(OPEN);
+        self.bar.open
+    }
+    pub fn high(&self) -> f64 {
+        self.mark(HIGH);
+        self.bar.high
+    }
+    pub fn low(&self) -> f64 {
+        self.mark(LOW);
+        self.bar.low
+    }
+    pub fn close(&self) -> f64 {
+        self.mark(CLOSE);
+        self.bar.close
+    }
+    pub fn volume(&self) -> f64 {
+        self.mark(VOLUME);
+        self.bar.volume
+    }
+
+    /// fields that are only known once the bar is finished
+    fn unknown_at_open(&self) -> Vec<String> {
+        let read = self.read.get();
+        [(HIGH, "high"), (LOW, "low"), (CLOSE, "close"), (VOLUME, "volume")]
+            .iter()
+            .filter(|(flag, _)| read & flag != 0)
+            .map(|(_, name)| name.to_string())
+            .collect()
+    }
+}
+
+/// an order of on_bar waiting for the next bar of its code
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QAPendingOrder {
+    pub code: String,
+    pub amount: f64,
+    pub towards: i32,
+    /// the bar the signal was computed on
+    pub signal_datetime: String,
+}
+
+/// same-bar orders sent after reading prices the bar had not printed yet when it opened
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QALookAheadWarning {
+    pub datetime: String,
+    pub code: String,
+    pub fields: Vec<String>,
+}
+
+/// orders and account queries available in on_bar, the account itself is read-only
+/// so every order goes through the execution mode
+pub struct QAExecutionContext<'a> {
+    acc: &'a mut QA_Account,
+    mode: QAExecutionMode,
+    datetime: String,
+    pending: &'a mut Vec<QAPendingOrder>,
+    rejected: &'a mut Vec<QAPendingOrder>,
+    sent: bool,
+}
+
+impl<'a> QAExecutionContext<'a> {
+    pub fn mode(&self) -> QAExecutionMode {
+        self.mode
+    }
+
+    pub fn account(&self) -> &QA_Account {
+        self.acc
+    }
+    pub fn get_cash(&mut self) -> f64 {
+        self.acc.get_cash()
+    }
+    pub fn get_balance(&mut self) -> f64 {
+        self.acc.get_balance()
+    }
+    /// 0 if the code has no position
+    pub fn get_volume_long(&mut self, code: &str) -> f64 {
+        self.acc.get_position(code).map(|p| p.volume_long()).unwrap_or(0.0)
+    }
+    pub fn get_volume_short(&mut self, code: &str) -> f64 {
+        self.acc.get_position(code).map(|p| p.volume_short()).unwrap_or(0.0)
+    }
+    /// a copy of the position
+    pub fn get_position(&mut self, code: &str) -> Option<QA_Postions> {
+        self.acc.get_position(code).map(|p| p.clone())
+    }
+
+    /// price is only used by SameBar, the other modes fill at the next bar
+    pub fn send_order(&mut self, code: &str, amount: f64, towards: i32, price: f64) {
+        self.sent = true;
+        let order = QAPendingOrder {
+            code: code.to_string(),
+            amount,
+            towards,
+            signal_datetime: self.datetime.clone(),
+        };
+        match self.mode {
+            QAExecutionMode::SameBar => {
+                if self
+                    .acc
+                    .send_order(code, amount, &self.datetime, towards, price, "")
+                    .is_err()
+                {
+                    self.rejected.push(order);
+                }
+            }
+            _ => self.pending.push(order),
+        }
+    }
+
+    pub fn buy(&mut self, code: &str, amount: f64, price: f64) {
+        self.send_order(code, amount, 1, price)
+    }
+    pub fn sell(&mut self, code: &str, amount: f64, price: f64) {
+        self.send_order(code, amount, -1, price)
+    }
+    pub fn buy_open(&mut self, code: &str, amount: f64, price: f64) {
+        self.send_order(code, amount, 2, price)
+    }
+    pub fn sell_open(&mut self, code: &str, amount: f64, price: f64) {
+        self.send_order(code, amount, -2, price)
+    }
+    pub fn buy_close(&mut self, code: &str, amount: f64, price: f64) {
+        self.send_order(code, amount, 3, price)
+    }
+    pub fn sell_close(&mut self, code: &str, amount: f64, price: f64) {
+        self.send_order(code, amount, -3, price)
+    }
+
+    /// conditional orders go to the account book, checked from the next bar on with its high/low
+    pub fn stop_market(&mut self, code: &str, towards: i32, amount: f64, trigger: f64) -> String {
+        self.acc.stop_market(code, towards, amount, trigger)
+    }
+    pub fn stop_limit(&mut self, code: &str, towards: i32, amount: f64, trigger: f64, limit: f64) -> String {
+        self.acc.stop_limit(code, towards, amount, trigger, limit)
+    }
+    pub fn take_profit(&mut self, code: &str, towards: i32, amount: f64, trigger: f64) -> String {
+        self.acc.take_profit(code, towards, amount, trigger)
+    }
+    pub fn trailing_stop(
+        &mut self,
+        code: &str,
+        towards: i32,
+        amount: f64,
+        distance: QATrailDistance,
+        activation: Option<f64>,
+    ) -> String {
+        self.acc.trailing_stop(code, towards, amount, distance, activation)
+    }
+    /// the position must be open already, an order still queued by NextOpen/NextVwap is not
+    pub fn bracket(&mut self, code: &str, stop: f64, take_profit: f64) -> Result<(String, String), ()> {
+        self.acc.bracket(code, stop, take_profit)
+    }
+    pub fn cancel_conditional(&mut self, id: &str) -> Result<(), ()> {
+        self.acc.cancel_conditional(id)
+    }
+}
+
+/// a strategy receives every bar after the account has been marked to its close,
+/// its orders are filled by the execution mode of the engine
+pub trait QAStrategy {
+    fn on_bar(&mut self, ctx: &mut QAExecutionContext, bar: &QABarView);
+}
+
+/// settles the account whenever the trading day changes, shared by the backtest loops
+#[derive(Debug, Clone, Default)]
+pub struct QASettleClock {
+    td: QATradeDate,
+    trading_day: String,
+}
+
+impl QASettleClock {
+    pub fn new() -> Self {
+        QASettleClock::default()
+    }
+
+    /// settle the previous day if datetime starts a new trading day, returns the trading day
+    pub fn advance(&mut self, acc: &mut QA_Account, datetime: &str) -> String {
+        let day = self.td.get_trade_day(datetime.to_string());
+        if !self.trading_day.is_empty() && day != self.trading_day {
+            acc.settle();
+        }
+        self.trading_day = day.clone();
+        day
+    }
+
+    /// settle the last trading day, if any
+    pub fn finish(&mut self, acc: &mut QA_Account) {
+        if !self.trading_day.is_empty() {
+            acc.settle();
+            self.trading_day.clear();
+        }
+    }
+}
+
+/// 回测撮合, NextOpen/NextVwap 模式下 on_bar 的订单在下一根 bar 成交, 避免使用未来数据
+#[derive(Debug, Clone)]
+pub struct QAExecutionEngine {
+    pub mode: QAExecutionMode,
+    pub pending: Vec<QAPendingOrder>,
+    pub warnings: Vec<QALookAheadWarning>,
+    /// orders the account refused, at fill time for the queued ones
+    pub rejected: Vec<QAPendingOrder>,
+    /// (code, fields) already logged
+    logged: BTreeSet<(String, String)>,
+}
+
+impl QAExecutionEngine {
+    pub fn new(mode: QAExecutionMode) -> Self {
+        QAExecutionEngine {
+            mode,
+            pending: vec![],
+            warnings: vec![],
+            rejected: vec![],
+            logged: BTreeSet::new(),
+        }
+    }
+
+    fn fill_price(&self, bar: &BAR) -> f64 {
+        match self.mode {
+            QAExecutionMode::NextVwap => (bar.high + bar.low + bar.close) / 3.0,
+            _ => bar.open,
+        }
+    }
+
+    /// fill the queued orders of the bar's code
+    fn fill(&mut self, acc: &mut QA_Account, bar: &BAR) {
+        let price = self.fill_price(bar);
+        let (now, later): (Vec<QAPendingOrder>, Vec<QAPendingOrder>) =
+            self.pending.drain(..).partition(|o| o.code == bar.code);
+        self.pending = later;
+        for order in now {
+            if acc
+                .send_order(&order.code, order.amount, &bar.datetime, order.towards, price, "")
+                .is_err()
+            {
+                self.rejected.push(order);
+            }
+        }
+    }
+
+    /// fill the queued orders of the bar's code at its open, then check the conditional
+    /// orders with the bar and mark the account to its close
+    pub fn mark(&mut self, acc: &mut QA_Account, bar: &BAR) {
+        acc.change_datetime(bar.datetime.clone());
+        self.fill(acc, bar);
+        acc.on_bar(bar);
+    }
+
+    /// hand the bar to the strategy, its orders go through the execution mode
+    pub fn signal<S: QAStrategy>(&mut self, strategy: &mut S, acc: &mut QA_Account, bar: &BAR) {
+        let view = QABarView::new(bar);
+        let mut pending = vec![];
+        let sent = {
+            let mut ctx = QAExecutionContext {
+                acc,
+                mode: self.mode,
+                datetime: bar.datetime.clone(),
+                pending: &mut pending,
+                rejected: &mut self.rejected,
+                sent: false,
+            };
+            strategy.on_bar(&mut ctx, &view);
+            ctx.sent
+        };
+        self.pending.extend(pending);
+
+        let fields = view.unknown_at_open();
+        if self.mode == QAExecutionMode::SameBar && sent && !fields.is_empty() {
+            if self.logged.insert((bar.code.clone(), fields.join(","))) {
+                warn!(
+                    "look-ahead: {} {} filled in the same bar after reading {}",
+                    bar.code,
+                    bar.datetime,
+                    fields.join(",")
+                );
+            }
+            self.warnings.push(QALookAheadWarning {
+                datetime: bar.datetime.clone(),
+                code: bar.code.clone(),
+                fields,
+            });
+        }
+    }
+
+    /// replay the bars on a new backtest account, settled at every new trading day and
+    /// after the last bar, orders still queued at the end are left in pending
+    pub fn run<S: QAStrategy>(
+        &mut self,
+        strategy: &mut S,
+        bars: &[BAR],
+        account_cookie: &str,
+        init_cash: f64,
+    ) -> QA_Account {
+        let mut clock = QASettleClock::new();
+        let mut acc = QA_Account::new(account_cookie, "execution", "admin", init_cash, false, "backtest");
+        for bar in bars {
+            clock.advance(&mut acc, &bar.datetime);
+            self.mark(&mut acc, bar);
+            self.signal(strategy, &mut acc, bar);
+        }
+        clock.finish(&mut acc);
+        acc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::bar;
+
+    /// buy on the first up bar, sell on the next one
+    struct UpBar {
+        count: usize,
+    }
+
+    impl QAStrategy for UpBar {
+        fn on_bar(&mut self, ctx: &mut QAExecutionContext, bar: &QABarView) {
+            if bar.close() > bar.open() {
+                match self.count {
+                    0 => ctx.buy_open(bar.code(), 1.0, bar.close()),
+                    1 => ctx.sell_close(bar.code(), 1.0, bar.close()),
+                    _ => {}
+                }
+                self.count += 1;
+            }
+        }
+    }
+
+    fn bars() -> Vec<BAR> {
+        [
+            ("2020-03-31 09:00:00", 3500.0, 3510.0),
+            ("2020-03-31 09:01:00", 3512.0, 3520.0),
+            ("2020-03-31 09:02:00", 3522.0, 3530.0),
+            ("2020-03-31 09:03:00", 3528.0, 3525.0),
+        ]
+        .iter()
+        .map(|(datetime, open, close)| {
+            bar("rb2005", datetime, *open)
+                .close(*close)
+                .high(open.max(*close) + 10.0)
+                .low(open.min(*close) - 10.0)
+                .build()
+        })
+        .collect()
+    }
+
+    #[test]
+    fn test_same_bar() {
+        let mut engine = QAExecutionEngine::new(QAExecutionMode::SameBar);
+        let acc = engine.run(&mut UpBar { count: 0 }, &bars(), "exec", 1000000.0);
+        assert_eq!(acc.history.len(), 2);
+        assert_eq!(acc.history[0].price, 3510.0);
+        assert_eq!(engine.warnings.len(), 2);
+        assert_eq!(engine.warnings[0].fields, vec!["close"]);
+        assert!(engine.rejected.is_empty());
+
+        // the first up bar closes a position that does not exist
+        let mut engine = QAExecutionEngine::new(QAExecutionMode::SameBar);
+        let acc = engine.run(&mut UpBar { count: 1 }, &bars(), "exec", 1000000.0);
+        assert!(acc.history.is_empty());
+        assert_eq!(engine.rejected.len(), 1);
+        assert_eq!(engine.rejected[0].towards, -3);
+    }
+
+    #[test]
+    fn test_next_bar() {
+        let mut engine = QAExecutionEngine::new(QAExecutionMode::NextOpen);
+        let acc = engine.run(&mut UpBar { count: 0 }, &bars(), "exec", 1000000.0);
+        assert!(engine.warnings.is_empty());
+        assert_eq!(acc.history.len(), 2);
+        assert_eq!(acc.history[0].price, 3512.0);
+        assert_eq!(acc.history[0].datetime, "2020-03-31 09:01:00");
+        assert_eq!(acc.history[1].price, 3522.0);
+
+        let mut engine = QAExecutionEngine::new(QAExecutionMode::NextVwap);
+        let acc = engine.run(&mut UpBar { count: 0 }, &bars(), "exec", 1000000.0);
+        // (3530 + 3502 + 3520) / 3
+        assert!((acc.history[0].price - 3517.333333333333).abs() < 1e-6);
+
+        // the signal of the last bar has no bar to be filled in
+        let mut engine = QAExecutionEngine::new(QAExecutionMode::NextOpen);
+        let mut short = bars();
+        short.truncate(1);
+        let acc = engine.run(&mut UpBar { count: 0 }, &short, "exec", 1000000.0);
+        assert!(acc.history.is_empty());
+        assert_eq!(engine.pending.len(), 1);
+    }
+}
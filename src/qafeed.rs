@@ -0,0 +1,215 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use crate::qaaccount::QA_Account;
+use crate::qaexecution::QASettleClock;
+use crate::qafetch::BAR;
+
+/// all the bars sharing one timestamp
+#[derive(Debug, Clone, Default)]
+pub struct QABarSlice {
+    pub datetime: String,
+    /// code -> bar, only the codes that have a bar at this datetime
+    pub bars: BTreeMap<String, BAR>,
+    /// code -> the last bar seen up to this datetime, for every code of the feed
+    pub latest: BTreeMap<String, BAR>,
+}
+
+impl QABarSlice {
+    pub fn get(&self, code: &str) -> Option<&BAR> {
+        self.bars.get(code)
+    }
+
+    /// the code has no bar at this datetime, latest holds an older one if any
+    pub fn is_missing(&self, code: &str) -> bool {
+        !self.bars.contains_key(code)
+    }
+
+    /// close of the latest bar, None if the code has not started yet
+    pub fn price(&self, code: &str) -> Option<f64> {
+        self.latest.get(code).map(|b| b.close)
+    }
+}
+
+/// 多品种行情源, 将多个 bar 序列按时间合并, 同一时间的 bar 组成一个 slice
+#[derive(Debug, Clone, Default)]
+pub struct QABarFeed {
+    bars: Vec<BAR>,
+    cursor: usize,
+    latest: BTreeMap<String, BAR>,
+}
+
+/// strategies that need all the instruments at once (cross-sectional, pairs ...)
+pub trait QAMultiStrategy {
+    fn on_slice(&mut self, acc: &mut QA_Account, slice: &QABarSlice);
+}
+
+impl QABarFeed {
+    pub fn new() -> Self {
+        QABarFeed::default()
+    }
+
+    pub fn from_sources(sources: Vec<Vec<BAR>>) -> Self {
+        let mut feed = QABarFeed::new();
+        for bars in sources {
+            feed.add_bars(bars);
+        }
+        feed
+    }
+
+    /// one source, usually one code, the sources do not need to share timestamps
+    pub fn add_bars(&mut self, bars: Vec<BAR>) {
+        self.bars.extend(bars);
+        // stable, bars at the same datetime keep the order they were added
+        self.bars.sort_by(|a, b| a.datetime.cmp(&b.datetime));
+    }
+
+    /// csv with the BAR columns, code,datetime,open,high,low,close,volume
+    pub fn add_csv(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        let mut rdr = csv::Reader::from_path(path)?;
+        let mut bars = vec![];
+        for result in rdr.deserialize() {
+            let bar: BAR = result?;
+            bars.push(bar);
+        }
+        self.add_bars(bars);
+        Ok(())
+    }
+
+    pub fn codes(&self) -> Vec<String> {
+        let mut codes: Vec<String> = self.bars.iter().map(|b| b.code.clone()).collect();
+        codes.sort();
+        codes.dedup();
+        codes
+    }
+
+    pub fn len(&self) -> usize {
+        self.bars.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bars.is_empty()
+    }
+
+    /// start again from the first bar
+    pub fn reset(&mut self) {
+        self.cursor = 0;
+        self.latest.clear();
+    }
+}
+
+impl Iterator for QABarFeed {
+    type Item = QABarSlice;
+
+    fn next(&mut self) -> Option<QABarSlice> {
+        if self.cursor >= self.bars.len() {
+            return None;
+        }
+        let datetime = self.bars[self.cursor].datetime.clone();
+        let mut bars = BTreeMap::new();
+        while self.cursor < self.bars.len() && self.bars[self.cursor].datetime == datetime {
+            let bar = self.bars[self.cursor].clone();
+            self.latest.insert(bar.code.clone(), bar.clone());
+            bars.insert(bar.code.clone(), bar);
+            self.cursor += 1;
+        }
+        Some(QABarSlice {
+            datetime,
+            bars,
+            latest: self.latest.clone(),
+        })
+    }
+}
+
+/// 逐 slice 回测, 先以 slice 中每根 bar 检查条件单并更新持仓, 再调用策略,
+/// 交易日变化时结算
+pub fn run_feed_backtest<S: QAMultiStrategy>(
+    strategy: &mut S,
+    feed: QABarFeed,
+    account_cookie: &str,
+    init_cash: f64,
+) -> QA_Account {
+    let mut clock = QASettleClock::new();
+    let mut acc = QA_Account::new(account_cookie, "feed", "admin", init_cash, false, "backtest");
+    for slice in feed {
+        clock.advance(&mut acc, &slice.datetime);
+        acc.change_datetime(slice.datetime.clone());
+        for bar in slice.bars.values() {
+            acc.on_bar(bar);
+        }
+        strategy.on_slice(&mut acc, &slice);
+    }
+    clock.finish(&mut acc);
+    acc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_helper::bar;
+
+    /// long rb short j once, then record what the account sees
+    struct Pair {
+        opened: bool,
+        missing: Vec<String>,
+    }
+
+    impl QAMultiStrategy for Pair {
+        fn on_slice(&mut self, acc: &mut QA_Account, slice: &QABarSlice) {
+            if slice.is_missing("j2005") {
+                self.missing.push(slice.datetime.clone());
+            }
+            if !self.opened {
+                let rb = slice.get("rb2005").unwrap();
+                let j = slice.get("j2005").unwrap();
+                acc.buy_open("rb2005", 1.0, &slice.datetime, rb.close).unwrap();
+                acc.sell_open("j2005", 1.0, &slice.datetime, j.close).unwrap();
+                self.opened = true;
+            }
+        }
+    }
+
+    fn feed() -> QABarFeed {
+        QABarFeed::from_sources(vec![
+            vec![
+                bar("rb2005", "2020-03-31 09:00:00", 3500.0).build(),
+                bar("rb2005", "2020-03-31 09:01:00", 3510.0).build(),
+                bar("rb2005", "2020-03-31 09:02:00", 3520.0).build(),
+                bar("rb2005", "2020-04-01 09:00:00", 3530.0).build(),
+            ],
+            vec![
+                bar("j2005", "2020-03-31 09:00:00", 1800.0).build(),
+                bar("j2005", "2020-03-31 09:02:00", 1790.0).build(),
+            ],
+        ])
+    }
+
+    #[test]
+    fn test_feed() {
+        let mut feed = feed();
+        assert_eq!(feed.len(), 6);
+        assert_eq!(feed.codes(), vec!["j2005", "rb2005"]);
+        let slices: Vec<QABarSlice> = feed.by_ref().collect();
+        assert_eq!(slices.len(), 4);
+        assert_eq!(slices[0].bars.len(), 2);
+        assert!(slices[1].is_missing("j2005"));
+        assert_eq!(slices[1].price("j2005"), Some(1800.0));
+        assert_eq!(slices[3].price("j2005"), Some(1790.0));
+        feed.reset();
+        assert_eq!(feed.count(), 4);
+    }
+
+    #[test]
+    fn test_feed_backtest() {
+        let mut strategy = Pair {
+            opened: false,
+            missing: vec![],
+        };
+        let mut acc = run_feed_backtest(&mut strategy, feed(), "feed_test", 1000000.0);
+        assert_eq!(strategy.missing, vec!["2020-03-31 09:01:00", "2020-04-01 09:00:00"]);
+        assert_eq!(acc.dailyassets.len(), 2);
+        // j has no bar on the second day, it is marked to its last close
+        assert_eq!(acc.get_position("j2005").unwrap().lastest_price, 1790.0);
+        assert_eq!(acc.get_position("rb2005").unwrap().lastest_price, 3530.0);
+    }
+}
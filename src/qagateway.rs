@@ -0,0 +1,362 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Debug;
+
+use chrono::NaiveDateTime;
+use qifi_rs::Order;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+use crate::qaorder::QAOrder;
+
+/// events sent back by a gateway, the account applies them in `sync_gateway`
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum GatewayEvent {
+    Accepted {
+        order_id: String,
+        exchange_order_id: String,
+        datetime: String,
+    },
+    Rejected {
+        order_id: String,
+        reason: String,
+        datetime: String,
+    },
+    Filled {
+        order_id: String,
+        trade_id: String,
+        price: f64,
+        volume: f64,
+        datetime: String,
+    },
+    Cancelled {
+        order_id: String,
+        volume_left: f64,
+        datetime: String,
+    },
+}
+
+/// trading gateway used by QA_Account in the "real" environment
+///
+/// submit/cancel only hand the request over, fills come back later from `poll`
+pub trait Gateway: Debug + Send {
+    fn submit(&mut self, order: &QAOrder) -> Result<(), String>;
+    fn cancel(&mut self, order_id: &str) -> Result<(), String>;
+    fn query(&self, order_id: &str) -> Option<Order>;
+    /// collect the events produced since the last poll
+    fn poll(&mut self) -> Vec<GatewayEvent>;
+    /// market data pushed by the account, a broker gateway can ignore it
+    fn on_price_change(&mut self, _code: &str, _price: f64, _datetime: &str) {}
+}
+
+#[derive(Debug, Clone)]
+struct MockOrder {
+    order: QAOrder,
+    /// the order reaches the matching engine at this time (nanos)
+    active_at: i64,
+    fill_count: i32,
+    status: String,
+    last_msg: String,
+}
+
+/// in-process exchange for testing the live order path without a broker
+///
+/// 模拟交易所: 可配置延迟(ms), 部分成交比例, 拒单
+#[derive(Debug)]
+pub struct MockExchange {
+    /// order latency in milliseconds of market time
+    pub latency: i64,
+    /// share of the left volume filled on every matching tick, 1.0 means full fill
+    pub fill_ratio: f64,
+    /// probability that an order is rejected on submit
+    pub reject_ratio: f64,
+    /// orders above this volume are rejected, 0.0 means no limit
+    pub max_volume: f64,
+    pub reject_codes: Vec<String>,
+    orders: BTreeMap<String, MockOrder>,
+    last_price: HashMap<String, f64>,
+    time: i64,
+    trade_id: i64,
+    events: Vec<GatewayEvent>,
+    rng: StdRng,
+}
+
+fn parse_time(datetime: &str) -> i64 {
+    let datetime = if datetime.len() == 10 {
+        format!("{} 00:00:00", datetime)
+    } else {
+        datetime.to_string()
+    };
+    NaiveDateTime::parse_from_str(&datetime, "%Y-%m-%d %H:%M:%S")
+        .map(|t| t.timestamp_nanos())
+        .unwrap_or(0)
+}
+
+impl Default for MockExchange {
+    fn default() -> Self {
+        MockExchange::new()
+    }
+}
+
+impl MockExchange {
+    pub fn new() -> Self {
+        MockExchange {
+            latency: 0,
+            fill_ratio: 1.0,
+            reject_ratio: 0.0,
+            max_volume: 0.0,
+            reject_codes: vec![],
+            orders: BTreeMap::new(),
+            last_price: HashMap::new(),
+            time: 0,
+            trade_id: 0,
+            events: vec![],
+            rng: StdRng::seed_from_u64(0),
+        }
+    }
+
+    pub fn with_latency(mut self, latency: i64) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    pub fn with_fill_ratio(mut self, fill_ratio: f64) -> Self {
+        self.fill_ratio = fill_ratio;
+        self
+    }
+
+    pub fn with_reject_ratio(mut self, reject_ratio: f64, seed: u64) -> Self {
+        self.reject_ratio = reject_ratio;
+        self.rng = StdRng::seed_from_u64(seed);
+        self
+    }
+
+    pub fn with_max_volume(mut self, max_volume: f64) -> Self {
+        self.max_volume = max_volume;
+        self
+    }
+
+    pub fn with_reject_code(mut self, code: &str) -> Self {
+        self.reject_codes.push(code.to_string());
+        self
+    }
+
+    fn datetime(&self) -> String {
+        NaiveDateTime::from_timestamp(self.time / 1_000_000_000, 0)
+            .format("%Y-%m-%d %H:%M:%S")
+            .to_string()
+    }
+
+    fn match_orders(&mut self, code: &str, price: f64) {
+        let datetime = self.datetime();
+        let mut fills = vec![];
+        for (order_id, mo) in self.orders.iter_mut() {
+            if mo.status != "ALIVE" || mo.order.instrument_id != code || mo.active_at > self.time
+            {
+                continue;
+            }
+            let crossed = match mo.order.direction.as_str() {
+                "BUY" => price <= mo.order.limit_price,
+                _ => price >= mo.order.limit_price,
+            };
+            if !crossed {
+                continue;
+            }
+            let mut volume = (mo.order.volume_left * self.fill_ratio).ceil();
+            if volume > mo.order.volume_left || volume <= 0.0 {
+                volume = mo.order.volume_left;
+            }
+            mo.order.volume_left -= volume;
+            mo.fill_count += 1;
+            if mo.order.volume_left <= 0.0 {
+                mo.status = "FINISHED".to_string();
+            }
+            fills.push((order_id.clone(), volume));
+        }
+        for (order_id, volume) in fills {
+            self.trade_id += 1;
+            self.events.push(GatewayEvent::Filled {
+                order_id,
+                trade_id: format!("MOCK_TRADE_{}", self.trade_id),
+                price,
+                volume,
+                datetime: datetime.clone(),
+            });
+        }
+    }
+}
+
+impl Gateway for MockExchange {
+    fn submit(&mut self, order: &QAOrder) -> Result<(), String> {
+        let now = parse_time(&order.order_time);
+        if now > self.time {
+            self.time = now;
+        }
+        let datetime = self.datetime();
+        let reason = if self.orders.contains_key(&order.order_id) {
+            Some("duplicate order id".to_string())
+        } else if self.reject_codes.contains(&order.instrument_id) {
+            Some(format!("instrument {} not tradable", order.instrument_id))
+        } else if self.max_volume > 0.0 && order.volume > self.max_volume {
+            Some(format!("volume {} exceeds {}", order.volume, self.max_volume))
+        } else if self.reject_ratio > 0.0 && self.rng.gen::<f64>() < self.reject_ratio {
+            Some("rejected by mock exchange".to_string())
+        } else {
+            None
+        };
+        let (status, last_msg) = match &reason {
+            Some(reason) => ("FINISHED".to_string(), reason.clone()),
+            None => ("ALIVE".to_string(), "".to_string()),
+        };
+        self.orders.insert(
+            order.order_id.clone(),
+            MockOrder {
+                order: order.clone(),
+                active_at: now + self.latency * 1_000_000,
+                fill_count: 0,
+                status,
+                last_msg,
+            },
+        );
+        match reason {
+            Some(reason) => {
+                self.events.push(GatewayEvent::Rejected {
+                    order_id: order.order_id.clone(),
+                    reason: reason.clone(),
+                    datetime,
+                });
+                Err(reason)
+            }
+            None => {
+                self.events.push(GatewayEvent::Accepted {
+                    order_id: order.order_id.clone(),
+                    exchange_order_id: order.exchange_order_id.clone(),
+                    datetime,
+                });
+                if self.latency == 0 {
+                    if let Some(price) = self.last_price.get(&order.instrument_id).cloned() {
+                        self.match_orders(&order.instrument_id, price);
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn cancel(&mut self, order_id: &str) -> Result<(), String> {
+        let datetime = self.datetime();
+        match self.orders.get_mut(order_id) {
+            Some(mo) if mo.status == "ALIVE" => {
+                mo.status = "FINISHED".to_string();
+                mo.last_msg = "cancelled".to_string();
+                self.events.push(GatewayEvent::Cancelled {
+                    order_id: order_id.to_string(),
+                    volume_left: mo.order.volume_left,
+                    datetime,
+                });
+                Ok(())
+            }
+            Some(_) => Err(format!("order {} is finished", order_id)),
+            None => Err(format!("order {} not found", order_id)),
+        }
+    }
+
+    fn query(&self, order_id: &str) -> Option<Order> {
+        self.orders.get(order_id).map(|mo| Order {
+            seqno: mo.fill_count,
+            user_id: mo.order.user_id.clone(),
+            order_id: mo.order.order_id.clone(),
+            exchange_id: mo.order.exchange_id.clone(),
+            instrument_id: mo.order.instrument_id.clone(),
+            direction: mo.order.direction.clone(),
+            offset: mo.order.offset.clone(),
+            volume_orign: mo.order.volume,
+            price_type: mo.order.price_type.clone(),
+            limit_price: mo.order.limit_price,
+            time_condition: mo.order.time_condition.clone(),
+            volume_condition: mo.order.volume_condition.clone(),
+            insert_date_time: mo.active_at - self.latency * 1_000_000,
+            exchange_order_id: mo.order.exchange_order_id.clone(),
+            status: mo.status.clone(),
+            volume_left: mo.order.volume_left,
+            last_msg: mo.last_msg.clone(),
+        })
+    }
+
+    fn poll(&mut self) -> Vec<GatewayEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn on_price_change(&mut self, code: &str, price: f64, datetime: &str) {
+        let now = parse_time(datetime);
+        if now > self.time {
+            self.time = now;
+        }
+        self.last_price.insert(code.to_string(), price);
+        self.match_orders(code, price);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn order(order_id: &str, towards: i32, volume: f64, price: f64) -> QAOrder {
+        QAOrder::new(
+            "test".to_string(),
+            "rb2005".to_string(),
+            towards,
+            "SHFE".to_string(),
+            "2020-01-20 09:30:00".to_string(),
+            volume,
+            price,
+            order_id.to_string(),
+        )
+    }
+
+    #[test]
+    fn test_latency_and_partial_fill() {
+        let mut ex = MockExchange::new().with_latency(1000).with_fill_ratio(0.5);
+        ex.submit(&order("o1", 2, 10.0, 3500.0)).unwrap();
+        ex.on_price_change("rb2005", 3490.0, "2020-01-20 09:30:00");
+        let events = ex.poll();
+        assert_eq!(events.len(), 1);
+
+        ex.on_price_change("rb2005", 3490.0, "2020-01-20 09:30:01");
+        ex.on_price_change("rb2005", 3510.0, "2020-01-20 09:30:02");
+        ex.on_price_change("rb2005", 3495.0, "2020-01-20 09:30:03");
+        let events = ex.poll();
+        println!("{:#?}", events);
+        assert_eq!(events.len(), 2);
+        let o = ex.query("o1").unwrap();
+        assert_eq!(o.volume_left, 2.0);
+        assert_eq!(o.status, "ALIVE");
+    }
+
+    #[test]
+    fn test_reject_and_cancel() {
+        let mut ex = MockExchange::new().with_max_volume(5.0);
+        assert!(ex.submit(&order("o1", 2, 10.0, 3500.0)).is_err());
+        assert!(ex.cancel("o1").is_err());
+        ex.submit(&order("o2", -2, 5.0, 3500.0)).unwrap();
+        ex.cancel("o2").unwrap();
+        ex.on_price_change("rb2005", 3600.0, "2020-01-20 09:31:00");
+        let events = ex.poll();
+        println!("{:#?}", events);
+        assert_eq!(events.len(), 3);
+        assert_eq!(ex.query("o2").unwrap().volume_left, 5.0);
+    }
+
+    #[test]
+    fn test_seeded_reject() {
+        let mut a = MockExchange::new().with_reject_ratio(0.5, 7);
+        let mut b = MockExchange::new().with_reject_ratio(0.5, 7);
+        for i in 0..20 {
+            let id = format!("o{}", i);
+            assert_eq!(
+                a.submit(&order(&id, 2, 1.0, 3500.0)).is_ok(),
+                b.submit(&order(&id, 2, 1.0, 3500.0)).is_ok()
+            );
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::qaperformance::{QAPnlMode, QATradePair};
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum QAResampleMethod {
+    /// draw the same number of pairs with replacement
+    Bootstrap,
+    /// reorder the pairs, the final equity is unchanged but the drawdown is not
+    Shuffle,
+}
+
+/// summary of the values of all the iterations
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QADistribution {
+    pub mean: f64,
+    pub std: f64,
+    pub min: f64,
+    pub max: f64,
+    pub p5: f64,
+    pub p25: f64,
+    pub p50: f64,
+    pub p75: f64,
+    pub p95: f64,
+    /// sorted ascending
+    pub values: Vec<f64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QAMonteCarloLevel {
+    pub capital: f64,
+    pub final_equity: QADistribution,
+    /// negative number, -0.1 means 10% drawdown
+    pub max_drawdown: QADistribution,
+    /// share of the paths whose equity touched capital * (1 - ruin_level)
+    pub ruin_probability: f64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QAMonteCarloReport {
+    pub iterations: usize,
+    pub seed: u64,
+    pub pairs: usize,
+    pub levels: Vec<QAMonteCarloLevel>,
+}
+
+/// 交易对蒙特卡洛重采样, 每次迭代使用 seed + i 的独立随机数, 结果与线程调度无关
+#[derive(Debug, Clone)]
+pub struct QAMonteCarlo {
+    pub iterations: usize,
+    pub seed: u64,
+    pub method: QAResampleMethod,
+    /// a path is ruined once it has lost this share of the capital, 1.0 means bankrupt
+    pub ruin_level: f64,
+    pub pnl_mode: QAPnlMode,
+}
+
+impl QADistribution {
+    pub fn from_values(mut values: Vec<f64>) -> Self {
+        if values.is_empty() {
+            return QADistribution::default();
+        }
+        values.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        let n = values.len() as f64;
+        let mean = values.iter().sum::<f64>() / n;
+        let std = if values.len() > 1 {
+            (values.iter().map(|v| (v - mean).powi(2)).sum::<f64>() / (n - 1.0)).sqrt()
+        } else {
+            0.0
+        };
+        QADistribution {
+            mean,
+            std,
+            min: values[0],
+            max: values[values.len() - 1],
+            p5: percentile(&values, 0.05),
+            p25: percentile(&values, 0.25),
+            p50: percentile(&values, 0.5),
+            p75: percentile(&values, 0.75),
+            p95: percentile(&values, 0.95),
+            values,
+        }
+    }
+}
+
+/// linear interpolation on sorted values
+pub fn percentile(sorted: &[f64], q: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let pos = q.max(0.0).min(1.0) * (sorted.len() - 1) as f64;
+    let lower = pos.floor() as usize;
+    let upper = pos.ceil() as usize;
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (pos - lower as f64)
+}
+
+impl QAMonteCarlo {
+    pub fn new(iterations: usize, seed: u64) -> Self {
+        QAMonteCarlo {
+            iterations,
+            seed,
+            method: QAResampleMethod::Bootstrap,
+            ruin_level: 0.5,
+            pnl_mode: QAPnlMode::Gross,
+        }
+    }
+
+    pub fn with_method(mut self, method: QAResampleMethod) -> Self {
+        self.method = method;
+        self
+    }
+
+    pub fn with_ruin_level(mut self, ruin_level: f64) -> Self {
+        self.ruin_level = ruin_level;
+        self
+    }
+
+    pub fn with_pnl_mode(mut self, pnl_mode: QAPnlMode) -> Self {
+        self.pnl_mode = pnl_mode;
+        self
+    }
+
+    /// one resampled pnl sequence
+    pub fn sample(&self, pnls: &[f64], iteration: usize) -> Vec<f64> {
+        let mut rng = StdRng::seed_from_u64(self.seed.wrapping_add(iteration as u64));
+        match self.method {
+            QAResampleMethod::Bootstrap => (0..pnls.len())
+                .map(|_| pnls[rng.gen_range(0, pnls.len())])
+                .collect(),
+            QAResampleMethod::Shuffle => {
+                let mut res = pnls.to_vec();
+                res.shuffle(&mut rng);
+                res
+            }
+        }
+    }
+
+    /// pairs are replayed in close order, every capital level uses the same resampled paths
+    pub fn run(&self, pairs: &[QATradePair], capitals: &[f64]) -> QAMonteCarloReport {
+        let mut sorted: Vec<&QATradePair> = pairs.iter().collect();
+        sorted.sort_by_key(|p| p.close_datetime);
+        let pnls: Vec<f64> = sorted.iter().map(|p| p.pnl(self.pnl_mode)).collect();
+
+        // (final equity, max drawdown, ruined) of every capital level
+        let paths: Vec<Vec<(f64, f64, bool)>> = (0..self.iterations)
+            .into_par_iter()
+            .map(|i| {
+                let sample = self.sample(&pnls, i);
+                capitals
+                    .iter()
+                    .map(|capital| self.replay(*capital, &sample))
+                    .collect()
+            })
+            .collect();
+
+        let levels = capitals
+            .iter()
+            .enumerate()
+            .map(|(j, capital)| {
+                let ruined = paths.iter().filter(|p| p[j].2).count();
+                QAMonteCarloLevel {
+                    capital: *capital,
+                    final_equity: QADistribution::from_values(paths.iter().map(|p| p[j].0).collect()),
+                    max_drawdown: QADistribution::from_values(paths.iter().map(|p| p[j].1).collect()),
+                    ruin_probability: if paths.is_empty() {
+                        0.0
+                    } else {
+                        ruined as f64 / paths.len() as f64
+                    },
+                }
+            })
+            .collect();
+        QAMonteCarloReport {
+            iterations: self.iterations,
+            seed: self.seed,
+            pairs: pnls.len(),
+            levels,
+        }
+    }
+
+    fn replay(&self, capital: f64, pnls: &[f64]) -> (f64, f64, bool) {
+        let ruin = capital * (1.0 - self.ruin_level);
+        let mut equity = capital;
+        let mut peak = capital;
+        let mut drawdown = 0.0f64;
+        let mut ruined = false;
+        for pnl in pnls {
+            equity += pnl;
+            peak = peak.max(equity);
+            if peak > 0.0 {
+                drawdown = drawdown.min(equity / peak - 1.0);
+            }
+            if equity <= ruin {
+                ruined = true;
+            }
+        }
+        (equity, drawdown, ruined)
+    }
+}
+
+impl QAMonteCarloReport {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pairs(pnls: &[f64]) -> Vec<QATradePair> {
+        pnls.iter()
+            .enumerate()
+            .map(|(i, pnl)| QATradePair {
+                open_datetime: i as i64,
+                close_datetime: i as i64 + 1,
+                opendate: "".to_string(),
+                closedate: "".to_string(),
+                if_buyopen: true,
+                code: "rb2005".to_string(),
+                amount: 1.0,
+                openprice: 3500.0,
+                closeprice: 3500.0,
+                open_trade_id: "".to_string(),
+                close_trade_id: "".to_string(),
+                pnl_ratio: 0.0,
+                pnl_money: *pnl,
+                hold_gap: 1.0,
+                open_commission: 1.0,
+                close_commission: 1.0,
+                net_pnl_ratio: 0.0,
+                net_pnl_money: pnl - 2.0,
+                mae_price: 0.0,
+                mfe_price: 0.0,
+                mae_money: 0.0,
+                mfe_money: 0.0,
+                mfe_time: 0.0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_percentile() {
+        let values = [1.0, 2.0, 3.0, 4.0, 5.0];
+        assert_eq!(percentile(&values, 0.5), 3.0);
+        assert_eq!(percentile(&values, 0.25), 2.0);
+        assert_eq!(percentile(&values, 0.1), 1.4);
+        let d = QADistribution::from_values(vec![5.0, 1.0, 3.0]);
+        assert_eq!((d.min, d.p50, d.max), (1.0, 3.0, 5.0));
+    }
+
+    #[test]
+    fn test_shuffle() {
+        let pairs = pairs(&[100.0, -300.0, 50.0, 200.0, -100.0, 80.0]);
+        let report = QAMonteCarlo::new(200, 7)
+            .with_method(QAResampleMethod::Shuffle)
+            .run(&pairs, &[1000.0, 400.0]);
+        println!("{}", report.to_json());
+        let level = &report.levels[0];
+        // shuffling keeps the total pnl
+        assert!((level.final_equity.min - 1030.0).abs() < 1e-9);
+        assert!((level.final_equity.max - 1030.0).abs() < 1e-9);
+        assert!(level.max_drawdown.min < level.max_drawdown.max);
+        assert_eq!(level.ruin_probability, 0.0);
+        // -300 and -100 in a row is a ruin for 400 with the default 50% level
+        assert!(report.levels[1].ruin_probability > 0.0);
+        assert!(report.levels[1].ruin_probability < 1.0);
+    }
+
+    #[test]
+    fn test_bootstrap_reproducible() {
+        let pairs = pairs(&[100.0, -300.0, 50.0, 200.0, -100.0, 80.0]);
+        let mc = QAMonteCarlo::new(500, 42).with_pnl_mode(QAPnlMode::Net);
+        let a = mc.run(&pairs, &[1000.0]);
+        let b = mc.run(&pairs, &[1000.0]);
+        assert_eq!(a.levels[0].final_equity.values, b.levels[0].final_equity.values);
+        assert!(a.levels[0].final_equity.min < a.levels[0].final_equity.max);
+        let c = QAMonteCarlo::new(500, 43).run(&pairs, &[1000.0]);
+        assert_ne!(a.levels[0].final_equity.values, c.levels[0].final_equity.values);
+        // net pnl is 2.0 lower per pair
+        assert!((a.levels[0].final_equity.mean - (1000.0 + 30.0 - 12.0)).abs() < 30.0);
+    }
+}
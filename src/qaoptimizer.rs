@@ -0,0 +1,446 @@
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+
+use rand::rngs::StdRng;
+use rand::seq::index;
+use rand::SeedableRng;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::qaaccount::QA_Account;
+use crate::qaexecution::{QAExecutionEngine, QAExecutionMode, QAStrategy};
+use crate::qafetch::BAR;
+use crate::qaperformance::{QAPerformance, QAPnlMode, QARiskMessage};
+use crate::qarisk::{QAEquityCurve, QARiskReport};
+
+/// parameter name -> value, integer parameters like K1/n1 are stored as f64 too
+pub type QAParams = BTreeMap<String, f64>;
+
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
+pub enum QAParamRange {
+    Values(Vec<f64>),
+    /// start, start + step, ... while <= end
+    Range { start: f64, end: f64, step: f64 },
+}
+
+/// 参数空间, 按参数名排序, 网格为各参数取值的笛卡尔积
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QAParamSpace {
+    pub params: BTreeMap<String, QAParamRange>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum QASearchMode {
+    Grid,
+    /// draw `samples` distinct points of the grid
+    Random { samples: usize, seed: u64 },
+}
+
+/// the results are ranked by this metric, the higher the better
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum QAOptimizeMetric {
+    TotalProfit,
+    WinRate,
+    ProfitFactor,
+    Expectancy,
+    TotalReturn,
+    AnnualizedReturn,
+    Sharpe,
+    Sortino,
+    Calmar,
+    /// negative number, so the smallest drawdown ranks first
+    MaxDrawdown,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QAOptimizeResult {
+    pub params: QAParams,
+    pub score: f64,
+    pub risk: QARiskReport,
+    pub message: QARiskMessage,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QAOptimizeReport {
+    pub metric: QAOptimizeMetric,
+    pub mode: QASearchMode,
+    /// sorted by score, best first
+    pub results: Vec<QAOptimizeResult>,
+}
+
+/// 参数优化, 每组参数在共享的只读 bar 数据上独立回测, 由 rayon 并行
+#[derive(Debug, Clone)]
+pub struct QAOptimizer {
+    pub space: QAParamSpace,
+    pub metric: QAOptimizeMetric,
+    pub mode: QASearchMode,
+    pub init_cash: f64,
+    pub risk_free: f64,
+    pub pnl_mode: QAPnlMode,
+    /// how the orders of every backtest are filled
+    pub execution: QAExecutionMode,
+}
+
+impl QAParamRange {
+    pub fn values(&self) -> Vec<f64> {
+        match self {
+            QAParamRange::Values(v) => v.clone(),
+            QAParamRange::Range { start, end, step } => {
+                let mut res = vec![];
+                if *step <= 0.0 {
+                    return vec![*start];
+                }
+                let mut i = 0;
+                loop {
+                    // multiply instead of accumulating the step to avoid drifting
+                    let v = start + step * i as f64;
+                    if v > end + step * 1e-9 {
+                        break;
+                    }
+                    res.push(v);
+                    i += 1;
+                }
+                res
+            }
+        }
+    }
+}
+
+impl QAParamSpace {
+    pub fn new() -> Self {
+        QAParamSpace::default()
+    }
+
+    pub fn add_values(mut self, name: &str, values: &[f64]) -> Self {
+        self.params
+            .insert(name.to_string(), QAParamRange::Values(values.to_vec()));
+        self
+    }
+
+    pub fn add_range(mut self, name: &str, start: f64, end: f64, step: f64) -> Self {
+        self.params
+            .insert(name.to_string(), QAParamRange::Range { start, end, step });
+        self
+    }
+
+    /// number of grid points
+    pub fn size(&self) -> usize {
+        self.params.values().map(|r| r.values().len()).product()
+    }
+
+    /// the index-th point of the grid, the last parameter changes fastest
+    pub fn point(&self, mut index: usize) -> QAParams {
+        let values: Vec<(&String, Vec<f64>)> =
+            self.params.iter().map(|(k, r)| (k, r.values())).collect();
+        let mut params = QAParams::new();
+        for (name, v) in values.iter().rev() {
+            params.insert(name.to_string(), v[index % v.len()]);
+            index /= v.len();
+        }
+        params
+    }
+
+    pub fn grid(&self) -> Vec<QAParams> {
+        (0..self.size()).map(|i| self.point(i)).collect()
+    }
+
+    /// distinct random points, the whole grid if samples >= size
+    pub fn sample(&self, samples: usize, seed: u64) -> Vec<QAParams> {
+        let size = self.size();
+        if samples >= size {
+            return self.grid();
+        }
+        let mut rng = StdRng::seed_from_u64(seed);
+        index::sample(&mut rng, size, samples)
+            .into_iter()
+            .map(|i| self.point(i))
+            .collect()
+    }
+}
+
+impl QAOptimizeMetric {
+    pub fn score(&self, risk: &QARiskReport, message: &QARiskMessage) -> f64 {
+        match self {
+            QAOptimizeMetric::TotalProfit => message.total_profit,
+            QAOptimizeMetric::WinRate => message.win_rate,
+            QAOptimizeMetric::ProfitFactor => message.profit_factor,
+            QAOptimizeMetric::Expectancy => message.expectancy,
+            QAOptimizeMetric::TotalReturn => risk.total_return,
+            QAOptimizeMetric::AnnualizedReturn => risk.annualized_return,
+            QAOptimizeMetric::Sharpe => risk.sharpe,
+            QAOptimizeMetric::Sortino => risk.sortino,
+            QAOptimizeMetric::Calmar => risk.calmar,
+            QAOptimizeMetric::MaxDrawdown => risk.drawdown.max_drawdown,
+        }
+    }
+}
+
+/// replay the bars on a new backtest account with the execution engine, it is settled
+/// whenever the trading day changes and once more after the last bar
+pub fn run_backtest<S: QAStrategy>(
+    strategy: &mut S,
+    bars: &[BAR],
+    account_cookie: &str,
+    init_cash: f64,
+    mode: QAExecutionMode,
+) -> QA_Account {
+    QAExecutionEngine::new(mode).run(strategy, bars, account_cookie, init_cash)
+}
+
+impl QAOptimizer {
+    pub fn new(space: QAParamSpace, metric: QAOptimizeMetric) -> Self {
+        QAOptimizer {
+            space,
+            metric,
+            mode: QASearchMode::Grid,
+            init_cash: 1000000.0,
+            risk_free: 0.0,
+            pnl_mode: QAPnlMode::Gross,
+            execution: QAExecutionMode::SameBar,
+        }
+    }
+
+    pub fn with_mode(mut self, mode: QASearchMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    pub fn with_init_cash(mut self, init_cash: f64) -> Self {
+        self.init_cash = init_cash;
+        self
+    }
+
+    pub fn with_risk_free(mut self, risk_free: f64) -> Self {
+        self.risk_free = risk_free;
+        self
+    }
+
+    pub fn with_pnl_mode(mut self, pnl_mode: QAPnlMode) -> Self {
+        self.pnl_mode = pnl_mode;
+        self
+    }
+
+    /// NextOpen/NextVwap fill the signals at the next bar, SameBar at the signal price
+    pub fn with_execution(mut self, execution: QAExecutionMode) -> Self {
+        self.execution = execution;
+        self
+    }
+
+    pub fn candidates(&self) -> Vec<QAParams> {
+        match self.mode {
+            QASearchMode::Grid => self.space.grid(),
+            QASearchMode::Random { samples, seed } => self.space.sample(samples, seed),
+        }
+    }
+
+    /// backtest and score one set of parameters
+    pub fn evaluate<F, S>(&self, bars: &[BAR], params: QAParams, factory: &F) -> QAOptimizeResult
+    where
+        F: Fn(&QAParams) -> S,
+        S: QAStrategy,
+    {
+        let mut strategy = factory(&params);
+        let mut acc = run_backtest(&mut strategy, bars, "optimizer", self.init_cash, self.execution);
+        self.score_account(params, &mut acc)
+    }
+
+    /// score a settled backtest account with the metric of the optimizer
+    pub fn score_account(&self, params: QAParams, acc: &mut QA_Account) -> QAOptimizeResult {
+        let risk = QAEquityCurve::from_account(acc).risk_report(self.risk_free);
+        let mut performance = QAPerformance::from_account(acc);
+        performance.set_pnl_mode(self.pnl_mode);
+        let message = performance.message();
+        QAOptimizeResult {
+            score: self.metric.score(&risk, &message),
+            params,
+            risk,
+            message,
+        }
+    }
+
+    /// NaN scores rank last, equal scores keep the candidate order
+    pub fn run<F, S>(&self, bars: &[BAR], factory: F) -> QAOptimizeReport
+    where
+        F: Fn(&QAParams) -> S + Sync,
+        S: QAStrategy,
+    {
+        let mut results: Vec<QAOptimizeResult> = self
+            .candidates()
+            .into_par_iter()
+            .map(|params| self.evaluate(bars, params, &factory))
+            .collect();
+        let key = |r: &QAOptimizeResult| if r.score.is_nan() { std::f64::NEG_INFINITY } else { r.score };
+        results.sort_by(|a, b| {
+            key(b)
+                .partial_cmp(&key(a))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        });
+        QAOptimizeReport {
+            metric: self.metric,
+            mode: self.mode,
+            results,
+        }
+    }
+}
+
+impl QAOptimizeReport {
+    pub fn best(&self) -> Option<&QAOptimizeResult> {
+        self.results.first()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// one row per result, ranked, with a column for every parameter
+    pub fn to_csv(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let mut names: Vec<String> = self
+            .results
+            .iter()
+            .flat_map(|r| r.params.keys().cloned())
+            .collect::<HashSet<String>>()
+            .into_iter()
+            .collect();
+        names.sort();
+
+        let mut wtr = csv::Writer::from_path(path)?;
+        let mut header = vec!["rank".to_string()];
+        header.extend(names.iter().cloned());
+        for col in &[
+            "score",
+            "total_return",
+            "annualized_return",
+            "sharpe",
+            "max_drawdown",
+            "total_profit",
+            "win_rate",
+            "profit_factor",
+            "total_count",
+        ] {
+            header.push(col.to_string());
+        }
+        wtr.write_record(&header)?;
+        for (i, r) in self.results.iter().enumerate() {
+            let mut row = vec![(i + 1).to_string()];
+            for name in names.iter() {
+                row.push(r.params.get(name).map(|v| v.to_string()).unwrap_or_default());
+            }
+            row.push(r.score.to_string());
+            row.push(r.risk.total_return.to_string());
+            row.push(r.risk.annualized_return.to_string());
+            row.push(r.risk.sharpe.to_string());
+            row.push(r.risk.drawdown.max_drawdown.to_string());
+            row.push(r.message.total_profit.to_string());
+            row.push(r.message.win_rate.to_string());
+            row.push(r.message.profit_factor.to_string());
+            row.push(r.message.total_count.to_string());
+            wtr.write_record(&row)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qaexecution::{QABarView, QAExecutionContext};
+    use crate::test_helper::{band, bar, sine_bars};
+
+    #[test]
+    fn test_param_space() {
+        let space = QAParamSpace::new()
+            .add_range("K1", 10.0, 30.0, 10.0)
+            .add_values("n1", &[20.0, 30.0]);
+        assert_eq!(space.size(), 6);
+        let grid = space.grid();
+        assert_eq!(grid[0]["K1"], 10.0);
+        assert_eq!(grid[1]["n1"], 30.0);
+        assert_eq!(grid[5]["K1"], 30.0);
+        let sample = space.sample(4, 1);
+        assert_eq!(sample.len(), 4);
+        assert_eq!(sample, space.sample(4, 1));
+        for p in sample.iter() {
+            assert!(grid.contains(p));
+        }
+        assert_eq!(space.sample(10, 1).len(), 6);
+    }
+
+    #[test]
+    fn test_optimizer() {
+        let bars = sine_bars();
+        let space = QAParamSpace::new()
+            .add_values("entry", &[3460.0, 3480.0])
+            .add_values("exit", &[3520.0, 3540.0]);
+        let report = QAOptimizer::new(space.clone(), QAOptimizeMetric::TotalProfit).run(&bars, band);
+        assert_eq!(report.results.len(), 4);
+        for w in report.results.windows(2) {
+            assert!(w[0].score >= w[1].score);
+        }
+        let best = report.best().unwrap();
+        assert!(best.message.total_count > 0);
+        assert_eq!(best.score, best.message.total_profit);
+        assert!(best.risk.trading_days > 50);
+
+        // the backtest is deterministic
+        let again = QAOptimizer::new(space.clone(), QAOptimizeMetric::TotalProfit).run(&bars, band);
+        assert_eq!(again.best().unwrap().params, best.params);
+
+        let random = QAOptimizer::new(space, QAOptimizeMetric::Sharpe)
+            .with_mode(QASearchMode::Random { samples: 3, seed: 7 })
+            .run(&bars, band);
+        assert_eq!(random.results.len(), 3);
+
+        let path = std::env::temp_dir().join("qaoptimizer_test.csv");
+        report.to_csv(path.to_str().unwrap()).unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert!(content.starts_with("rank,entry,exit,score"));
+        assert_eq!(content.lines().count(), 5);
+    }
+
+    #[test]
+    fn test_backtest_execution() {
+        let bars = sine_bars();
+        let space = QAParamSpace::new()
+            .add_values("entry", &[3460.0])
+            .add_values("exit", &[3520.0]);
+        let params = space.point(0);
+        let same = run_backtest(&mut band(&params), &bars, "same", 1000000.0, QAExecutionMode::SameBar);
+        let mut next = run_backtest(&mut band(&params), &bars, "next", 1000000.0, QAExecutionMode::NextOpen);
+        // the signal bar fills at once, or at the open of the bar after it
+        let k = bars.iter().position(|b| b.datetime == same.history[0].datetime).unwrap();
+        assert_eq!(next.history[0].datetime, bars[k + 1].datetime);
+        assert_eq!(next.history[0].price, bars[k + 1].open);
+
+        let report = QAOptimizer::new(space, QAOptimizeMetric::TotalProfit)
+            .with_execution(QAExecutionMode::NextOpen)
+            .run(&bars, band);
+        let expected = QAPerformance::from_account(&mut next).message().total_profit;
+        assert_eq!(report.best().unwrap().score, expected);
+    }
+
+    /// buy once and protect the position with a stop
+    struct Protected;
+
+    impl QAStrategy for Protected {
+        fn on_bar(&mut self, ctx: &mut QAExecutionContext, bar: &QABarView) {
+            if ctx.account().history.is_empty() {
+                ctx.buy_open(bar.code(), 1.0, bar.close());
+                ctx.stop_market(bar.code(), -3, 1.0, 3480.0);
+            }
+        }
+    }
+
+    #[test]
+    fn test_backtest_stop() {
+        let bars = vec![
+            bar("rb2005", "2020-03-31 09:00:00", 3500.0).build(),
+            // only the low touches the stop
+            bar("rb2005", "2020-03-31 09:01:00", 3495.0).open(3500.0).low(3470.0).build(),
+        ];
+        let acc = run_backtest(&mut Protected, &bars, "stop", 1000000.0, QAExecutionMode::SameBar);
+        assert_eq!(acc.history.len(), 2);
+        assert_eq!(acc.history[1].price, 3480.0);
+        assert_eq!(acc.history[1].datetime, "2020-03-31 09:01:00");
+    }
+}
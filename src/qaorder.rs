@@ -1,6 +1,10 @@
+use chrono::{TimeZone, Utc};
+use qifi_rs::Order;
 use uuid::Uuid;
 use serde::{Serialize, Deserialize};
 
+use crate::qaprotocol::CancelOrder;
+
 #[derive(Debug, Clone)]
 pub struct QAOrder {
     pub account_cookie: String,
@@ -101,10 +105,39 @@ impl QAOrder {
             time_condition: self.time_condition.clone()
         }
     }
+
+    pub fn to_qifi_order(&self) -> Order {
+        Order {
+            seqno: 0,
+            user_id: self.user_id.clone(),
+            order_id: self.order_id.clone(),
+            exchange_id: self.exchange_id.clone(),
+            instrument_id: self.instrument_id.clone(),
+            direction: self.direction.clone(),
+            offset: self.offset.clone(),
+            volume_orign: self.volume,
+            price_type: self.price_type.clone(),
+            limit_price: self.limit_price,
+            time_condition: self.time_condition.clone(),
+            volume_condition: self.volume_condition.clone(),
+            insert_date_time: Utc
+                .datetime_from_str(self.order_time.as_ref(), "%Y-%m-%d %H:%M:%S")
+                .map(|t| t.timestamp_nanos() - 28800000000000)
+                .unwrap_or(0),
+            exchange_order_id: self.exchange_order_id.clone(),
+            status: if self.volume_left > 0.0 { "ALIVE" } else { "FINISHED" }.to_string(),
+            volume_left: self.volume_left,
+            last_msg: self.last_msg.clone(),
+        }
+    }
+
+    pub fn to_cancel_order(&self) -> CancelOrder {
+        CancelOrder::new(&self.account_cookie, &self.order_id)
+    }
 }
 
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct TradeOrder {
     pub aid: String,
     pub user_id: String,
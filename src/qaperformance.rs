@@ -1,11 +1,19 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
 
-use chrono::{DateTime, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Utc};
+use chrono::{
+    DateTime, Datelike, FixedOffset, NaiveDate, NaiveDateTime, NaiveTime, TimeZone, Timelike, Utc,
+};
 use chrono::format::ParseError;
 use qifi_rs::account::Trade;
 use serde::{Deserialize, Serialize};
 
-use crate::market_preset::MarketPreset;
+use log::warn;
+
+use crate::market_preset::{CodePreset, MarketPreset};
+use crate::qaaccount::QA_Account;
+use crate::qafetch::BAR;
+use crate::trade_date::QATradeDate;
 
 /// performace is a simple way for analaysis single pair of every trades
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -24,8 +32,86 @@ pub struct QATradePair {
     pub pnl_ratio: f64,
     pub pnl_money: f64,
     pub hold_gap: f64,
+    /// commission + tax of the open/close trade allocated to this pair
+    pub open_commission: f64,
+    pub close_commission: f64,
+    pub net_pnl_ratio: f64,
+    pub net_pnl_money: f64,
+    /// maximum adverse/favorable excursion during the hold, price distance >= 0
+    pub mae_price: f64,
+    pub mfe_price: f64,
+    pub mae_money: f64,
+    pub mfe_money: f64,
+    /// seconds from open to the MFE
+    pub mfe_time: f64,
 }
 
+/// one point of the MAE/MFE scatter
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QAExcursion {
+    pub code: String,
+    pub opendate: String,
+    pub closedate: String,
+    pub if_buyopen: bool,
+    pub amount: f64,
+    pub mae_price: f64,
+    pub mfe_price: f64,
+    pub mae_money: f64,
+    pub mfe_money: f64,
+    pub mfe_time: f64,
+    pub pnl_money: f64,
+    pub net_pnl_money: f64,
+}
+
+/// 统计使用毛盈亏还是扣除手续费后的净盈亏
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum QAPnlMode {
+    Gross,
+    Net,
+}
+
+impl Default for QAPnlMode {
+    fn default() -> Self {
+        QAPnlMode::Gross
+    }
+}
+
+impl QATradePair {
+    pub fn commission(&self) -> f64 {
+        self.open_commission + self.close_commission
+    }
+
+    pub fn pnl(&self, mode: QAPnlMode) -> f64 {
+        match mode {
+            QAPnlMode::Gross => self.pnl_money,
+            QAPnlMode::Net => self.net_pnl_money,
+        }
+    }
+
+    pub fn to_excursion(&self) -> QAExcursion {
+        QAExcursion {
+            code: self.code.clone(),
+            opendate: self.opendate.clone(),
+            closedate: self.closedate.clone(),
+            if_buyopen: self.if_buyopen,
+            amount: self.amount,
+            mae_price: self.mae_price,
+            mfe_price: self.mfe_price,
+            mae_money: self.mae_money,
+            mfe_money: self.mfe_money,
+            mfe_time: self.mfe_time,
+            pnl_money: self.pnl_money,
+            net_pnl_money: self.net_pnl_money,
+        }
+    }
+
+    pub fn ratio(&self, mode: QAPnlMode) -> f64 {
+        match mode {
+            QAPnlMode::Gross => self.pnl_ratio,
+            QAPnlMode::Net => self.net_pnl_ratio,
+        }
+    }
+}
 
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -37,6 +123,45 @@ pub struct Temp {
     pub code: String,
     pub price: f64,
     pub trade_id: String,
+    pub trading_day: String,
+    /// commission of the volume still open
+    pub commission: f64,
+    /// highest/lowest price seen since open, from the bars and the trades
+    pub high: f64,
+    pub low: f64,
+    pub high_datetime: i64,
+    pub low_datetime: i64,
+}
+
+impl Temp {
+    fn update_extreme(&mut self, high: f64, low: f64, datetime: i64) {
+        if high > self.high {
+            self.high = high;
+            self.high_datetime = datetime;
+        }
+        if low < self.low {
+            self.low = low;
+            self.low_datetime = datetime;
+        }
+    }
+}
+
+/// the input of QAPerformance_Single, kept in arrival order so the pairs can be recomputed
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub enum QAPerformanceEvent {
+    Trade(Trade),
+    Bar(BAR),
+}
+
+/// how a close trade is matched against the open trades
+///
+/// CLOSETODAY is always matched against the opens of the same trading day
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum QAMatchPolicy {
+    FIFO,
+    LIFO,
+    /// open price of every pair is the average cost of the open trades
+    AverageCost,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -44,31 +169,290 @@ pub struct QAPerformance_Single {
     pub market_set: MarketPreset,
     pub pair: Vec<QATradePair>,
     pub temp: HashMap<String, Vec<Temp>>,
+    pub policy: QAMatchPolicy,
+    pub pnl_mode: QAPnlMode,
+    pub events: Vec<QAPerformanceEvent>,
+    /// only built when the single is used on its own, QAPerformance passes its calendar
+    #[serde(skip)]
+    trade_date: Option<QATradeDate>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct QAPerformance {
-    pub market: HashMap<String, QAPerformance_Single>
+    pub market: HashMap<String, QAPerformance_Single>,
+    pub policy: QAMatchPolicy,
+    pub pnl_mode: QAPnlMode,
+    /// shared by all the codes
+    #[serde(skip)]
+    trade_date: QATradeDate,
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-pub struct QARiskMessage {}
+/// trade_date_time is utc nanos, the trading day is calculated in china time
+fn get_trading_day(td: &mut QATradeDate, datetime: i64) -> String {
+    td.get_trade_day(Utc.timestamp_nanos(datetime + 28800000000000).to_string()[0..19].to_string())
+}
+
+/// BAR.datetime is china time, same as trade_date_time it is converted to utc nanos
+fn parse_bar_datetime(datetime: &str) -> Option<i64> {
+    let datetime = if datetime.len() == 10 {
+        format!("{} 00:00:00", datetime)
+    } else {
+        datetime.to_string()
+    };
+    NaiveDateTime::parse_from_str(&datetime, "%Y-%m-%d %H:%M:%S")
+        .ok()
+        .map(|t| t.timestamp_nanos() - 28800000000000)
+}
+
+fn build_pair(
+    codeset: &mut CodePreset,
+    f: &Temp,
+    openprice: f64,
+    trade: &Trade,
+    amount: f64,
+    is_buy: bool,
+    open_commission: f64,
+) -> QATradePair {
+    let hold_gap = (trade.trade_date_time - f.datetime) as f64 / 1000000000.0;
+    let mut pnl_money = codeset.unit_table as f64 * (trade.price - openprice) * amount;
+    if !is_buy {
+        pnl_money = pnl_money * -1.0;
+    }
+    let pnl_ratio = pnl_money / (openprice * amount * codeset.calc_coeff());
+    let close_commission = if trade.volume > 0.0 {
+        trade.commission * amount / trade.volume
+    } else {
+        0.0
+    };
+    let net_pnl_money = pnl_money - open_commission - close_commission;
+    let net_pnl_ratio = net_pnl_money / (openprice * amount * codeset.calc_coeff());
+
+    let (mfe_price, mae_price, mfe_datetime) = if is_buy {
+        (f.high - openprice, openprice - f.low, f.high_datetime)
+    } else {
+        (openprice - f.low, f.high - openprice, f.low_datetime)
+    };
+    let mfe_price = mfe_price.max(0.0);
+    let mae_price = mae_price.max(0.0);
+    QATradePair {
+        open_datetime: f.datetime,
+        close_datetime: trade.trade_date_time,
+        opendate: Utc.timestamp_nanos(f.datetime + 28800000000000).to_string()[0..19].to_string(),
+        closedate: Utc.timestamp_nanos(trade.trade_date_time + 28800000000000).to_string()[0..19]
+            .to_string(),
+        if_buyopen: is_buy,
+        code: f.code.clone(),
+        amount,
+        openprice,
+        closeprice: trade.price,
+        open_trade_id: f.trade_id.clone(),
+        close_trade_id: trade.trade_id.clone(),
+        pnl_ratio,
+        pnl_money,
+        hold_gap,
+        open_commission,
+        close_commission,
+        net_pnl_ratio,
+        net_pnl_money,
+        mae_price,
+        mfe_price,
+        mae_money: codeset.unit_table as f64 * mae_price * amount,
+        mfe_money: codeset.unit_table as f64 * mfe_price * amount,
+        mfe_time: (mfe_datetime - f.datetime) as f64 / 1000000000.0,
+    }
+}
+
+/// statistics of a group of trade pairs
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QAPairStats {
+    pub count: i32,
+    pub profit_count: i32,
+    pub loss_count: i32,
+    pub win_rate: f64,
+    pub total_profit: f64,
+    pub average_profit: f64,
+}
+
+/// 交易对统计报告
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QARiskMessage {
+    pub total_count: i32,
+    pub profit_count: i32,
+    pub loss_count: i32,
+    pub win_rate: f64,
+    pub total_profit: f64,
+    pub gross_profit: f64,
+    pub gross_loss: f64,
+    /// gross_profit / |gross_loss|, infinite when there are wins and no losses
+    pub profit_factor: f64,
+    pub average_win: f64,
+    pub average_loss: f64,
+    /// average_win / |average_loss|, infinite when there are wins and no losses
+    pub payoff_ratio: f64,
+    /// average pnl per pair
+    pub expectancy: f64,
+    pub max_profit: f64,
+    pub largest_loss: f64,
+    pub max_consecutive_wins: i32,
+    pub max_consecutive_losses: i32,
+    /// seconds
+    pub average_hold_gap: f64,
+    pub by_code: BTreeMap<String, QAPairStats>,
+    /// LONG / SHORT, by if_buyopen
+    pub by_direction: BTreeMap<String, QAPairStats>,
+    /// hour of the open time
+    pub by_hour: BTreeMap<u32, QAPairStats>,
+    /// weekday of the open time, Mon..Sun
+    pub by_weekday: BTreeMap<String, QAPairStats>,
+    pub pnl_mode: QAPnlMode,
+    pub total_commission: f64,
+}
+
+impl QAPairStats {
+    fn add(&mut self, pnl: f64) {
+        self.count += 1;
+        if pnl > 0.0 {
+            self.profit_count += 1;
+        } else if pnl < 0.0 {
+            self.loss_count += 1;
+        }
+        self.total_profit += pnl;
+        self.win_rate = self.profit_count as f64 / self.count as f64;
+        self.average_profit = self.total_profit / self.count as f64;
+    }
+}
+
+impl QARiskMessage {
+    pub fn from_pairs(pairs: &[QATradePair], mode: QAPnlMode) -> Self {
+        let mut msg = QARiskMessage {
+            pnl_mode: mode,
+            ..QARiskMessage::default()
+        };
+        let mut sorted: Vec<&QATradePair> = pairs.iter().collect();
+        sorted.sort_by_key(|p| p.close_datetime);
+
+        let mut wins = 0;
+        let mut losses = 0;
+        let mut hold_gap = 0.0;
+        for pair in sorted {
+            let pnl = pair.pnl(mode);
+            msg.total_count += 1;
+            msg.total_commission += pair.commission();
+            msg.total_profit += pnl;
+            hold_gap += pair.hold_gap;
+            if pnl > 0.0 {
+                msg.profit_count += 1;
+                msg.gross_profit += pnl;
+                wins += 1;
+                losses = 0;
+            } else if pnl < 0.0 {
+                msg.loss_count += 1;
+                msg.gross_loss += pnl;
+                losses += 1;
+                wins = 0;
+            } else {
+                wins = 0;
+                losses = 0;
+            }
+            msg.max_consecutive_wins = msg.max_consecutive_wins.max(wins);
+            msg.max_consecutive_losses = msg.max_consecutive_losses.max(losses);
+            if msg.total_count == 1 || pnl > msg.max_profit {
+                msg.max_profit = pnl;
+            }
+            if pnl < msg.largest_loss {
+                msg.largest_loss = pnl;
+            }
+
+            msg.by_code.entry(pair.code.clone()).or_default().add(pnl);
+            let direction = if pair.if_buyopen { "LONG" } else { "SHORT" };
+            msg.by_direction.entry(direction.to_string()).or_default().add(pnl);
+            if let Ok(t) = NaiveDateTime::parse_from_str(&pair.opendate, "%Y-%m-%d %H:%M:%S") {
+                msg.by_hour.entry(t.hour()).or_default().add(pnl);
+                msg.by_weekday
+                    .entry(t.weekday().to_string())
+                    .or_default()
+                    .add(pnl);
+            }
+        }
+        if msg.total_count > 0 {
+            let n = msg.total_count as f64;
+            msg.win_rate = msg.profit_count as f64 / n;
+            msg.expectancy = msg.total_profit / n;
+            msg.average_hold_gap = hold_gap / n;
+        }
+        if msg.profit_count > 0 {
+            msg.average_win = msg.gross_profit / msg.profit_count as f64;
+        }
+        if msg.loss_count > 0 {
+            msg.average_loss = msg.gross_loss / msg.loss_count as f64;
+            msg.profit_factor = msg.gross_profit / msg.gross_loss.abs();
+            msg.payoff_ratio = msg.average_win / msg.average_loss.abs();
+        } else if msg.profit_count > 0 {
+            msg.profit_factor = std::f64::INFINITY;
+            msg.payoff_ratio = std::f64::INFINITY;
+        }
+        msg
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
 
 
 impl QAPerformance {
     pub fn new() -> Self {
+        Self::new_with_policy(QAMatchPolicy::FIFO)
+    }
+
+    pub fn new_with_policy(policy: QAMatchPolicy) -> Self {
         QAPerformance {
-            market: HashMap::new()
+            market: HashMap::new(),
+            policy,
+            pnl_mode: QAPnlMode::Gross,
+            trade_date: QATradeDate::new(),
+        }
+    }
+
+    /// trades of history (backtest) and dailytrades (real, the last trading day only), in time order
+    pub fn from_account(acc: &mut QA_Account) -> Self {
+        let mut trades: Vec<Trade> = acc.history.iter_mut().map(|t| t.to_qifitrade()).collect();
+        trades.extend(acc.dailytrades.values().cloned());
+        trades.sort_by_key(|t| t.trade_date_time);
+        let mut performance = QAPerformance::new();
+        for trade in trades {
+            performance.insert_trade(trade);
+        }
+        performance
+    }
+
+    /// 毛盈亏/净盈亏, 影响全部的统计
+    pub fn set_pnl_mode(&mut self, mode: QAPnlMode) {
+        self.pnl_mode = mode;
+        for ps in self.market.values_mut() {
+            ps.pnl_mode = mode;
+        }
+    }
+
+    /// 切换匹配方式, 已有的交易对会重新计算
+    pub fn set_policy(&mut self, policy: QAMatchPolicy) {
+        self.policy = policy;
+        for ps in self.market.values_mut() {
+            ps.replay(policy, &mut self.trade_date);
         }
     }
 
     pub fn insert_trade(&mut self, trade: Trade) {
         let code = trade.instrument_id.clone();
         if self.market.contains_key(&code) {
-            self.market.get_mut(&code).unwrap().insert_trade(trade.clone());
+            self.market
+                .get_mut(&code)
+                .unwrap()
+                .insert_trade_on(trade, &mut self.trade_date);
         } else {
-            let mut u = QAPerformance_Single::new();
-            u.insert_trade(trade.clone());
+            let mut u = QAPerformance_Single::new_with_policy(self.policy);
+            u.pnl_mode = self.pnl_mode;
+            u.insert_trade_on(trade, &mut self.trade_date);
             self.market.insert(code.clone(), u);
         }
     }
@@ -89,10 +473,43 @@ impl QAPerformance {
         }
         px
     }
+
+    /// 全部品种的交易对统计
+    pub fn message(&mut self) -> QARiskMessage {
+        QARiskMessage::from_pairs(&self.pair(), self.pnl_mode)
+    }
+
+    /// 持仓期间的 bar, 用于计算 MAE/MFE
+    ///
+    /// bar 需要和成交按时间顺序交替输入, 没有持仓的品种会被忽略
+    pub fn on_bar(&mut self, bar: &BAR) {
+        if let Some(ps) = self.market.get_mut(&bar.code) {
+            ps.on_bar(bar);
+        }
+    }
+
+    pub fn excursion(&mut self) -> Vec<QAExcursion> {
+        let mut ex: Vec<QAExcursion> = self.pair().iter().map(|p| p.to_excursion()).collect();
+        ex.sort_by(|a, b| a.closedate.cmp(&b.closedate));
+        ex
+    }
+
+    pub fn excursion_to_csv(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        let mut wtr = csv::Writer::from_path(path)?;
+        for item in self.excursion().iter() {
+            wtr.serialize(item)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
 }
 
 impl QAPerformance_Single {
     pub fn new() -> Self {
+        Self::new_with_policy(QAMatchPolicy::FIFO)
+    }
+
+    pub fn new_with_policy(policy: QAMatchPolicy) -> Self {
         let mut temp = HashMap::new();
         temp.insert("BUY".to_string(), vec![]);
         temp.insert("SELL".to_string(), vec![]);
@@ -100,9 +517,58 @@ impl QAPerformance_Single {
             market_set: MarketPreset::new(),
             pair: vec![],
             temp,
+            policy,
+            pnl_mode: QAPnlMode::Gross,
+            events: vec![],
+            trade_date: None,
         }
     }
+    /// 按当前的 policy 重新计算全部交易对
+    pub fn set_policy(&mut self, policy: QAMatchPolicy) {
+        let mut td = self.trade_date.take().unwrap_or_default();
+        self.replay(policy, &mut td);
+        self.trade_date = Some(td);
+    }
+
+    fn replay(&mut self, policy: QAMatchPolicy, td: &mut QATradeDate) {
+        self.policy = policy;
+        self.pair = vec![];
+        for u in self.temp.values_mut() {
+            u.clear();
+        }
+        for event in std::mem::take(&mut self.events) {
+            match event {
+                QAPerformanceEvent::Trade(trade) => self.insert_trade_on(trade, td),
+                QAPerformanceEvent::Bar(bar) => self.on_bar(&bar),
+            }
+        }
+    }
+
+    /// update the high/low of the open trades, the bar is only kept for set_policy
+    /// while a trade is open since it changes nothing otherwise
+    pub fn on_bar(&mut self, bar: &BAR) {
+        let mut open = false;
+        if let Some(datetime) = parse_bar_datetime(&bar.datetime) {
+            for u in self.temp.values_mut() {
+                for f in u.iter_mut().filter(|f| f.code == bar.code) {
+                    f.update_extreme(bar.high, bar.low, datetime);
+                    open = true;
+                }
+            }
+        }
+        if open {
+            self.events.push(QAPerformanceEvent::Bar(bar.clone()));
+        }
+    }
+
     pub fn insert_trade(&mut self, trade: Trade) {
+        let mut td = self.trade_date.take().unwrap_or_default();
+        self.insert_trade_on(trade, &mut td);
+        self.trade_date = Some(td);
+    }
+
+    fn insert_trade_on(&mut self, trade: Trade, td: &mut QATradeDate) {
+        self.events.push(QAPerformanceEvent::Trade(trade.clone()));
         match trade.offset.as_str() {
             "OPEN" => {
                 let direction = trade.direction.as_str();
@@ -115,6 +581,12 @@ impl QAPerformance_Single {
                     code: trade.instrument_id.clone(),
                     price: trade.price.clone(),
                     trade_id: trade.trade_id.clone(),
+                    trading_day: get_trading_day(td, trade.trade_date_time),
+                    commission: trade.commission,
+                    high: trade.price,
+                    low: trade.price,
+                    high_datetime: trade.trade_date_time,
+                    low_datetime: trade.trade_date_time,
                 });
             }
             "CLOSE" | "CLOSETODAY" => {
@@ -123,101 +595,72 @@ impl QAPerformance_Single {
                     "SELL" => ("BUY", true),
                     _ => ("", false),
                 };
-                let u = self.temp.get_mut(raw_direction).unwrap();
-                //println!("{:#?}", u);
-
                 let mut codeset = self.market_set.get(trade.instrument_id.as_ref());
+                let u = match self.temp.get_mut(raw_direction) {
+                    Some(u) => u,
+                    None => return,
+                };
 
-                let f = u.get_mut(0).unwrap();
+                // 平今只能匹配当日开仓
+                let trading_day = get_trading_day(td, trade.trade_date_time);
+                let mut eligible: Vec<usize> = (0..u.len())
+                    .filter(|i| trade.offset != "CLOSETODAY" || u[*i].trading_day == trading_day)
+                    .collect();
+                if self.policy == QAMatchPolicy::LIFO {
+                    eligible.reverse();
+                }
+                let avg_price = if self.policy == QAMatchPolicy::AverageCost {
+                    let amount: f64 = eligible.iter().map(|i| u[*i].amount).sum();
+                    let cost: f64 = eligible.iter().map(|i| u[*i].amount * u[*i].price).sum();
+                    if amount > 0.0 {
+                        Some(cost / amount)
+                    } else {
+                        None
+                    }
+                } else {
+                    None
+                };
 
-                if trade.volume > f.amount {
-                    // close> raw ==> 注销继续loop
-                    let hold_gap = (trade.trade_date_time.clone() - f.datetime.clone()) as f64/1000000000.0;
-                    let mut pnl_money = codeset.unit_table as f64
-                        * (trade.price.clone() - f.price.clone())
-                        * f.amount.clone();
-                    if !is_buy{
-                        pnl_money = pnl_money * -1.0;
+                let mut left = trade.volume;
+                let mut finished = vec![];
+                for i in eligible.iter() {
+                    if left <= 0.0 {
+                        break;
                     }
-                    let pnl_ratio =
-                        pnl_money / (f.price.clone() * f.amount.clone() * codeset.calc_coeff());
-                    self.pair.push(QATradePair {
-                        open_datetime: f.datetime.clone(),
-                        close_datetime: trade.trade_date_time.clone(),
-                        opendate:  Utc.timestamp_nanos(f.datetime.clone()+ 28800000000000).to_string()[0..19].to_string(),
-                        closedate: Utc.timestamp_nanos(trade.trade_date_time.clone()+ 28800000000000).to_string()[0..19].to_string(),
-                        if_buyopen: is_buy,
-                        code: f.code.clone(),
-                        amount: f.amount.clone(),
-                        openprice: f.price.clone(),
-                        closeprice: trade.price.clone(),
-                        open_trade_id: f.trade_id.clone(),
-                        close_trade_id: trade.trade_id.clone(),
-                        pnl_ratio,
-                        pnl_money,
-                        hold_gap
-                    });
-                    let mut new_t = trade.clone();
-
-                    new_t.volume -= f.amount;
-                    u.remove(0);
-                    self.insert_trade(new_t)
-                } else if trade.volume < f.amount {
-                    let hold_gap: f64 = (trade.trade_date_time.clone() - f.datetime.clone()) as f64/1000000000.0;
-                    let mut pnl_money = codeset.unit_table as f64
-                        * (trade.price.clone() - f.price.clone())
-                        * trade.volume.clone();
-                    if !is_buy{
-                        pnl_money = pnl_money * -1.0;
+                    let f = u.get_mut(*i).unwrap();
+                    f.update_extreme(trade.price, trade.price, trade.trade_date_time);
+                    let amount = if left < f.amount { left } else { f.amount };
+                    let openprice = avg_price.unwrap_or(f.price);
+                    // 部分平仓按数量分摊开仓手续费
+                    let open_commission = f.commission * amount / f.amount;
+                    self.pair.push(build_pair(
+                        &mut codeset,
+                        f,
+                        openprice,
+                        &trade,
+                        amount,
+                        is_buy,
+                        open_commission,
+                    ));
+                    f.commission -= open_commission;
+                    f.amount -= amount;
+                    left -= amount;
+                    if f.amount <= 0.0 {
+                        finished.push(*i);
                     }
-                    let pnl_ratio =
-                        pnl_money / (f.price.clone() * trade.volume.clone() * codeset.calc_coeff());
-                    self.pair.push(QATradePair {
-                        open_datetime: f.datetime.clone(),
-                        close_datetime: trade.trade_date_time.clone(),
-                        opendate:  Utc.timestamp_nanos(f.datetime.clone()+ 28800000000000).to_string()[0..19].to_string(),
-                        closedate: Utc.timestamp_nanos(trade.trade_date_time.clone()+ 28800000000000).to_string()[0..19].to_string(),
-                        if_buyopen: is_buy,
-                        code: f.code.clone(),
-                        amount: trade.volume.clone(),
-                        openprice: f.price.clone(),
-                        closeprice: trade.price.clone(),
-                        open_trade_id: f.trade_id.clone(),
-                        close_trade_id: trade.trade_id.clone(),
-                        pnl_ratio,
-                        pnl_money,
-                        hold_gap
-                    });
-                    f.amount -= trade.volume.clone();
-
-                    //u.insert(0, f.clone());
-                } else {
-                    let mut pnl_money = codeset.unit_table as f64
-                        * (trade.price.clone() - f.price.clone())
-                        * f.amount.clone();
-                    if !is_buy{
-                        pnl_money = pnl_money * -1.0;
+                }
+                if let Some(avg) = avg_price {
+                    // 剩余仓位的成本同样为均价
+                    for i in eligible.iter() {
+                        u[*i].price = avg;
                     }
-                    let pnl_ratio =
-                        pnl_money / (f.price.clone() * f.amount.clone() * codeset.calc_coeff());
-                    let hold_gap:f64 = (trade.trade_date_time.clone() - f.datetime.clone()) as f64/1000000000.0;
-                    self.pair.push(QATradePair {
-                        open_datetime: f.datetime.clone(),
-                        close_datetime: trade.trade_date_time.clone(),
-                        opendate:  Utc.timestamp_nanos(f.datetime.clone()+ 28800000000000).to_string()[0..19].to_string(),
-                        closedate: Utc.timestamp_nanos(trade.trade_date_time.clone()+ 28800000000000).to_string()[0..19].to_string(),
-                        if_buyopen: is_buy,
-                        code: f.code.clone(),
-                        amount: f.amount.clone(),
-                        openprice: f.price.clone(),
-                        closeprice: trade.price.clone(),
-                        open_trade_id: f.trade_id.clone(),
-                        close_trade_id: trade.trade_id.clone(),
-                        pnl_ratio,
-                        pnl_money,
-                        hold_gap
-                    });
-                    u.remove(0);
+                }
+                finished.sort();
+                for i in finished.iter().rev() {
+                    u.remove(*i);
+                }
+                if left > 0.0 {
+                    warn!("NO OPEN TRADE TO MATCH {} {}", trade.trade_id, left);
                 }
             }
             _ => {}
@@ -225,10 +668,11 @@ impl QAPerformance_Single {
     }
     pub fn get_totalprofit(&mut self) -> f64 {
         let mut profit = 0.0;
+        let mode = self.pnl_mode;
         let _: Vec<_> = self
             .pair
             .iter_mut()
-            .map(|a| profit += a.pnl_money)
+            .map(|a| profit += a.pnl(mode))
             .collect();
         profit
     }
@@ -241,10 +685,11 @@ impl QAPerformance_Single {
     /// 手续费贡献：差额手续费（元）/日出总金额（万）
     pub fn get_maxprofit(&mut self) -> f64 {
         let mut profit: Vec<f64> = vec![];
+        let mode = self.pnl_mode;
         let _: Vec<_> = self
             .pair
             .iter_mut()
-            .map(|a| profit.push(a.pnl_money))
+            .map(|a| profit.push(a.pnl(mode)))
             .collect();
         profit.iter().cloned().fold(0. / 0., f64::max)
     }
@@ -257,24 +702,29 @@ impl QAPerformance_Single {
     }
     pub fn get_profitcount(&mut self) -> i32 {
         let mut count = 0;
+        let mode = self.pnl_mode;
         let _: Vec<_> = self
             .pair
             .iter_mut()
             .map(|a| {
-                if a.pnl_money > 0.0 {
+                if a.pnl(mode) > 0.0 {
                     count += 1
                 }
             })
             .collect();
         count
     }
+    pub fn message(&mut self) -> QARiskMessage {
+        QARiskMessage::from_pairs(&self.pair, self.pnl_mode)
+    }
     pub fn get_losscount(&mut self) -> i32 {
         let mut count = 0;
+        let mode = self.pnl_mode;
         let _: Vec<_> = self
             .pair
             .iter_mut()
             .map(|a| {
-                if a.pnl_money < 0.0 {
+                if a.pnl(mode) < 0.0 {
                     count += 1
                 }
             })
@@ -286,6 +736,7 @@ impl QAPerformance_Single {
 #[cfg(test)]
 mod tests {
     use crate::qaaccount::QA_Account;
+    use crate::test_helper::bar;
 
     use super::*;
 
@@ -390,6 +841,179 @@ mod tests {
         // println!("{:#?}", p.get_averageprofit());
     }
 
+    #[test]
+    fn test_message() {
+        let mut acc = QA_Account::new("test", "test", "admin", 1000000.0, false, "real");
+        let code = "rb2005";
+        let mut p = QAPerformance::new();
+        acc.buy_open(code, 10.0, "2020-04-03 09:30:00", 3500.0);
+        acc.sell_close(code, 10.0, "2020-04-03 10:30:00", 3520.0);
+        acc.sell_open(code, 10.0, "2020-04-03 13:30:00", 3520.0);
+        acc.buy_close(code, 10.0, "2020-04-03 14:00:00", 3530.0);
+        acc.buy_open(code, 10.0, "2020-04-03 14:10:00", 3530.0);
+        acc.sell_close(code, 10.0, "2020-04-03 14:50:00", 3510.0);
+        acc.buy_open("000001", 1000.0, "2020-04-03 09:40:00", 12.0);
+        acc.sell_close("000001", 1000.0, "2020-04-03 14:55:00", 12.5);
+        for (_, i) in acc.dailytrades.iter_mut() {
+            p.insert_trade(i.to_owned());
+        }
+        let msg = p.message();
+        println!("{}", msg.to_json());
+        assert_eq!(msg.total_count, 4);
+        assert_eq!(msg.profit_count, 2);
+        assert_eq!(msg.loss_count, 2);
+        assert_eq!(msg.win_rate, 0.5);
+        assert_eq!(msg.gross_profit, 2500.0);
+        assert_eq!(msg.gross_loss, -3000.0);
+        assert_eq!(msg.largest_loss, -2000.0);
+        assert_eq!(msg.max_profit, 2000.0);
+        assert_eq!(msg.max_consecutive_losses, 2);
+        assert_eq!(msg.max_consecutive_wins, 1);
+        assert!((msg.profit_factor - 2500.0 / 3000.0).abs() < 1e-9);
+        assert_eq!(msg.payoff_ratio, 1250.0 / 1500.0);
+        assert_eq!(msg.expectancy, -125.0);
+        assert_eq!(msg.by_code["rb2005"].count, 3);
+        assert_eq!(msg.by_direction["SHORT"].total_profit, -1000.0);
+        assert_eq!(msg.by_hour[&14].count, 1);
+        assert_eq!(msg.by_weekday["Fri"].count, 4);
+        assert_eq!(msg.average_hold_gap, (3600.0 + 1800.0 + 2400.0 + 18900.0) / 4.0);
+    }
+
+    #[test]
+    fn test_message_no_loss() {
+        let mut acc = QA_Account::new("test", "test", "admin", 1000000.0, false, "real");
+        acc.buy_open("rb2005", 10.0, "2020-04-03 09:30:00", 3500.0);
+        acc.sell_close("rb2005", 10.0, "2020-04-03 10:30:00", 3520.0);
+        let mut p = QAPerformance::new();
+        for (_, i) in acc.dailytrades.iter_mut() {
+            p.insert_trade(i.to_owned());
+        }
+        let msg = p.message();
+        assert_eq!(msg.loss_count, 0);
+        assert_eq!(msg.profit_factor, std::f64::INFINITY);
+        assert_eq!(msg.payoff_ratio, std::f64::INFINITY);
+        // nothing traded, nothing to compare
+        assert_eq!(QAPerformance::new().message().profit_factor, 0.0);
+    }
+
+    #[test]
+    fn test_policy() {
+        let mut acc = QA_Account::new("test", "test", "admin", 1000000.0, false, "real");
+        let code = "rb2005";
+        acc.buy_open(code, 10.0, "2020-04-02 09:30:00", 3500.0);
+        acc.buy_open(code, 10.0, "2020-04-03 09:30:00", 3600.0);
+        acc.sell_closetoday(code, 5.0, "2020-04-03 10:00:00", 3650.0);
+        acc.sell_close(code, 10.0, "2020-04-03 11:00:00", 3700.0);
+        let mut trades: Vec<Trade> = acc.dailytrades.values().cloned().collect();
+        trades.sort_by_key(|t| t.trade_date_time);
+
+        let mut p = QAPerformance::new();
+        for t in trades {
+            p.insert_trade(t);
+        }
+        let ps = p.market.get_mut(code).unwrap();
+        // 平今只匹配 2020-04-03 的开仓
+        assert_eq!(ps.pair[0].openprice, 3600.0);
+        assert_eq!(ps.pair[0].pnl_money, 2500.0);
+        assert_eq!(ps.get_totalprofit(), 22500.0);
+
+        p.set_policy(QAMatchPolicy::LIFO);
+        let ps = p.market.get_mut(code).unwrap();
+        assert_eq!(ps.pair.len(), 3);
+        assert_eq!(ps.pair[0].pnl_money, 2500.0);
+        assert_eq!(ps.get_totalprofit(), 2500.0 + 5000.0 + 10000.0);
+
+        p.set_policy(QAMatchPolicy::AverageCost);
+        let ps = p.market.get_mut(code).unwrap();
+        assert_eq!(ps.pair[0].pnl_money, 2500.0);
+        let avg = (3500.0 * 10.0 + 3600.0 * 5.0) / 15.0;
+        assert!((ps.get_totalprofit() - 2500.0 - 10.0 * (3700.0 - avg) * 10.0).abs() < 1e-6);
+        assert!(ps.temp["BUY"].iter().all(|t| t.price == avg));
+    }
+
+    #[test]
+    fn test_net_pnl() {
+        let mut acc = QA_Account::new("test", "test", "admin", 1000000.0, false, "real");
+        let code = "rb2005";
+        acc.buy_open(code, 10.0, "2020-04-03 09:30:00", 3500.0);
+        acc.sell_close(code, 4.0, "2020-04-03 10:00:00", 3520.0);
+        acc.sell_close(code, 6.0, "2020-04-03 10:30:00", 3490.0);
+        let mut trades: Vec<Trade> = acc.dailytrades.values().cloned().collect();
+        trades.sort_by_key(|t| t.trade_date_time);
+        let open_commission = trades[0].commission;
+        let commission: f64 = trades.iter().map(|t| t.commission).sum();
+        assert!(commission > 0.0);
+
+        let mut p = QAPerformance::new();
+        for t in trades.iter() {
+            p.insert_trade(t.clone());
+        }
+        let pairs = p.pair();
+        assert_eq!(pairs.len(), 2);
+        assert!((pairs[0].open_commission - open_commission * 0.4).abs() < 1e-9);
+        assert!((pairs[1].open_commission - open_commission * 0.6).abs() < 1e-9);
+        assert_eq!(pairs[0].close_commission, trades[1].commission);
+        assert_eq!(
+            pairs[0].net_pnl_money,
+            pairs[0].pnl_money - pairs[0].open_commission - pairs[0].close_commission
+        );
+
+        let gross = p.get_totalprofit();
+        assert_eq!(gross, 800.0 - 600.0);
+        p.set_pnl_mode(QAPnlMode::Net);
+        assert!((p.get_totalprofit() - (gross - commission)).abs() < 1e-9);
+        let msg = p.message();
+        assert_eq!(msg.pnl_mode, QAPnlMode::Net);
+        assert!((msg.total_commission - commission).abs() < 1e-9);
+        assert!((msg.total_profit - (gross - commission)).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_excursion() {
+        let mut acc = QA_Account::new("test", "test", "admin", 1000000.0, false, "real");
+        let code = "rb2005";
+        // opens at the low, closes at the high
+        let up_bar = |datetime: &str, high: f64, low: f64| bar(code, datetime, low).close(high).build();
+        let mut p = QAPerformance::new();
+        let insert = |acc: &mut QA_Account, p: &mut QAPerformance| {
+            let mut trades: Vec<Trade> = acc.dailytrades.values().cloned().collect();
+            trades.sort_by_key(|t| t.trade_date_time);
+            p.insert_trade(trades.last().unwrap().clone());
+        };
+        acc.buy_open(code, 10.0, "2020-04-03 09:30:00", 3500.0);
+        insert(&mut acc, &mut p);
+        p.on_bar(&up_bar("2020-04-03 09:31:00", 3520.0, 3490.0));
+        p.on_bar(&up_bar("2020-04-03 09:32:00", 3550.0, 3480.0));
+        acc.sell_close(code, 10.0, "2020-04-03 09:40:00", 3510.0);
+        insert(&mut acc, &mut p);
+        // flat, the bar is not kept
+        p.on_bar(&up_bar("2020-04-03 09:50:00", 3600.0, 3400.0));
+        acc.sell_open(code, 10.0, "2020-04-03 10:00:00", 3500.0);
+        insert(&mut acc, &mut p);
+        p.on_bar(&up_bar("2020-04-03 10:01:00", 3530.0, 3470.0));
+        acc.buy_close(code, 10.0, "2020-04-03 10:05:00", 3480.0);
+        insert(&mut acc, &mut p);
+
+        let ex = p.excursion();
+        assert_eq!(ex.len(), 2);
+        assert_eq!((ex[0].mfe_price, ex[0].mae_price), (50.0, 20.0));
+        assert_eq!((ex[0].mfe_money, ex[0].mae_money), (5000.0, 2000.0));
+        assert_eq!(ex[0].mfe_time, 120.0);
+        assert_eq!((ex[1].mfe_price, ex[1].mae_price), (30.0, 30.0));
+        assert_eq!(ex[1].mfe_time, 60.0);
+        assert_eq!(p.market[code].events.len(), 4 + 3);
+
+        // bars are replayed when the pairs are recomputed
+        p.set_policy(QAMatchPolicy::LIFO);
+        assert_eq!(p.excursion()[0].mfe_price, 50.0);
+
+        let path = std::env::temp_dir().join("qaperformance_excursion.csv");
+        p.excursion_to_csv(path.to_str().unwrap()).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(text.lines().count(), 3);
+        assert!(text.starts_with("code,opendate,closedate"));
+    }
+
     #[test]
     fn test_pairtoday() {
         let mut acc = QA_Account::new("test", "test", "admin", 1000000.0, false, "real");
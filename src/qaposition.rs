@@ -315,6 +315,30 @@ impl QA_Postions {
         }
     }
 
+    /// write back the stored fields of a qifi position, the derived ones (volume_long, float_profit ...) are recomputed
+    pub fn apply_qifi_position(&mut self, p: &Position) {
+        self.exchange_id = p.exchange_id.clone();
+        self.volume_long_today = p.volume_long_today;
+        self.volume_long_his = p.volume_long_his;
+        self.volume_short_today = p.volume_short_today;
+        self.volume_short_his = p.volume_short_his;
+        self.volume_long_frozen_today = p.volume_long_frozen_today;
+        self.volume_long_frozen_his = p.volume_long_frozen_his;
+        self.volume_short_frozen_today = p.volume_short_frozen_today;
+        self.volume_short_frozen_his = p.volume_short_frozen_his;
+        self.open_price_long = p.open_price_long;
+        self.open_price_short = p.open_price_short;
+        self.open_cost_long = p.open_cost_long;
+        self.open_cost_short = p.open_cost_short;
+        self.position_price_long = p.position_price_long;
+        self.position_price_short = p.position_price_short;
+        self.position_cost_long = p.position_cost_long;
+        self.position_cost_short = p.position_cost_short;
+        self.margin_long = p.margin_long;
+        self.margin_short = p.margin_short;
+        self.lastest_price = p.last_price;
+    }
+
     pub fn position_profit_long(&mut self) -> f64 {
         self.lastest_price * self.volume_long() * self.preset.unit_table as f64
             - self.position_cost_long
@@ -382,7 +406,7 @@ impl QA_Postions {
                 self.open_cost_short += temp_cost;
                 self.position_cost_short += temp_cost;
             }
-            3 => {
+            3 | 4 => {
                 //self.volume_short_today -= amount;
                 // 有昨仓先平昨仓
 
@@ -392,7 +416,14 @@ impl QA_Postions {
                 self.open_cost_short =
                     self.open_cost_short * (volume_short - amount) / volume_short;
 
-                self.volume_short_frozen_today -= amount;
+                // 平今 (4) 只冻结了今仓
+                let his = if towards == 3 {
+                    amount.min(self.volume_short_frozen_his)
+                } else {
+                    0.0
+                };
+                self.volume_short_frozen_his -= his;
+                self.volume_short_frozen_today -= amount - his;
 
                 //println!("amount  {},position_price_short {}", amount, self.position_price_short);
 
@@ -431,7 +462,7 @@ impl QA_Postions {
                     self.margin_long += margin_value;
                 } else {}
             }
-            -3 => {
+            -3 | -4 => {
                 //self.volume_long_today -= amount;
 
                 let volume_long = self.volume_long();
@@ -439,7 +470,13 @@ impl QA_Postions {
                     self.position_cost_long * (volume_long - amount) / volume_long;
                 self.open_cost_long = self.open_cost_long * (volume_long - amount) / volume_long;
 
-                self.volume_long_frozen_today -= amount;
+                let his = if towards == -3 {
+                    amount.min(self.volume_long_frozen_his)
+                } else {
+                    0.0
+                };
+                self.volume_long_frozen_his -= his;
+                self.volume_long_frozen_today -= amount - his;
                 margin_value = -1.0
                     * (self.position_price_long
                     * amount
@@ -0,0 +1,291 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::qaaccount::QA_Account;
+use crate::qaorder::TradeOrder;
+
+/// otg/QIFI 交易协议
+///
+/// 所有消息通过 aid 区分, 下行的 rtn_data 为 json merge patch 形式的增量数据
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReqLogin {
+    pub aid: String,
+    pub bid: String,
+    pub user_name: String,
+    pub password: String,
+}
+
+impl ReqLogin {
+    pub fn new(bid: &str, user_name: &str, password: &str) -> Self {
+        ReqLogin {
+            aid: "req_login".to_string(),
+            bid: bid.to_string(),
+            user_name: user_name.to_string(),
+            password: password.to_string(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CancelOrder {
+    pub aid: String,
+    pub user_id: String,
+    pub order_id: String,
+}
+
+impl CancelOrder {
+    pub fn new(user_id: &str, order_id: &str) -> Self {
+        CancelOrder {
+            aid: "cancel_order".to_string(),
+            user_id: user_id.to_string(),
+            order_id: order_id.to_string(),
+        }
+    }
+}
+
+/// notify: {"type": "MESSAGE", "level": "INFO", "code": 1000, "content": "..."}
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Notify {
+    #[serde(rename = "type")]
+    pub notify_type: String,
+    pub level: String,
+    pub code: i64,
+    pub content: String,
+}
+
+/// the diff of one trading user, every value is a merge patch
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TradeDiff {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub user_id: Option<String>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub accounts: HashMap<String, Value>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub positions: HashMap<String, Value>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub orders: HashMap<String, Value>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub trades: HashMap<String, Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RtnDataItem {
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub trade: HashMap<String, TradeDiff>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub notify: HashMap<String, Notify>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RtnData {
+    pub aid: String,
+    pub data: Vec<RtnDataItem>,
+}
+
+impl RtnData {
+    pub fn new(data: Vec<RtnDataItem>) -> Self {
+        RtnData {
+            aid: "rtn_data".to_string(),
+            data,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum QAMessage {
+    ReqLogin(ReqLogin),
+    InsertOrder(TradeOrder),
+    CancelOrder(CancelOrder),
+    RtnData(RtnData),
+}
+
+impl QAMessage {
+    pub fn to_json(&self) -> String {
+        match self {
+            QAMessage::ReqLogin(msg) => serde_json::to_string(msg),
+            QAMessage::InsertOrder(msg) => serde_json::to_string(msg),
+            QAMessage::CancelOrder(msg) => serde_json::to_string(msg),
+            QAMessage::RtnData(msg) => serde_json::to_string(msg),
+        }
+        .unwrap()
+    }
+}
+
+/// 解析一条协议消息
+pub fn parse_message(message: &str) -> Result<QAMessage, String> {
+    let value: Value = serde_json::from_str(message).map_err(|e| e.to_string())?;
+    let aid = value
+        .get("aid")
+        .and_then(|aid| aid.as_str())
+        .ok_or_else(|| "message without aid".to_string())?
+        .to_string();
+    let msg = match aid.as_str() {
+        "req_login" => serde_json::from_value(value).map(QAMessage::ReqLogin),
+        "insert_order" => serde_json::from_value(value).map(QAMessage::InsertOrder),
+        "cancel_order" => serde_json::from_value(value).map(QAMessage::CancelOrder),
+        "rtn_data" => serde_json::from_value(value).map(QAMessage::RtnData),
+        _ => return Err(format!("unknown aid {}", aid)),
+    };
+    msg.map_err(|e| e.to_string())
+}
+
+/// callbacks of the dispatcher, all of them are no-op by default
+pub trait QAProtocolHandler {
+    fn on_req_login(&mut self, _msg: ReqLogin) {}
+    fn on_insert_order(&mut self, _msg: TradeOrder) {}
+    fn on_cancel_order(&mut self, _msg: CancelOrder) {}
+    fn on_rtn_data(&mut self, _msg: RtnData) {}
+    fn on_notify(&mut self, _id: &str, _msg: Notify) {}
+}
+
+/// 解析并分发消息, rtn_data 中的 notify 会额外分发到 on_notify
+pub fn dispatch<H: QAProtocolHandler>(handler: &mut H, message: &str) -> Result<(), String> {
+    match parse_message(message)? {
+        QAMessage::ReqLogin(msg) => handler.on_req_login(msg),
+        QAMessage::InsertOrder(msg) => handler.on_insert_order(msg),
+        QAMessage::CancelOrder(msg) => handler.on_cancel_order(msg),
+        QAMessage::RtnData(msg) => {
+            for item in msg.data.iter() {
+                for (id, notify) in item.notify.iter() {
+                    handler.on_notify(id, notify.clone());
+                }
+            }
+            handler.on_rtn_data(msg)
+        }
+    }
+    Ok(())
+}
+
+/// json merge patch, a null in the patch removes the key
+pub fn merge_patch(target: &mut Value, patch: &Value) {
+    match patch {
+        Value::Object(p) => {
+            if !target.is_object() {
+                *target = Value::Object(Map::new());
+            }
+            let t = target.as_object_mut().unwrap();
+            for (k, v) in p.iter() {
+                if v.is_null() {
+                    t.remove(k);
+                } else {
+                    merge_patch(t.entry(k.clone()).or_insert(Value::Null), v);
+                }
+            }
+        }
+        _ => *target = patch.clone(),
+    }
+}
+
+impl QAProtocolHandler for QA_Account {
+    fn on_insert_order(&mut self, msg: TradeOrder) {
+        let towards = match (msg.direction.as_str(), msg.offset.as_str()) {
+            ("BUY", "OPEN") => 2,
+            ("SELL", "OPEN") => -2,
+            ("BUY", "CLOSE") => 3,
+            ("SELL", "CLOSE") => -3,
+            ("BUY", "CLOSETODAY") => 4,
+            ("SELL", "CLOSETODAY") => -4,
+            _ => return,
+        };
+        let time = self.get_account_info().datetime;
+        let _ = self.send_order(
+            msg.instrument_id.as_ref(),
+            msg.volume as f64,
+            time.as_ref(),
+            towards,
+            msg.limit_price,
+            msg.order_id.as_ref(),
+        );
+    }
+
+    fn on_cancel_order(&mut self, msg: CancelOrder) {
+        let _ = self.cancel_order(msg.order_id.as_ref());
+    }
+
+    fn on_rtn_data(&mut self, msg: RtnData) {
+        self.apply_rtn_data(&msg);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_message() {
+        let login = ReqLogin::new("simnow", "000001", "123456");
+        let msg = parse_message(&serde_json::to_string(&login).unwrap()).unwrap();
+        match msg {
+            QAMessage::ReqLogin(m) => assert_eq!(m.user_name, "000001"),
+            _ => panic!("wrong message"),
+        }
+        let cancel = CancelOrder::new("000001", "order1");
+        let msg = parse_message(&QAMessage::CancelOrder(cancel).to_json()).unwrap();
+        match msg {
+            QAMessage::CancelOrder(m) => assert_eq!(m.order_id, "order1"),
+            _ => panic!("wrong message"),
+        }
+        assert!(parse_message(r#"{"aid": "peek_message"}"#).is_err());
+        assert!(parse_message(r#"{"user_id": "x"}"#).is_err());
+    }
+
+    #[test]
+    fn test_merge_patch() {
+        let mut target = serde_json::json!({"a": 1, "b": {"c": 2, "d": 3}});
+        merge_patch(&mut target, &serde_json::json!({"b": {"c": 4, "d": null}, "e": 5}));
+        assert_eq!(target, serde_json::json!({"a": 1, "b": {"c": 4}, "e": 5}));
+    }
+
+    #[test]
+    fn test_rtn_data() {
+        let mut acc = QA_Account::new("000001", "test", "admin", 100000.0, false, "real");
+        acc.buy_open("rb2005", 2.0, "2020-01-20 22:10:00", 3500.0);
+        let rtn = acc.get_rtn_data();
+        let text = QAMessage::RtnData(rtn).to_json();
+        println!("{}", text);
+
+        let mut remote = QA_Account::new("000001", "test", "admin", 100000.0, false, "real");
+        dispatch(&mut remote, &text).unwrap();
+        assert_eq!(remote.get_volume_long("rb2005"), 2.0);
+        assert_eq!(remote.money, acc.money);
+        assert_eq!(remote.dailyorders.len(), 1);
+        assert_eq!(remote.dailytrades.len(), 1);
+
+        let (margin, cost) = {
+            let pos = remote.get_position("rb2005").unwrap();
+            // one lot frozen by a pending close
+            pos.volume_long_today -= 1.0;
+            pos.volume_long_frozen_today += 1.0;
+            (pos.margin_long, pos.position_cost_long)
+        };
+
+        let order_id = remote.dailyorders.keys().next().unwrap().clone();
+        let patch = format!(
+            r#"{{"aid": "rtn_data", "data": [
+                {{"trade": {{"000001": {{
+                    "accounts": {{"CNY": {{"available": 1000.0}}}},
+                    "orders": {{"{}": {{"last_msg": "patched"}}}},
+                    "positions": {{"SHFE.rb2005": {{"volume_long_his": 1.0}}}}
+                }}}}}},
+                {{"notify": {{"2001": {{"type": "MESSAGE", "level": "INFO", "code": 1000, "content": "ok"}}}}}}
+            ]}}"#,
+            order_id
+        );
+        dispatch(&mut remote, &patch).unwrap();
+        assert_eq!(remote.money, 1000.0);
+        assert_eq!(remote.dailyorders[&order_id].last_msg, "patched");
+        assert_eq!(remote.get_volume_long("rb2005"), 3.0);
+        // fields missing from the patch are kept
+        let pos = remote.get_position("rb2005").unwrap();
+        assert_eq!(pos.volume_long_frozen_today, 1.0);
+        assert_eq!(pos.margin_long, margin);
+        assert_eq!(pos.position_cost_long, cost);
+
+        let remove = r#"{"aid": "rtn_data", "data": [{"trade": {"000001": {"positions": {"rb2005": null}}}}]}"#;
+        dispatch(&mut remote, remove).unwrap();
+        assert!(remote.get_position("rb2005").is_none());
+    }
+}
@@ -0,0 +1,456 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as FmtWrite;
+use std::fs::File;
+use std::io::{self, Write};
+
+use qifi_rs::QIFI;
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::qaaccount::QA_Account;
+use crate::qaperformance::{QAPerformance, QARiskMessage, QATradePair};
+use crate::qarisk::{QAEquityCurve, QAReturnTable, QARiskReport};
+use crate::trade_date::QATradeDate;
+
+const WIDTH: f64 = 860.0;
+const HEIGHT: f64 = 260.0;
+const PAD: f64 = 50.0;
+const COLORS: [&str; 6] = ["#c0392b", "#2471a3", "#229954", "#d68910", "#7d3c98", "#566573"];
+
+/// 回测报告, 由结算后的账户生成单个 html 文件, 图表为内联 svg, 不依赖任何网络资源
+#[derive(Debug, Clone)]
+pub struct QAReport {
+    pub title: String,
+    pub qifi: QIFI,
+    pub curve: QAEquityCurve,
+    pub risk: QARiskReport,
+    pub returns: QAReturnTable,
+    pub message: QARiskMessage,
+    pub pairs: Vec<QATradePair>,
+    /// code -> (trading day, volume_long - volume_short)
+    pub positions: BTreeMap<String, Vec<(String, f64)>>,
+}
+
+impl QAReport {
+    /// the trades are taken from history (backtest) and dailytrades (real, the last trading day only)
+    pub fn from_account(acc: &mut QA_Account) -> Self {
+        let curve = QAEquityCurve::from_account(acc);
+
+        let mut performance = QAPerformance::from_account(acc);
+        let mut pairs = performance.pair();
+        pairs.sort_by_key(|p| p.close_datetime);
+
+        // the curve keeps the last slice of each trading day, so do the positions
+        let mut td = QATradeDate::new();
+        let mut slices: Vec<_> = acc.dailyassets.values().collect();
+        slices.sort_by(|a, b| a.datetime.cmp(&b.datetime));
+        let mut days = BTreeMap::new();
+        for slice in slices {
+            days.insert(td.get_trade_day(slice.datetime.clone()), slice);
+        }
+        let mut positions: BTreeMap<String, Vec<(String, f64)>> = BTreeMap::new();
+        for (date, slice) in days.iter() {
+            for (code, pos) in slice.positions.iter() {
+                let volume = pos.volume_long_today + pos.volume_long_his
+                    - pos.volume_short_today
+                    - pos.volume_short_his;
+                positions
+                    .entry(code.clone())
+                    .or_default()
+                    .push((date.clone(), volume));
+            }
+        }
+
+        QAReport {
+            title: format!("{} backtest report", acc.account_cookie),
+            qifi: acc.get_qifi_slice(),
+            risk: curve.risk_report(0.0),
+            returns: curve.return_table(),
+            message: performance.message(),
+            curve,
+            pairs,
+            positions,
+        }
+    }
+
+    pub fn to_html(&self) -> String {
+        let mut html = String::new();
+        let _ = write!(
+            html,
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>{}</style>\n</head>\n<body>\n<h1>{}</h1>\n",
+            escape(&self.title),
+            STYLE,
+            escape(&self.title)
+        );
+
+        let dates = self.curve.dates();
+        html.push_str("<h2>Equity</h2>\n");
+        html.push_str(&svg_lines(&[("nav".to_string(), self.curve.nav())], &dates));
+        html.push_str("<h2>Drawdown</h2>\n");
+        html.push_str(&svg_underwater(&self.curve.drawdown_series(), &dates));
+        html.push_str("<h2>Monthly returns</h2>\n");
+        html.push_str(&svg_heatmap(&self.returns.heatmap));
+        html.push_str("<h2>Trade pair PnL</h2>\n");
+        let pnl: Vec<f64> = self.pairs.iter().map(|p| p.pnl_money).collect();
+        html.push_str(&svg_histogram(&pnl, 20));
+        html.push_str("<h2>Position</h2>\n");
+        let series: Vec<(String, Vec<f64>)> = self
+            .positions
+            .iter()
+            .map(|(code, v)| {
+                // a code can appear after the first day, pad it with flat
+                let mut volumes = vec![0.0; dates.len() - v.len().min(dates.len())];
+                volumes.extend(v.iter().map(|x| x.1));
+                (code.clone(), volumes)
+            })
+            .collect();
+        html.push_str(&svg_lines(&series, &dates));
+
+        html.push_str("<h2>Risk</h2>\n");
+        html.push_str(&table_from(&self.risk));
+        html.push_str(&table_from(&self.risk.drawdown));
+        html.push_str("<h2>Trade pairs</h2>\n");
+        html.push_str(&table_from(&self.message));
+        html.push_str(&group_table("code", &self.message.by_code));
+        html.push_str(&group_table("direction", &self.message.by_direction));
+
+        html.push_str("<h2>QIFI</h2>\n");
+        let summary = serde_json::json!({
+            "account_cookie": self.qifi.account_cookie,
+            "portfolio": self.qifi.portfolio,
+            "trading_day": self.qifi.trading_day,
+            "updatetime": self.qifi.updatetime,
+        });
+        html.push_str(&table_from(&summary));
+        html.push_str(&table_from(&self.qifi.accounts));
+        let mut codes: Vec<&String> = self.qifi.positions.keys().collect();
+        codes.sort();
+        if !codes.is_empty() {
+            let columns = ["volume_long", "volume_short", "open_price_long", "open_price_short", "last_price", "float_profit", "margin"];
+            html.push_str("<table>\n<tr><th>code</th>");
+            for c in columns.iter() {
+                let _ = write!(html, "<th>{}</th>", c);
+            }
+            html.push_str("</tr>\n");
+            for code in codes {
+                let pos = serde_json::to_value(&self.qifi.positions[code]).unwrap_or(Value::Null);
+                let _ = write!(html, "<tr><td>{}</td>", escape(code));
+                for c in columns.iter() {
+                    let _ = write!(html, "<td>{}</td>", format_value(&pos[*c]));
+                }
+                html.push_str("</tr>\n");
+            }
+            html.push_str("</table>\n");
+        }
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+
+    pub fn to_file(&self, path: &str) -> io::Result<()> {
+        let mut file = File::create(path)?;
+        file.write_all(self.to_html().as_bytes())
+    }
+}
+
+const STYLE: &str = "body{font-family:sans-serif;margin:24px;color:#222}\
+table{border-collapse:collapse;margin:8px 0 16px 0;font-size:13px}\
+td,th{border:1px solid #ccc;padding:3px 8px;text-align:right}\
+th{background:#f2f2f2}svg{background:#fff;border:1px solid #eee}";
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+fn format_value(value: &Value) -> String {
+    match value {
+        Value::Number(n) => match n.as_f64() {
+            Some(f) if f.fract() != 0.0 => format!("{:.4}", f),
+            _ => n.to_string(),
+        },
+        Value::String(s) => escape(s),
+        Value::Bool(b) => b.to_string(),
+        _ => "".to_string(),
+    }
+}
+
+/// key/value table of the scalar fields, nested fields are skipped
+fn table_from<T: Serialize>(item: &T) -> String {
+    let mut html = String::from("<table>\n");
+    if let Ok(Value::Object(map)) = serde_json::to_value(item) {
+        for (k, v) in map.iter() {
+            if v.is_object() || v.is_array() {
+                continue;
+            }
+            let _ = writeln!(html, "<tr><th>{}</th><td>{}</td></tr>", escape(k), format_value(v));
+        }
+    }
+    html.push_str("</table>\n");
+    html
+}
+
+fn group_table<T: Serialize>(name: &str, groups: &BTreeMap<String, T>) -> String {
+    let mut html = String::from("<table>\n");
+    let mut header = false;
+    for (key, item) in groups.iter() {
+        if let Ok(Value::Object(map)) = serde_json::to_value(item) {
+            if !header {
+                let _ = write!(html, "<tr><th>{}</th>", name);
+                for k in map.keys() {
+                    let _ = write!(html, "<th>{}</th>", escape(k));
+                }
+                html.push_str("</tr>\n");
+                header = true;
+            }
+            let _ = write!(html, "<tr><td>{}</td>", escape(key));
+            for v in map.values() {
+                let _ = write!(html, "<td>{}</td>", format_value(v));
+            }
+            html.push_str("</tr>\n");
+        }
+    }
+    html.push_str("</table>\n");
+    html
+}
+
+fn bounds(values: &[f64]) -> (f64, f64) {
+    let min = values.iter().cloned().fold(std::f64::INFINITY, f64::min);
+    let max = values.iter().cloned().fold(std::f64::NEG_INFINITY, f64::max);
+    if !min.is_finite() || !max.is_finite() {
+        (0.0, 1.0)
+    } else if (max - min).abs() < 1e-12 {
+        (min - 1.0, max + 1.0)
+    } else {
+        (min, max)
+    }
+}
+
+fn x_at(i: usize, n: usize) -> f64 {
+    if n < 2 {
+        PAD
+    } else {
+        PAD + i as f64 * (WIDTH - 2.0 * PAD) / (n - 1) as f64
+    }
+}
+
+fn y_at(v: f64, min: f64, max: f64) -> f64 {
+    HEIGHT - PAD - (v - min) / (max - min) * (HEIGHT - 2.0 * PAD)
+}
+
+fn svg_open() -> String {
+    format!(
+        "<svg width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\">\n",
+        WIDTH, HEIGHT, WIDTH, HEIGHT
+    )
+}
+
+fn svg_axis(svg: &mut String, min: f64, max: f64, labels: &[String]) {
+    let _ = write!(
+        svg,
+        "<line x1=\"{p}\" y1=\"{b}\" x2=\"{r}\" y2=\"{b}\" stroke=\"#999\"/>\n\
+         <line x1=\"{p}\" y1=\"{p}\" x2=\"{p}\" y2=\"{b}\" stroke=\"#999\"/>\n\
+         <text x=\"4\" y=\"{p}\" font-size=\"11\">{max:.4}</text>\n\
+         <text x=\"4\" y=\"{b}\" font-size=\"11\">{min:.4}</text>\n",
+        p = PAD,
+        b = HEIGHT - PAD,
+        r = WIDTH - PAD,
+        max = max,
+        min = min
+    );
+    if let (Some(first), Some(last)) = (labels.first(), labels.last()) {
+        let _ = write!(
+            svg,
+            "<text x=\"{}\" y=\"{}\" font-size=\"11\">{}</text>\n\
+             <text x=\"{}\" y=\"{}\" font-size=\"11\" text-anchor=\"end\">{}</text>\n",
+            PAD,
+            HEIGHT - PAD + 16.0,
+            escape(first),
+            WIDTH - PAD,
+            HEIGHT - PAD + 16.0,
+            escape(last)
+        );
+    }
+}
+
+/// line chart, every series has one value per label
+fn svg_lines(series: &[(String, Vec<f64>)], labels: &[String]) -> String {
+    let all: Vec<f64> = series.iter().flat_map(|s| s.1.iter().cloned()).collect();
+    let (min, max) = bounds(&all);
+    let mut svg = svg_open();
+    svg_axis(&mut svg, min, max, labels);
+    for (i, (name, values)) in series.iter().enumerate() {
+        let color = COLORS[i % COLORS.len()];
+        let points: Vec<String> = values
+            .iter()
+            .enumerate()
+            .map(|(j, v)| format!("{:.1},{:.1}", x_at(j, values.len()), y_at(*v, min, max)))
+            .collect();
+        let _ = write!(
+            svg,
+            "<polyline fill=\"none\" stroke=\"{}\" stroke-width=\"1.5\" points=\"{}\"/>\n\
+             <text x=\"{}\" y=\"{}\" font-size=\"12\" fill=\"{}\">{}</text>\n",
+            color,
+            points.join(" "),
+            WIDTH - PAD + 4.0,
+            PAD + 14.0 * i as f64,
+            color,
+            escape(name)
+        );
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+/// drawdown as a filled area under zero
+fn svg_underwater(drawdown: &[f64], labels: &[String]) -> String {
+    let (min, _) = bounds(drawdown);
+    let min = min.min(-1e-6);
+    let max = 0.0;
+    let mut svg = svg_open();
+    svg_axis(&mut svg, min, max, labels);
+    let n = drawdown.len();
+    let mut points = vec![format!("{:.1},{:.1}", x_at(0, n), y_at(0.0, min, max))];
+    for (i, v) in drawdown.iter().enumerate() {
+        points.push(format!("{:.1},{:.1}", x_at(i, n), y_at(*v, min, max)));
+    }
+    points.push(format!("{:.1},{:.1}", x_at(n.saturating_sub(1), n), y_at(0.0, min, max)));
+    let _ = write!(
+        svg,
+        "<polygon fill=\"#2e86c1\" fill-opacity=\"0.5\" stroke=\"#2471a3\" points=\"{}\"/>\n</svg>\n",
+        points.join(" ")
+    );
+    svg
+}
+
+/// year x month grid, red for gains and green for losses
+fn svg_heatmap(heatmap: &BTreeMap<String, Vec<Option<f64>>>) -> String {
+    let cell_w = (WIDTH - 2.0 * PAD) / 12.0;
+    let cell_h = 24.0;
+    let height = PAD + cell_h * heatmap.len() as f64 + 10.0;
+    let scale = heatmap
+        .values()
+        .flat_map(|m| m.iter().filter_map(|v| *v))
+        .fold(1e-6, |a: f64, b| a.max(b.abs()));
+    let mut svg = format!(
+        "<svg width=\"{}\" height=\"{}\" viewBox=\"0 0 {} {}\">\n",
+        WIDTH, height, WIDTH, height
+    );
+    for m in 0..12 {
+        let _ = writeln!(
+            svg,
+            "<text x=\"{:.1}\" y=\"{}\" font-size=\"11\" text-anchor=\"middle\">{}</text>",
+            PAD + cell_w * (m as f64 + 0.5),
+            PAD - 8.0,
+            m + 1
+        );
+    }
+    for (row, (year, months)) in heatmap.iter().enumerate() {
+        let y = PAD + cell_h * row as f64;
+        let _ = writeln!(
+            svg,
+            "<text x=\"4\" y=\"{:.1}\" font-size=\"11\">{}</text>",
+            y + 16.0,
+            escape(year)
+        );
+        for (m, value) in months.iter().enumerate() {
+            let x = PAD + cell_w * m as f64;
+            let (fill, text) = match value {
+                Some(v) => {
+                    let alpha = (v.abs() / scale).min(1.0) * 0.8 + 0.1;
+                    let color = if *v >= 0.0 { "192,57,43" } else { "34,153,84" };
+                    (format!("rgba({},{:.2})", color, alpha), format!("{:.2}%", v * 100.0))
+                }
+                None => ("#f4f6f6".to_string(), "".to_string()),
+            };
+            let _ = write!(
+                svg,
+                "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{}\" fill=\"{}\" stroke=\"#fff\"/>\n\
+                 <text x=\"{:.1}\" y=\"{:.1}\" font-size=\"10\" text-anchor=\"middle\">{}</text>\n",
+                x,
+                y,
+                cell_w,
+                cell_h,
+                fill,
+                x + cell_w / 2.0,
+                y + 16.0,
+                text
+            );
+        }
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+fn svg_histogram(values: &[f64], bins: usize) -> String {
+    let (min, max) = bounds(values);
+    let width = (max - min) / bins as f64;
+    let mut counts = vec![0usize; bins];
+    for v in values.iter() {
+        let i = (((v - min) / width) as usize).min(bins - 1);
+        counts[i] += 1;
+    }
+    let top = counts.iter().cloned().max().unwrap_or(0).max(1) as f64;
+    let mut svg = svg_open();
+    svg_axis(
+        &mut svg,
+        0.0,
+        top,
+        &[format!("{:.2}", min), format!("{:.2}", max)],
+    );
+    let bar_w = (WIDTH - 2.0 * PAD) / bins as f64;
+    for (i, c) in counts.iter().enumerate() {
+        let center = min + width * (i as f64 + 0.5);
+        let color = if center >= 0.0 { "#c0392b" } else { "#229954" };
+        let y = y_at(*c as f64, 0.0, top);
+        let _ = writeln!(
+            svg,
+            "<rect x=\"{:.1}\" y=\"{:.1}\" width=\"{:.1}\" height=\"{:.1}\" fill=\"{}\"/>",
+            PAD + bar_w * i as f64 + 1.0,
+            y,
+            bar_w - 2.0,
+            HEIGHT - PAD - y,
+            color
+        );
+    }
+    svg.push_str("</svg>\n");
+    svg
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report() {
+        let mut acc = QA_Account::new("RustT01B2_RBL8", "test", "admin", 1000000.0, false, "backtest");
+        let code = "rb2005";
+        acc.buy_open(code, 10.0, "2020-03-31 09:30:00", 3500.0);
+        acc.on_price_change(code.to_string(), 3520.0, "2020-03-31 15:00:00".to_string());
+        acc.settle();
+        acc.sell_close(code, 10.0, "2020-04-01 10:00:00", 3480.0);
+        acc.buy_open("000001", 1000.0, "2020-04-01 10:00:00", 12.0);
+        acc.settle();
+        acc.sell("000001", 1000.0, "2020-04-02 10:00:00", 12.5);
+        acc.settle();
+
+        let report = QAReport::from_account(&mut acc);
+        assert_eq!(report.curve.points.len(), 3);
+        assert_eq!(report.pairs.len(), 2);
+        assert_eq!(report.positions[code].len(), 3);
+        assert_eq!(report.positions[code][0], ("2020-03-31".to_string(), 10.0));
+        assert_eq!(report.positions[code][1].1, 0.0);
+        assert_eq!(report.positions["000001"][0], ("2020-04-01".to_string(), 1000.0));
+
+        let html = report.to_html();
+        assert_eq!(html.matches("<svg").count(), 5);
+        assert!(!html.contains("http"));
+        assert!(html.contains("max_drawdown"));
+        assert!(html.contains("RustT01B2_RBL8"));
+
+        let path = std::env::temp_dir().join("qareport.html");
+        report.to_file(path.to_str().unwrap()).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), html);
+    }
+}
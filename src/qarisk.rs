@@ -0,0 +1,781 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use serde::{Deserialize, Serialize};
+
+use crate::qaaccount::{QAAccountSlice, QA_Account};
+use crate::qafetch::BAR;
+use crate::trade_date::QATradeDate;
+
+/// one settled trading day of an account
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QAEquityPoint {
+    /// trading day, like "2020-01-20"
+    pub date: String,
+    pub balance: f64,
+    /// deposit - withdraw of the day, not counted as return
+    pub net_flow: f64,
+    pub daily_return: f64,
+    /// compounded net value, starts from 1.0
+    pub nav: f64,
+}
+
+/// daily equity curve built from dailyassets
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QAEquityCurve {
+    pub init_balance: f64,
+    pub points: Vec<QAEquityPoint>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QADrawdown {
+    /// negative number, -0.1 means 10% drawdown
+    pub max_drawdown: f64,
+    pub start: String,
+    pub trough: String,
+    /// empty if the curve has not recovered yet
+    pub recovery: String,
+    /// trading days from start to recovery (or to the last day)
+    pub duration: usize,
+}
+
+/// return based risk metrics, QA_Risk in python QUANTAXIS
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QARiskReport {
+    pub start: String,
+    pub end: String,
+    pub trading_days: usize,
+    pub days_per_year: f64,
+    pub total_return: f64,
+    pub annualized_return: f64,
+    pub volatility: f64,
+    pub sharpe: f64,
+    pub sortino: f64,
+    pub calmar: f64,
+    pub drawdown: QADrawdown,
+}
+
+/// benchmark close prices (an index or a continuous contract) keyed by trading day
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QABenchmark {
+    pub code: String,
+    pub prices: BTreeMap<String, f64>,
+}
+
+/// strategy vs benchmark metrics, alpha/tracking error/information ratio are annualized
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QABenchmarkReport {
+    pub benchmark: String,
+    pub aligned_days: usize,
+    pub benchmark_return: f64,
+    pub benchmark_annualized_return: f64,
+    pub alpha: f64,
+    pub beta: f64,
+    pub correlation: f64,
+    pub tracking_error: f64,
+    pub information_ratio: f64,
+    pub up_capture: f64,
+    pub down_capture: f64,
+    /// (trading day, strategy nav / benchmark nav - 1)
+    pub excess_curve: Vec<(String, f64)>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum QAFrequency {
+    Daily,
+    Weekly,
+    Monthly,
+    Yearly,
+}
+
+/// compounded return of one calendar period
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QAPeriodReturn {
+    /// "2020-04-01" / "2020-W14" / "2020-04" / "2020"
+    pub period: String,
+    /// first and last trading day of the period
+    pub start: String,
+    pub end: String,
+    pub days: usize,
+    pub period_return: f64,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QAPeriodSummary {
+    pub count: usize,
+    pub positive_ratio: f64,
+    pub mean: f64,
+    pub best: QAPeriodReturn,
+    pub worst: QAPeriodReturn,
+}
+
+/// 日/周/月/年收益表
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QAReturnTable {
+    pub daily: Vec<QAPeriodReturn>,
+    pub weekly: Vec<QAPeriodReturn>,
+    pub monthly: Vec<QAPeriodReturn>,
+    pub yearly: Vec<QAPeriodReturn>,
+    /// year -> return of Jan..Dec, None if the month has no data
+    pub heatmap: BTreeMap<String, Vec<Option<f64>>>,
+    /// keyed by daily/weekly/monthly/yearly
+    pub summary: BTreeMap<String, QAPeriodSummary>,
+    /// share of the months with a positive return
+    pub positive_months: f64,
+}
+
+impl QAPeriodSummary {
+    fn from_periods(periods: &[QAPeriodReturn]) -> Self {
+        let mut summary = QAPeriodSummary::default();
+        if periods.is_empty() {
+            return summary;
+        }
+        let returns: Vec<f64> = periods.iter().map(|p| p.period_return).collect();
+        summary.count = periods.len();
+        summary.positive_ratio =
+            returns.iter().filter(|r| **r > 0.0).count() as f64 / periods.len() as f64;
+        summary.mean = mean(&returns);
+        summary.best = periods[0].clone();
+        summary.worst = periods[0].clone();
+        for p in periods.iter() {
+            if p.period_return > summary.best.period_return {
+                summary.best = p.clone();
+            }
+            if p.period_return < summary.worst.period_return {
+                summary.worst = p.clone();
+            }
+        }
+        summary
+    }
+}
+
+impl QAReturnTable {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    /// long format: frequency,period,start,end,days,period_return
+    pub fn to_csv(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let mut wtr = csv::Writer::from_path(path)?;
+        wtr.write_record(["frequency", "period", "start", "end", "days", "period_return"])?;
+        for (freq, periods) in [
+            ("daily", &self.daily),
+            ("weekly", &self.weekly),
+            ("monthly", &self.monthly),
+            ("yearly", &self.yearly),
+        ]
+        .iter()
+        {
+            for p in periods.iter() {
+                wtr.write_record(&[
+                    freq.to_string(),
+                    p.period.clone(),
+                    p.start.clone(),
+                    p.end.clone(),
+                    p.days.to_string(),
+                    p.period_return.to_string(),
+                ])?;
+            }
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+
+    /// year,1..12,year_return, empty cell for the months without data
+    pub fn heatmap_to_csv(&self, path: &str) -> Result<(), Box<dyn Error>> {
+        let mut wtr = csv::Writer::from_path(path)?;
+        let mut header = vec!["year".to_string()];
+        header.extend((1..=12).map(|m| m.to_string()));
+        header.push("year_return".to_string());
+        wtr.write_record(&header)?;
+        for (year, months) in self.heatmap.iter() {
+            let mut row = vec![year.clone()];
+            row.extend(months.iter().map(|m| m.map(|r| r.to_string()).unwrap_or_default()));
+            row.push(
+                self.yearly
+                    .iter()
+                    .find(|p| &p.period == year)
+                    .map(|p| p.period_return.to_string())
+                    .unwrap_or_default(),
+            );
+            wtr.write_record(&row)?;
+        }
+        wtr.flush()?;
+        Ok(())
+    }
+}
+
+impl QABenchmark {
+    /// datetime can be a date or a datetime, the last price of each trading day is kept
+    pub fn new(code: &str, datetimes: &[String], prices: &[f64]) -> Self {
+        let mut td = QATradeDate::new();
+        let mut map = BTreeMap::new();
+        let mut pairs: Vec<(&String, &f64)> = datetimes.iter().zip(prices.iter()).collect();
+        pairs.sort_by(|a, b| a.0.cmp(b.0));
+        for (datetime, price) in pairs {
+            map.insert(td.get_trade_day(datetime.clone()), *price);
+        }
+        QABenchmark {
+            code: code.to_string(),
+            prices: map,
+        }
+    }
+
+    pub fn from_bars(bars: &[BAR]) -> Self {
+        let code = bars.first().map(|b| b.code.clone()).unwrap_or_default();
+        let datetimes: Vec<String> = bars.iter().map(|b| b.datetime.clone()).collect();
+        let prices: Vec<f64> = bars.iter().map(|b| b.close).collect();
+        Self::new(&code, &datetimes, &prices)
+    }
+
+    /// daily return of the benchmark, the first day has no return
+    pub fn returns(&self) -> BTreeMap<String, f64> {
+        let mut res = BTreeMap::new();
+        let mut last: Option<f64> = None;
+        for (date, price) in self.prices.iter() {
+            if let Some(l) = last {
+                if l > 0.0 {
+                    res.insert(date.clone(), price / l - 1.0);
+                }
+            }
+            last = Some(*price);
+        }
+        res
+    }
+}
+
+/// sample covariance
+pub fn cov(a: &[f64], b: &[f64]) -> f64 {
+    if a.len() < 2 || a.len() != b.len() {
+        return 0.0;
+    }
+    let (ma, mb) = (mean(a), mean(b));
+    a.iter()
+        .zip(b.iter())
+        .map(|(x, y)| (x - ma) * (y - mb))
+        .sum::<f64>()
+        / (a.len() as f64 - 1.0)
+}
+
+/// average number of trading days per calendar year between start and end
+pub fn trading_days_per_year(start: &str, end: &str) -> f64 {
+    let mut td = QATradeDate::new();
+    let y0: i32 = start[0..4].parse().unwrap();
+    let y1: i32 = end[0..4].parse().unwrap();
+    let mut total = 0;
+    let mut years = 0;
+    for y in y0..=y1 {
+        let n = td
+            .get_trade_range(&format!("{}-01-01", y), &format!("{}-12-31", y))
+            .len();
+        // skip the years the calendar does not cover completely
+        if n > 200 {
+            total += n;
+            years += 1;
+        }
+    }
+    if years > 0 {
+        total as f64 / years as f64
+    } else {
+        250.0
+    }
+}
+
+pub fn mean(data: &[f64]) -> f64 {
+    if data.is_empty() {
+        0.0
+    } else {
+        data.iter().sum::<f64>() / data.len() as f64
+    }
+}
+
+/// sample standard deviation
+pub fn std(data: &[f64]) -> f64 {
+    if data.len() < 2 {
+        return 0.0;
+    }
+    let m = mean(data);
+    (data.iter().map(|x| (x - m).powi(2)).sum::<f64>() / (data.len() as f64 - 1.0)).sqrt()
+}
+
+impl QAEquityCurve {
+    /// 由结算切片构建, 同一交易日只保留最后一个切片
+    pub fn from_slices(slices: &[QAAccountSlice]) -> Self {
+        let mut td = QATradeDate::new();
+        let mut sorted: Vec<&QAAccountSlice> = slices.iter().collect();
+        sorted.sort_by(|a, b| a.datetime.cmp(&b.datetime));
+        let init_balance = sorted
+            .first()
+            .map(|s| s.accounts.pre_balance)
+            .unwrap_or(0.0);
+
+        let mut days: BTreeMap<String, (f64, f64)> = BTreeMap::new();
+        for slice in sorted {
+            let date = td.get_trade_day(slice.datetime.clone());
+            let flow = slice.accounts.deposit - slice.accounts.withdraw;
+            let entry = days.entry(date).or_insert((0.0, 0.0));
+            entry.0 = slice.accounts.balance;
+            entry.1 += flow;
+        }
+        let dates: Vec<String> = days.keys().cloned().collect();
+        let balances: Vec<f64> = days.values().map(|v| v.0).collect();
+        let flows: Vec<f64> = days.values().map(|v| v.1).collect();
+        Self::from_balances(init_balance, &dates, &balances, &flows)
+    }
+
+    pub fn from_account(acc: &QA_Account) -> Self {
+        let slices: Vec<QAAccountSlice> = acc.dailyassets.values().cloned().collect();
+        Self::from_slices(&slices)
+    }
+
+    /// dates/balances/flows must have the same length and be sorted by date
+    pub fn from_balances(
+        init_balance: f64,
+        dates: &[String],
+        balances: &[f64],
+        flows: &[f64],
+    ) -> Self {
+        let mut points = vec![];
+        let mut last = init_balance;
+        let mut nav = 1.0;
+        for i in 0..dates.len() {
+            let flow = flows.get(i).cloned().unwrap_or(0.0);
+            let r = if last > 0.0 {
+                (balances[i] - flow - last) / last
+            } else {
+                0.0
+            };
+            nav *= 1.0 + r;
+            points.push(QAEquityPoint {
+                date: dates[i].clone(),
+                balance: balances[i],
+                net_flow: flow,
+                daily_return: r,
+                nav,
+            });
+            last = balances[i];
+        }
+        QAEquityCurve {
+            init_balance,
+            points,
+        }
+    }
+
+    pub fn dates(&self) -> Vec<String> {
+        self.points.iter().map(|p| p.date.clone()).collect()
+    }
+
+    pub fn returns(&self) -> Vec<f64> {
+        self.points.iter().map(|p| p.daily_return).collect()
+    }
+
+    pub fn nav(&self) -> Vec<f64> {
+        self.points.iter().map(|p| p.nav).collect()
+    }
+
+    pub fn days_per_year(&self) -> f64 {
+        match (self.points.first(), self.points.last()) {
+            (Some(first), Some(last)) => trading_days_per_year(&first.date, &last.date),
+            _ => 250.0,
+        }
+    }
+
+    pub fn total_return(&self) -> f64 {
+        self.points.last().map(|p| p.nav - 1.0).unwrap_or(0.0)
+    }
+
+    pub fn annualized_return(&self) -> f64 {
+        let n = self.points.len();
+        if n == 0 {
+            return 0.0;
+        }
+        (1.0 + self.total_return()).powf(self.days_per_year() / n as f64) - 1.0
+    }
+
+    pub fn volatility(&self) -> f64 {
+        std(&self.returns()) * self.days_per_year().sqrt()
+    }
+
+    /// risk_free is the annual risk free rate
+    pub fn sharpe(&self, risk_free: f64) -> f64 {
+        let n = self.days_per_year();
+        let excess: Vec<f64> = self.returns().iter().map(|r| r - risk_free / n).collect();
+        let sd = std(&excess);
+        if sd > 0.0 {
+            mean(&excess) / sd * n.sqrt()
+        } else {
+            0.0
+        }
+    }
+
+    pub fn sortino(&self, risk_free: f64) -> f64 {
+        let n = self.days_per_year();
+        let excess: Vec<f64> = self.returns().iter().map(|r| r - risk_free / n).collect();
+        if excess.is_empty() {
+            return 0.0;
+        }
+        let downside = (excess.iter().map(|r| r.min(0.0).powi(2)).sum::<f64>()
+            / excess.len() as f64)
+            .sqrt();
+        if downside > 0.0 {
+            mean(&excess) / downside * n.sqrt()
+        } else {
+            0.0
+        }
+    }
+
+    /// drawdown of every day, 0.0 at a new high
+    pub fn drawdown_series(&self) -> Vec<f64> {
+        let mut peak = 1.0f64;
+        self.points
+            .iter()
+            .map(|p| {
+                peak = peak.max(p.nav);
+                p.nav / peak - 1.0
+            })
+            .collect()
+    }
+
+    pub fn max_drawdown(&self) -> QADrawdown {
+        let mut res = QADrawdown::default();
+        if self.points.is_empty() {
+            return res;
+        }
+        let mut peak = 1.0f64;
+        // the peak before the first point is the initial balance
+        let mut peak_i: Option<usize> = None;
+        let mut start_i: Option<usize> = None;
+        let mut trough_i = 0;
+        for (i, p) in self.points.iter().enumerate() {
+            if p.nav >= peak {
+                peak = p.nav;
+                peak_i = Some(i);
+            }
+            let dd = p.nav / peak - 1.0;
+            if dd < res.max_drawdown {
+                res.max_drawdown = dd;
+                start_i = peak_i;
+                trough_i = i;
+            }
+        }
+        if res.max_drawdown == 0.0 {
+            return res;
+        }
+        let start_nav = start_i.map(|i| self.points[i].nav).unwrap_or(1.0);
+        res.start = match start_i {
+            Some(i) => self.points[i].date.clone(),
+            None => self.points[0].date.clone(),
+        };
+        res.trough = self.points[trough_i].date.clone();
+        let begin = start_i.unwrap_or(0);
+        let recovery = (trough_i..self.points.len()).find(|i| self.points[*i].nav >= start_nav);
+        match recovery {
+            Some(i) => {
+                res.recovery = self.points[i].date.clone();
+                res.duration = i - begin;
+            }
+            None => res.duration = self.points.len() - 1 - begin,
+        }
+        res
+    }
+
+    pub fn calmar(&self) -> f64 {
+        let dd = self.max_drawdown().max_drawdown;
+        if dd < 0.0 {
+            self.annualized_return() / dd.abs()
+        } else {
+            0.0
+        }
+    }
+
+    /// strategy and benchmark returns on the trading days both of them have
+    pub fn align(&self, benchmark: &QABenchmark) -> (Vec<String>, Vec<f64>, Vec<f64>) {
+        let bench = benchmark.returns();
+        let mut dates = vec![];
+        let mut rs = vec![];
+        let mut rb = vec![];
+        for p in self.points.iter() {
+            if let Some(r) = bench.get(&p.date) {
+                dates.push(p.date.clone());
+                rs.push(p.daily_return);
+                rb.push(*r);
+            }
+        }
+        (dates, rs, rb)
+    }
+
+    pub fn benchmark_report(&self, benchmark: &QABenchmark, risk_free: f64) -> QABenchmarkReport {
+        let (dates, rs, rb) = self.align(benchmark);
+        let n = self.days_per_year();
+        let mut report = QABenchmarkReport {
+            benchmark: benchmark.code.clone(),
+            aligned_days: dates.len(),
+            ..QABenchmarkReport::default()
+        };
+        if dates.len() < 2 {
+            return report;
+        }
+        let var_b = std(&rb).powi(2);
+        report.beta = if var_b > 0.0 { cov(&rs, &rb) / var_b } else { 0.0 };
+        let (sd_s, sd_b) = (std(&rs), std(&rb));
+        report.correlation = if sd_s > 0.0 && sd_b > 0.0 {
+            cov(&rs, &rb) / (sd_s * sd_b)
+        } else {
+            0.0
+        };
+        // jensen alpha on daily returns, annualized
+        let rf = risk_free / n;
+        report.alpha = (mean(&rs) - rf - report.beta * (mean(&rb) - rf)) * n;
+
+        let excess: Vec<f64> = rs.iter().zip(rb.iter()).map(|(s, b)| s - b).collect();
+        report.tracking_error = std(&excess) * n.sqrt();
+        report.information_ratio = if report.tracking_error > 0.0 {
+            mean(&excess) * n / report.tracking_error
+        } else {
+            0.0
+        };
+
+        let capture = |up: bool| {
+            let (s, b): (Vec<f64>, Vec<f64>) = rs
+                .iter()
+                .zip(rb.iter())
+                .filter(|(_, b)| if up { **b > 0.0 } else { **b < 0.0 })
+                .map(|(s, b)| (*s, *b))
+                .unzip();
+            let mb = mean(&b);
+            if b.is_empty() || mb == 0.0 {
+                0.0
+            } else {
+                mean(&s) / mb
+            }
+        };
+        report.up_capture = capture(true);
+        report.down_capture = capture(false);
+
+        let mut nav_s = 1.0;
+        let mut nav_b = 1.0;
+        for i in 0..dates.len() {
+            nav_s *= 1.0 + rs[i];
+            nav_b *= 1.0 + rb[i];
+            report.excess_curve.push((dates[i].clone(), nav_s / nav_b - 1.0));
+        }
+        report.benchmark_return = nav_b - 1.0;
+        report.benchmark_annualized_return = nav_b.powf(n / dates.len() as f64) - 1.0;
+        report
+    }
+
+    /// 按交易日所属的周/月/年复合日收益
+    pub fn period_returns(&self, freq: QAFrequency) -> Vec<QAPeriodReturn> {
+        let mut td = QATradeDate::new();
+        let mut res: Vec<QAPeriodReturn> = vec![];
+        for p in self.points.iter() {
+            let period = match freq {
+                QAFrequency::Daily => td.get_trade_day(p.date.clone()),
+                QAFrequency::Weekly => td.get_trade_week(&p.date),
+                QAFrequency::Monthly => td.get_trade_month(&p.date),
+                QAFrequency::Yearly => td.get_trade_month(&p.date)[0..4].to_string(),
+            };
+            match res.last_mut() {
+                Some(last) if last.period == period => {
+                    last.period_return = (1.0 + last.period_return) * (1.0 + p.daily_return) - 1.0;
+                    last.end = p.date.clone();
+                    last.days += 1;
+                }
+                _ => res.push(QAPeriodReturn {
+                    period,
+                    start: p.date.clone(),
+                    end: p.date.clone(),
+                    days: 1,
+                    period_return: p.daily_return,
+                }),
+            }
+        }
+        res
+    }
+
+    pub fn return_table(&self) -> QAReturnTable {
+        let mut table = QAReturnTable {
+            daily: self.period_returns(QAFrequency::Daily),
+            weekly: self.period_returns(QAFrequency::Weekly),
+            monthly: self.period_returns(QAFrequency::Monthly),
+            yearly: self.period_returns(QAFrequency::Yearly),
+            ..QAReturnTable::default()
+        };
+        for m in table.monthly.iter() {
+            let month: usize = m.period[5..7].parse().unwrap();
+            table
+                .heatmap
+                .entry(m.period[0..4].to_string())
+                .or_insert_with(|| vec![None; 12])[month - 1] = Some(m.period_return);
+        }
+        for (freq, periods) in [
+            ("daily", &table.daily),
+            ("weekly", &table.weekly),
+            ("monthly", &table.monthly),
+            ("yearly", &table.yearly),
+        ]
+        .iter()
+        {
+            table
+                .summary
+                .insert(freq.to_string(), QAPeriodSummary::from_periods(periods));
+        }
+        table.positive_months = table.summary["monthly"].positive_ratio;
+        table
+    }
+
+    pub fn risk_report(&self, risk_free: f64) -> QARiskReport {
+        QARiskReport {
+            start: self.points.first().map(|p| p.date.clone()).unwrap_or_default(),
+            end: self.points.last().map(|p| p.date.clone()).unwrap_or_default(),
+            trading_days: self.points.len(),
+            days_per_year: self.days_per_year(),
+            total_return: self.total_return(),
+            annualized_return: self.annualized_return(),
+            volatility: self.volatility(),
+            sharpe: self.sharpe(risk_free),
+            sortino: self.sortino(risk_free),
+            calmar: self.calmar(),
+            drawdown: self.max_drawdown(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn curve(balances: &[f64]) -> QAEquityCurve {
+        let mut td = QATradeDate::new();
+        let dates: Vec<String> = (0..balances.len())
+            .map(|i| td.get_next_n_day("2020-03-31", i as i32 + 1))
+            .collect();
+        QAEquityCurve::from_balances(100.0, &dates, balances, &vec![0.0; balances.len()])
+    }
+
+    #[test]
+    fn test_return_table() {
+        let dates: Vec<String> = ["2019-12-30", "2019-12-31", "2020-01-02", "2020-01-03", "2020-02-03"]
+            .iter()
+            .map(|d| d.to_string())
+            .collect();
+        let c = QAEquityCurve::from_balances(100.0, &dates, &[110.0, 99.0, 99.0, 108.9, 98.01], &[0.0; 5]);
+        let table = c.return_table();
+        println!("{}", table.to_json());
+        assert_eq!(table.daily.len(), 5);
+        // 2019-12-30 ~ 2020-01-03 is one ISO week
+        assert_eq!(table.weekly.len(), 2);
+        assert_eq!(table.weekly[0].period, "2020-W01");
+        assert!((table.weekly[0].period_return - 0.089).abs() < 1e-9);
+        assert_eq!(table.monthly.len(), 3);
+        assert!((table.monthly[0].period_return + 0.01).abs() < 1e-9);
+        assert!((table.monthly[1].period_return - 0.1).abs() < 1e-9);
+        assert_eq!(table.yearly.len(), 2);
+        assert_eq!(table.heatmap["2020"][0], Some(table.monthly[1].period_return));
+        assert_eq!(table.heatmap["2020"][2], None);
+        assert!((table.positive_months - 1.0 / 3.0).abs() < 1e-9);
+        assert_eq!(table.summary["monthly"].best.period, "2020-01");
+        assert_eq!(table.summary["monthly"].worst.period, "2020-02");
+
+        let path = std::env::temp_dir().join("qarisk_heatmap.csv");
+        table.heatmap_to_csv(path.to_str().unwrap()).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(text.lines().count(), 3);
+        let path = std::env::temp_dir().join("qarisk_returns.csv");
+        table.to_csv(path.to_str().unwrap()).unwrap();
+        let text = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(text.lines().count(), 1 + 5 + 2 + 3 + 2);
+    }
+
+    #[test]
+    fn test_days_per_year() {
+        let n = trading_days_per_year("2019-05-01", "2020-03-01");
+        assert!(n > 240.0 && n < 250.0);
+    }
+
+    #[test]
+    fn test_max_drawdown() {
+        let c = curve(&[110.0, 99.0, 88.0, 105.0, 111.0, 100.0]);
+        let dd = c.max_drawdown();
+        println!("{:#?}", dd);
+        assert!((dd.max_drawdown + 0.2).abs() < 1e-9);
+        assert_eq!(dd.start, "2020-04-01");
+        assert_eq!(dd.trough, "2020-04-03");
+        assert_eq!(dd.recovery, "2020-04-08");
+        assert_eq!(dd.duration, 4);
+
+        let c = curve(&[90.0, 95.0]);
+        let dd = c.max_drawdown();
+        assert_eq!(dd.start, "2020-04-01");
+        assert_eq!(dd.recovery, "");
+        assert_eq!(dd.duration, 1);
+    }
+
+    #[test]
+    fn test_flows_are_not_returns() {
+        let dates = vec!["2020-04-01".to_string(), "2020-04-02".to_string()];
+        let c = QAEquityCurve::from_balances(100.0, &dates, &[110.0, 210.0], &[0.0, 100.0]);
+        assert!((c.points[1].daily_return).abs() < 1e-9);
+        assert!((c.total_return() - 0.1).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_risk_report() {
+        let c = curve(&[101.0, 100.0, 102.0, 103.0, 101.0, 104.0]);
+        let report = c.risk_report(0.0);
+        println!("{:#?}", report);
+        assert!((report.total_return - 0.04).abs() < 1e-9);
+        assert!(report.sharpe > 0.0);
+        assert!(report.sortino > report.sharpe);
+        assert!(report.calmar > 0.0);
+        assert_eq!(report.trading_days, 6);
+    }
+
+    #[test]
+    fn test_benchmark_report() {
+        // strategy = 2x benchmark, so beta 2, correlation 1, alpha 0
+        let bench_prices = [100.0, 101.0, 99.0, 102.0, 101.0, 103.0, 104.0];
+        let mut td = QATradeDate::new();
+        let bench_dates: Vec<String> = (0..bench_prices.len())
+            .map(|i| format!("{} 15:00:00", td.get_next_n_day("2020-03-30", i as i32 + 1)))
+            .collect();
+        let benchmark = QABenchmark::new("000300", &bench_dates, &bench_prices);
+        let mut balances = vec![];
+        let mut b = 100.0;
+        for i in 1..bench_prices.len() {
+            b *= 1.0 + 2.0 * (bench_prices[i] / bench_prices[i - 1] - 1.0);
+            balances.push(b);
+        }
+        let c = curve(&balances);
+        let report = c.benchmark_report(&benchmark, 0.0);
+        println!("{:#?}", report);
+        assert_eq!(report.aligned_days, 6);
+        assert!((report.beta - 2.0).abs() < 1e-9);
+        assert!((report.correlation - 1.0).abs() < 1e-9);
+        assert!(report.alpha.abs() < 1e-9);
+        assert!((report.up_capture - 2.0).abs() < 1e-9);
+        assert!((report.down_capture - 2.0).abs() < 1e-9);
+        assert!(report.tracking_error > 0.0);
+        assert!((report.benchmark_return - 0.04).abs() < 1e-9);
+        assert_eq!(report.excess_curve.len(), 6);
+    }
+
+    #[test]
+    fn test_from_account() {
+        let code = "RB2005";
+        let mut acc = QA_Account::new("test", "test", "admin", 100000.0, false, "backtest");
+        acc.buy_open(code, 1.0, "2020-01-20 09:30:00", 3500.0);
+        acc.on_price_change(code.to_string(), 3600.0, "2020-01-20 15:00:00".to_string());
+        acc.settle();
+        acc.deposit(50000.0);
+        acc.on_price_change(code.to_string(), 3550.0, "2020-01-21 15:00:00".to_string());
+        acc.settle();
+        let c = QAEquityCurve::from_account(&acc);
+        println!("{:#?}", c);
+        assert_eq!(c.dates(), vec!["2020-01-20", "2020-01-21"]);
+        assert!((c.points[0].daily_return - 0.01).abs() < 1e-9);
+        // settle books the commission into the static balance
+        let commission = acc.history[0].commission;
+        assert!((c.points[1].daily_return + (500.0 + commission) / 101000.0).abs() < 1e-9);
+    }
+}
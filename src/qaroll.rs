@@ -0,0 +1,579 @@
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use serde::{Deserialize, Serialize};
+
+use crate::market_preset::MarketPreset;
+use crate::qaaccount::QA_Account;
+use crate::qaexecution::{QAExecutionEngine, QAExecutionMode, QASettleClock, QAStrategy};
+use crate::qafeed::{QABarFeed, QABarSlice};
+use crate::qafetch::BAR;
+use crate::trade_date::QATradeDate;
+
+/// product of a contract or a continuous code, rb2005 / RBL8 -> RB
+pub fn product(code: &str) -> String {
+    let code = if code.ends_with("L8") || code.ends_with("L9") {
+        &code[..code.len() - 2]
+    } else {
+        code
+    };
+    code.chars()
+        .take_while(|c| c.is_ascii_alphabetic())
+        .collect::<String>()
+        .to_uppercase()
+}
+
+/// delivery month of a contract as yyyymm, rb2005 -> 202005
+///
+/// CZCE codes carry one digit of the year (SR001), the decade is the one that puts the
+/// delivery on or after the year of `date`, SR001 traded in 2019 -> 202001
+pub fn delivery_month(code: &str, date: &str) -> Option<u32> {
+    let digits: String = code.chars().skip_while(|c| c.is_ascii_alphabetic()).collect();
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    let number: u32 = digits.parse().ok()?;
+    let month = number % 100;
+    let year = match digits.len() {
+        4 => 2000 + number / 100,
+        3 => {
+            let current: u32 = date.get(0..4)?.parse().ok()?;
+            let year = current / 10 * 10 + number / 100;
+            if year < current {
+                year + 10
+            } else {
+                year
+            }
+        }
+        _ => return None,
+    };
+    if month == 0 || month > 12 {
+        return None;
+    }
+    Some(year * 100 + month)
+}
+
+/// one row of the main contract csv, date,code
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QAMainContractRecord {
+    pub date: String,
+    pub code: String,
+}
+
+/// 主力合约映射, 交易日 -> 真实合约
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QAMainContractMap {
+    /// RBL8, RBL9 ...
+    pub continuous: String,
+    pub days: BTreeMap<String, String>,
+}
+
+impl QAMainContractMap {
+    pub fn new(continuous: &str) -> Self {
+        QAMainContractMap {
+            continuous: continuous.to_string(),
+            days: BTreeMap::new(),
+        }
+    }
+
+    /// (datetime, code, weight) of the contracts of the product, weight is the volume or the
+    /// open interest. The main contract of a day is the one with the largest weight on the
+    /// previous trading day, so the map holds no future data, the first day uses its own weight.
+    /// The main contract never rolls back to an earlier delivery month.
+    pub fn from_weights(continuous: &str, records: &[(String, String, f64)]) -> Self {
+        let target = product(continuous);
+        let mut td = QATradeDate::new();
+        // trading day -> code -> summed weight
+        let mut weights: BTreeMap<String, BTreeMap<String, f64>> = BTreeMap::new();
+        for (datetime, code, weight) in records {
+            if product(code) != target {
+                continue;
+            }
+            let day = td.get_trade_day(datetime.clone());
+            *weights.entry(day).or_default().entry(code.clone()).or_insert(0.0) += weight;
+        }
+
+        let mut map = QAMainContractMap::new(continuous);
+        let mut main: Option<String> = None;
+        let mut previous: Option<&BTreeMap<String, f64>> = None;
+        for (day, today) in weights.iter() {
+            let leader = previous
+                .unwrap_or(today)
+                .iter()
+                .fold(None, |best: Option<(&String, f64)>, (code, w)| match best {
+                    Some((_, bw)) if bw >= *w => best,
+                    _ => Some((code, *w)),
+                })
+                .map(|(code, _)| code.clone());
+            if let Some(leader) = leader {
+                let later = |m: &String| match (delivery_month(&leader, day), delivery_month(m, day)) {
+                    (Some(a), Some(b)) => a > b,
+                    _ => leader > *m,
+                };
+                if main.as_ref().map_or(true, later) {
+                    main = Some(leader);
+                }
+            }
+            if let Some(m) = &main {
+                map.days.insert(day.clone(), m.clone());
+            }
+            previous = Some(today);
+        }
+        map
+    }
+
+    /// main contract by volume, the bars of all the contracts of the product
+    pub fn from_bars(continuous: &str, bars: &[BAR]) -> Self {
+        let records: Vec<(String, String, f64)> = bars
+            .iter()
+            .map(|b| (b.datetime.clone(), b.code.clone(), b.volume))
+            .collect();
+        QAMainContractMap::from_weights(continuous, &records)
+    }
+
+    /// csv with the columns date,code, rows of other products are skipped
+    pub fn from_csv(continuous: &str, path: &str) -> Result<Self, Box<dyn Error>> {
+        let target = product(continuous);
+        let mut map = QAMainContractMap::new(continuous);
+        let mut rdr = csv::Reader::from_path(path)?;
+        for result in rdr.deserialize() {
+            let record: QAMainContractRecord = result?;
+            if product(&record.code) == target {
+                map.days.insert(record.date, record.code);
+            }
+        }
+        Ok(map)
+    }
+
+    /// main contract of the trading day, the last known one if the day is not in the map
+    pub fn get(&self, date: &str) -> Option<&String> {
+        self.days.range(..=date.to_string()).next_back().map(|(_, code)| code)
+    }
+
+    /// (first day of the new contract, old contract, new contract)
+    pub fn switches(&self) -> Vec<(String, String, String)> {
+        let mut res = vec![];
+        let mut last: Option<&String> = None;
+        for (day, code) in self.days.iter() {
+            if let Some(prev) = last {
+                if prev != code {
+                    res.push((day.clone(), prev.clone(), code.clone()));
+                }
+            }
+            last = Some(code);
+        }
+        res
+    }
+}
+
+/// when the position is moved to the new main contract
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+pub enum QARollTiming {
+    /// at the open of the first day of the new contract
+    Open,
+    /// at the close of the last day of the old contract
+    Close,
+    /// at the open, n trading days after the switch
+    DaysAfter(usize),
+}
+
+/// one roll, the old contract is closed and the same volume opened on the new one
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QARollRecord {
+    pub datetime: String,
+    pub from: String,
+    pub to: String,
+    pub volume_long: f64,
+    pub volume_short: f64,
+    pub from_price: f64,
+    pub to_price: f64,
+    /// commission and tax of the legs
+    pub commission: f64,
+    /// slippage_ticks on every filled leg, in money
+    pub slippage: f64,
+    pub cost: f64,
+    /// false if a leg was rejected, the filled legs are reversed so the old position is
+    /// held again and the engine stays on the old contract
+    pub completed: bool,
+    /// the rejected leg, code volume towards
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QARollReport {
+    pub continuous: String,
+    pub rolls: Vec<QARollRecord>,
+    /// rolls with a rejected leg
+    pub failed: usize,
+    pub commission: f64,
+    pub slippage: f64,
+    pub cost: f64,
+}
+
+/// 主力合约换月回测, 策略交易真实合约, 切换日按 timing 将旧合约持仓平仓并在新合约开仓
+#[derive(Debug, Clone)]
+pub struct QARollEngine {
+    pub map: QAMainContractMap,
+    pub timing: QARollTiming,
+    /// ticks paid against the bar price on every leg of a roll
+    pub slippage_ticks: f64,
+    pub rolls: Vec<QARollRecord>,
+    /// fills the orders of the strategy, the rolls are sent to the account directly
+    pub execution: QAExecutionEngine,
+    preset: MarketPreset,
+}
+
+impl QARollEngine {
+    pub fn new(map: QAMainContractMap, timing: QARollTiming) -> Self {
+        QARollEngine {
+            map,
+            timing,
+            slippage_ticks: 0.0,
+            rolls: vec![],
+            execution: QAExecutionEngine::new(QAExecutionMode::SameBar),
+            preset: MarketPreset::new(),
+        }
+    }
+
+    pub fn with_slippage(mut self, ticks: f64) -> Self {
+        self.slippage_ticks = ticks;
+        self
+    }
+
+    pub fn with_execution(mut self, mode: QAExecutionMode) -> Self {
+        self.execution = QAExecutionEngine::new(mode);
+        self
+    }
+
+    fn roll_price(&self, slice: &QABarSlice, code: &str) -> Option<f64> {
+        match slice.get(code) {
+            Some(bar) if self.timing == QARollTiming::Close => Some(bar.close),
+            Some(bar) => Some(bar.open),
+            None => slice.price(code),
+        }
+    }
+
+    /// None if one of the contracts has no price yet, the roll is tried again on the next slice,
+    /// otherwise whether every leg was filled. At the first rejected leg the filled ones are
+    /// sent back in reverse order, a close is opened again and an open is closed
+    fn roll(&mut self, acc: &mut QA_Account, slice: &QABarSlice, from: &str, to: &str) -> Option<bool> {
+        let (volume_long, volume_short) = match acc.get_position(from) {
+            Some(pos) => (pos.volume_long(), pos.volume_short()),
+            None => (0.0, 0.0),
+        };
+        let mut record = QARollRecord {
+            datetime: slice.datetime.clone(),
+            from: from.to_string(),
+            to: to.to_string(),
+            volume_long,
+            volume_short,
+            completed: true,
+            ..QARollRecord::default()
+        };
+        if volume_long > 0.0 || volume_short > 0.0 {
+            let (from_price, to_price) = match (self.roll_price(slice, from), self.roll_price(slice, to)) {
+                (Some(f), Some(t)) => (f, t),
+                _ => return None,
+            };
+            let preset = self.preset.get(to);
+            let slip = self.slippage_ticks * preset.price_tick;
+            let fees = |acc: &QA_Account| acc.history.iter().map(|t| t.commission + t.tax).sum::<f64>();
+            let before = fees(acc);
+            let dt = slice.datetime.as_str();
+            // closes first, so the margin of the old contract is free for the new one
+            let legs = [
+                (from, volume_long, -3, from_price - slip),
+                (from, volume_short, 3, from_price + slip),
+                (to, volume_long, 2, to_price + slip),
+                (to, volume_short, -2, to_price - slip),
+            ];
+            let mut filled = vec![];
+            for (code, volume, towards, price) in legs.iter() {
+                if *volume == 0.0 {
+                    continue;
+                }
+                if acc.send_order(code, *volume, dt, *towards, *price, "").is_err() {
+                    record.completed = false;
+                    record.message = format!("{} {} {} rejected", code, volume, towards);
+                    break;
+                }
+                filled.push((*code, *volume, *towards));
+            }
+            let mut traded: f64 = filled.iter().map(|(_, volume, _)| volume).sum();
+            if !record.completed {
+                for (code, volume, towards) in filled.iter().rev() {
+                    // -3 -> 2, 3 -> -2, 2 -> -3, -2 -> 3, paying the slippage again
+                    let back = match towards {
+                        -3 => 2,
+                        3 => -2,
+                        2 => -3,
+                        _ => 3,
+                    };
+                    let base = if *code == from { from_price } else { to_price };
+                    let price = if back > 0 { base + slip } else { base - slip };
+                    if acc.send_order(code, *volume, dt, back, price, "").is_err() {
+                        record.message += &format!(", {} {} {} not reversed", code, volume, back);
+                    } else {
+                        traded += volume;
+                    }
+                }
+            }
+            record.from_price = from_price;
+            record.to_price = to_price;
+            record.commission = fees(acc) - before;
+            record.slippage = traded * slip * preset.unit_table as f64;
+            record.cost = record.commission + record.slippage;
+        }
+        let completed = record.completed;
+        self.rolls.push(record);
+        Some(completed)
+    }
+
+    /// the feed holds the bars of the contracts of the map, the strategy gets the bar of the
+    /// contract being traded and orders on bar.code, settled at every new trading day
+    pub fn run<S: QAStrategy>(
+        &mut self,
+        strategy: &mut S,
+        feed: QABarFeed,
+        account_cookie: &str,
+        init_cash: f64,
+    ) -> QA_Account {
+        let mut td = QATradeDate::new();
+        let mut acc = QA_Account::new(account_cookie, "roll", "admin", init_cash, false, "backtest");
+        let slices: Vec<QABarSlice> = feed.collect();
+        let days: Vec<String> = slices.iter().map(|s| td.get_trade_day(s.datetime.clone())).collect();
+
+        // trading days in order, and the switches between two of them
+        let mut trading_days: Vec<String> = days.clone();
+        trading_days.dedup();
+        let mut switch_at: BTreeMap<usize, String> = BTreeMap::new();
+        for (k, day) in trading_days.iter().enumerate() {
+            let main = match self.map.get(day) {
+                Some(main) => main.clone(),
+                None => continue,
+            };
+            if k == 0 || self.map.get(&trading_days[k - 1]) == Some(&main) {
+                continue;
+            }
+            let roll_day = match self.timing {
+                QARollTiming::Open => Some(k),
+                QARollTiming::Close => Some(k - 1),
+                QARollTiming::DaysAfter(n) => Some(k + n).filter(|d| *d < trading_days.len()),
+            };
+            if let Some(d) = roll_day {
+                // Close rolls at the last slice of the day, the others at the first one
+                let index = match self.timing {
+                    QARollTiming::Close => days.iter().rposition(|x| *x == trading_days[d]),
+                    _ => days.iter().position(|x| *x == trading_days[d]),
+                };
+                if let Some(i) = index {
+                    switch_at.insert(i, main);
+                }
+            }
+        }
+
+        let mut current: Option<String> = trading_days.first().and_then(|d| self.map.get(d)).cloned();
+        let mut pending: Option<String> = None;
+        let mut clock = QASettleClock::new();
+        for (i, slice) in slices.iter().enumerate() {
+            clock.advance(&mut acc, &slice.datetime);
+            acc.change_datetime(slice.datetime.clone());
+            for bar in slice.bars.values() {
+                self.execution.mark(&mut acc, bar);
+            }
+
+            if let Some(to) = switch_at.get(&i) {
+                pending = Some(to.clone());
+            }
+            // Close rolls after the strategy has seen the last bar of the old contract
+            if self.timing != QARollTiming::Close {
+                self.roll_pending(&mut acc, slice, &mut current, &mut pending);
+            }
+            if let Some(bar) = current.as_ref().and_then(|c| slice.get(c)) {
+                self.execution.signal(strategy, &mut acc, bar);
+            }
+            if self.timing == QARollTiming::Close {
+                self.roll_pending(&mut acc, slice, &mut current, &mut pending);
+            }
+        }
+        clock.finish(&mut acc);
+        acc
+    }
+
+    fn roll_pending(
+        &mut self,
+        acc: &mut QA_Account,
+        slice: &QABarSlice,
+        current: &mut Option<String>,
+        pending: &mut Option<String>,
+    ) {
+        if let Some(to) = pending.clone() {
+            let done = match current.clone() {
+                Some(from) if from != to => self.roll(acc, slice, &from, &to),
+                _ => Some(true),
+            };
+            match done {
+                Some(true) => {
+                    *current = Some(to);
+                    *pending = None;
+                }
+                // a rejected roll is not retried, the strategy keeps the old contract and position
+                Some(false) => *pending = None,
+                None => {}
+            }
+        }
+    }
+
+    pub fn report(&self) -> QARollReport {
+        QARollReport {
+            continuous: self.map.continuous.clone(),
+            failed: self.rolls.iter().filter(|r| !r.completed).count(),
+            commission: self.rolls.iter().map(|r| r.commission).sum(),
+            slippage: self.rolls.iter().map(|r| r.slippage).sum(),
+            cost: self.rolls.iter().map(|r| r.cost).sum(),
+            rolls: self.rolls.clone(),
+        }
+    }
+}
+
+impl QARollReport {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qaexecution::{QABarView, QAExecutionContext};
+    use crate::test_helper::bar;
+
+    /// rb2010 takes over the volume on 2020-04-02, so it is the main contract from 2020-04-03,
+    /// it trades `spread` above rb2005
+    fn bars_with_spread(spread: f64) -> Vec<BAR> {
+        let days = ["2020-03-31", "2020-04-01", "2020-04-02", "2020-04-03", "2020-04-07"];
+        let mut bars = vec![];
+        for (i, day) in days.iter().enumerate() {
+            let (v05, v10) = if i < 2 { (1000.0, 300.0) } else { (200.0, 1200.0) };
+            for (j, time) in ["09:00:00", "14:00:00"].iter().enumerate() {
+                let p = 3500.0 + 10.0 * (i * 2 + j) as f64;
+                let datetime = format!("{} {}", day, time);
+                bars.push(bar("rb2005", &datetime, p + 2.0).open(p).volume(v05).build());
+                let q = p + spread;
+                bars.push(bar("rb2010", &datetime, q + 2.0).open(q).volume(v10).build());
+            }
+        }
+        bars
+    }
+
+    fn bars() -> Vec<BAR> {
+        bars_with_spread(50.0)
+    }
+
+    /// buy 2 lots of the main contract once
+    struct Hold {
+        seen: Vec<String>,
+    }
+
+    impl QAStrategy for Hold {
+        fn on_bar(&mut self, ctx: &mut QAExecutionContext, bar: &QABarView) {
+            if self.seen.is_empty() {
+                ctx.buy_open(bar.code(), 2.0, bar.close());
+            }
+            self.seen.push(bar.code().to_string());
+        }
+    }
+
+    #[test]
+    fn test_main_contract_map() {
+        assert_eq!(product("RBL8"), "RB");
+        assert_eq!(product("rb2005"), "RB");
+        assert_eq!(delivery_month("rb2005", "2020-01-02"), Some(202005));
+        assert_eq!(delivery_month("SR001", "2019-09-02"), Some(202001));
+        assert_eq!(delivery_month("SR909", "2019-09-02"), Some(201909));
+        assert_eq!(delivery_month("RBL8", "2019-09-02"), None);
+        let mut other = bars();
+        other.push(bar("j2005", "2020-04-01 09:00:00", 1800.0).volume(100000.0).build());
+        let map = QAMainContractMap::from_bars("RBL8", &other);
+        assert_eq!(map.days.len(), 5);
+        assert_eq!(map.get("2020-04-02").unwrap(), "rb2005");
+        assert_eq!(map.get("2020-04-03").unwrap(), "rb2010");
+        assert_eq!(map.get("2020-04-05").unwrap(), "rb2010");
+        assert_eq!(
+            map.switches(),
+            vec![("2020-04-03".to_string(), "rb2005".to_string(), "rb2010".to_string())]
+        );
+
+        // CZCE across a year boundary, SR001 < SR909 as strings
+        let czce: Vec<(String, String, f64)> = [
+            ("2019-08-01 09:00:00", "SR909", 1000.0),
+            ("2019-08-01 09:00:00", "SR001", 500.0),
+            ("2019-08-02 09:00:00", "SR909", 400.0),
+            ("2019-08-02 09:00:00", "SR001", 900.0),
+            ("2019-08-05 09:00:00", "SR909", 300.0),
+            ("2019-08-05 09:00:00", "SR001", 900.0),
+        ]
+        .iter()
+        .map(|(datetime, code, volume)| (datetime.to_string(), code.to_string(), *volume))
+        .collect();
+        let map = QAMainContractMap::from_weights("SRL8", &czce);
+        assert_eq!(map.get("2019-08-02").unwrap(), "SR909");
+        assert_eq!(map.get("2019-08-05").unwrap(), "SR001");
+    }
+
+    #[test]
+    fn test_roll() {
+        let map = QAMainContractMap::from_bars("RBL8", &bars());
+        let mut engine = QARollEngine::new(map.clone(), QARollTiming::Open).with_slippage(1.0);
+        let mut strategy = Hold { seen: vec![] };
+        let mut acc = engine.run(&mut strategy, QABarFeed::from_sources(vec![bars()]), "roll", 1000000.0);
+        assert_eq!(strategy.seen[5], "rb2005");
+        assert_eq!(strategy.seen[6], "rb2010");
+        assert_eq!(acc.get_position("rb2005").unwrap().volume_long(), 0.0);
+        assert_eq!(acc.get_position("rb2010").unwrap().volume_long(), 2.0);
+        let report = engine.report();
+        println!("{}", report.to_json());
+        assert_eq!(report.rolls.len(), 1);
+        let roll = &report.rolls[0];
+        assert_eq!(roll.datetime, "2020-04-03 09:00:00");
+        assert_eq!((roll.from_price, roll.to_price), (3560.0, 3610.0));
+        // 2 legs of 2 lots, 1 tick of 1 yuan on 10 tons
+        assert_eq!(roll.slippage, 40.0);
+        assert!(roll.completed);
+        assert_eq!(report.failed, 0);
+        assert!(roll.commission > 0.0);
+        assert_eq!(report.cost, roll.commission + roll.slippage);
+
+        let mut engine = QARollEngine::new(map.clone(), QARollTiming::Close);
+        engine.run(&mut Hold { seen: vec![] }, QABarFeed::from_sources(vec![bars()]), "roll", 1000000.0);
+        assert_eq!(engine.rolls[0].datetime, "2020-04-02 14:00:00");
+        assert_eq!(engine.rolls[0].from_price, 3552.0);
+
+        let mut engine = QARollEngine::new(map, QARollTiming::DaysAfter(1));
+        engine.run(&mut Hold { seen: vec![] }, QABarFeed::from_sources(vec![bars()]), "roll", 1000000.0);
+        assert_eq!(engine.rolls[0].datetime, "2020-04-07 09:00:00");
+    }
+
+    #[test]
+    fn test_roll_rejected() {
+        // 2 lots of rb2010 need more margin than the account holds
+        let bars = bars_with_spread(5000.0);
+        let map = QAMainContractMap::from_bars("RBL8", &bars);
+        let mut engine = QARollEngine::new(map, QARollTiming::Open).with_slippage(1.0);
+        let mut strategy = Hold { seen: vec![] };
+        let mut acc = engine.run(&mut strategy, QABarFeed::from_sources(vec![bars]), "roll", 10000.0);
+        assert!(strategy.seen.iter().all(|code| code == "rb2005"));
+        assert_eq!(strategy.seen.len(), 10);
+        // the close of rb2005 was reversed, the long is held again
+        assert_eq!(acc.get_position("rb2005").unwrap().volume_long(), 2.0);
+        assert!(acc.get_position("rb2010").map_or(true, |p| p.volume_long() == 0.0));
+        let report = engine.report();
+        assert_eq!(report.failed, 1);
+        let roll = &report.rolls[0];
+        assert!(!roll.completed);
+        assert_eq!(roll.message, "rb2010 2 2 rejected");
+        // the close of rb2005 and the open back, 2 lots each
+        assert_eq!(roll.slippage, 40.0);
+        assert_eq!(acc.history.len(), 3);
+    }
+}
@@ -0,0 +1,629 @@
+//! rolling metrics over the last n trading days, fed with one daily return at a time
+//!
+//! 与 indicators 相同的流式接口, 回测和实盘结算后都可以逐日调用 next
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::*;
+use crate::qaaccount::QA_Account;
+use crate::qaperformance::{QAPnlMode, QATradePair};
+use crate::qarisk::{QABenchmark, QAEquityCurve};
+use crate::trade_date::QATradeDate;
+use crate::{Next, Reset};
+
+/// Annualized volatility of the last n daily returns.
+#[derive(Debug, Clone)]
+pub struct RollingVolatility {
+    n: u32,
+    days_per_year: f64,
+    window: VecDeque<f64>,
+    sum: f64,
+    sum2: f64,
+}
+
+impl RollingVolatility {
+    pub fn new(n: u32, days_per_year: f64) -> Result<Self> {
+        match n {
+            0 => Err(Error::from_kind(ErrorKind::InvalidParameter)),
+            _ => Ok(RollingVolatility {
+                n,
+                days_per_year,
+                window: VecDeque::with_capacity(n as usize),
+                sum: 0.0,
+                sum2: 0.0,
+            }),
+        }
+    }
+
+    fn push(&mut self, input: f64) {
+        if self.window.len() == self.n as usize {
+            let old = self.window.pop_front().unwrap();
+            self.sum -= old;
+            self.sum2 -= old * old;
+        }
+        self.window.push_back(input);
+        self.sum += input;
+        self.sum2 += input * input;
+    }
+
+    fn mean(&self) -> f64 {
+        if self.window.is_empty() {
+            0.0
+        } else {
+            self.sum / self.window.len() as f64
+        }
+    }
+
+    /// sample standard deviation of the window, not annualized
+    fn std(&self) -> f64 {
+        let k = self.window.len() as f64;
+        if k < 2.0 {
+            return 0.0;
+        }
+        ((self.sum2 - self.sum * self.sum / k) / (k - 1.0)).max(0.0).sqrt()
+    }
+}
+
+impl Next<f64> for RollingVolatility {
+    type Output = f64;
+
+    fn next(&mut self, input: f64) -> Self::Output {
+        self.push(input);
+        self.std() * self.days_per_year.sqrt()
+    }
+}
+
+impl Reset for RollingVolatility {
+    fn reset(&mut self) {
+        self.window.clear();
+        self.sum = 0.0;
+        self.sum2 = 0.0;
+    }
+}
+
+impl Default for RollingVolatility {
+    fn default() -> Self {
+        Self::new(20, 250.0).unwrap()
+    }
+}
+
+impl fmt::Display for RollingVolatility {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "VOL({})", self.n)
+    }
+}
+
+/// Annualized Sharpe ratio of the last n daily returns, same formula as `QAEquityCurve::sharpe`.
+#[derive(Debug, Clone)]
+pub struct RollingSharpe {
+    risk_free: f64,
+    volatility: RollingVolatility,
+}
+
+impl RollingSharpe {
+    /// risk_free is the annual risk free rate
+    pub fn new(n: u32, risk_free: f64, days_per_year: f64) -> Result<Self> {
+        Ok(RollingSharpe {
+            risk_free,
+            volatility: RollingVolatility::new(n, days_per_year)?,
+        })
+    }
+}
+
+impl Next<f64> for RollingSharpe {
+    type Output = f64;
+
+    fn next(&mut self, input: f64) -> Self::Output {
+        self.volatility.push(input);
+        let n = self.volatility.days_per_year;
+        let sd = self.volatility.std();
+        if sd > 0.0 {
+            (self.volatility.mean() - self.risk_free / n) / sd * n.sqrt()
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Reset for RollingSharpe {
+    fn reset(&mut self) {
+        self.volatility.reset();
+    }
+}
+
+impl Default for RollingSharpe {
+    fn default() -> Self {
+        Self::new(20, 0.0, 250.0).unwrap()
+    }
+}
+
+impl fmt::Display for RollingSharpe {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SHARPE({})", self.volatility.n)
+    }
+}
+
+/// Maximum drawdown of the nav compounded from the last n daily returns, <= 0.0.
+#[derive(Debug, Clone)]
+pub struct RollingDrawdown {
+    n: u32,
+    window: VecDeque<f64>,
+}
+
+impl RollingDrawdown {
+    pub fn new(n: u32) -> Result<Self> {
+        match n {
+            0 => Err(Error::from_kind(ErrorKind::InvalidParameter)),
+            _ => Ok(RollingDrawdown {
+                n,
+                window: VecDeque::with_capacity(n as usize),
+            }),
+        }
+    }
+}
+
+impl Next<f64> for RollingDrawdown {
+    type Output = f64;
+
+    fn next(&mut self, input: f64) -> Self::Output {
+        if self.window.len() == self.n as usize {
+            self.window.pop_front();
+        }
+        self.window.push_back(input);
+        let mut nav = 1.0f64;
+        let mut peak = 1.0f64;
+        let mut drawdown = 0.0f64;
+        for r in self.window.iter() {
+            nav *= 1.0 + r;
+            peak = peak.max(nav);
+            drawdown = drawdown.min(nav / peak - 1.0);
+        }
+        drawdown
+    }
+}
+
+impl Reset for RollingDrawdown {
+    fn reset(&mut self) {
+        self.window.clear();
+    }
+}
+
+impl Default for RollingDrawdown {
+    fn default() -> Self {
+        Self::new(20).unwrap()
+    }
+}
+
+impl fmt::Display for RollingDrawdown {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "DD({})", self.n)
+    }
+}
+
+/// Win rate of the trade pairs closed in the last n trading days, input is the pnl of the pairs closed today.
+#[derive(Debug, Clone)]
+pub struct RollingWinRate {
+    n: u32,
+    window: VecDeque<(usize, usize)>,
+    wins: usize,
+    count: usize,
+}
+
+impl RollingWinRate {
+    pub fn new(n: u32) -> Result<Self> {
+        match n {
+            0 => Err(Error::from_kind(ErrorKind::InvalidParameter)),
+            _ => Ok(RollingWinRate {
+                n,
+                window: VecDeque::with_capacity(n as usize),
+                wins: 0,
+                count: 0,
+            }),
+        }
+    }
+}
+
+impl<'a> Next<&'a [f64]> for RollingWinRate {
+    type Output = f64;
+
+    fn next(&mut self, input: &'a [f64]) -> Self::Output {
+        if self.window.len() == self.n as usize {
+            let (wins, count) = self.window.pop_front().unwrap();
+            self.wins -= wins;
+            self.count -= count;
+        }
+        let wins = input.iter().filter(|pnl| **pnl > 0.0).count();
+        self.window.push_back((wins, input.len()));
+        self.wins += wins;
+        self.count += input.len();
+        if self.count > 0 {
+            self.wins as f64 / self.count as f64
+        } else {
+            0.0
+        }
+    }
+}
+
+impl Reset for RollingWinRate {
+    fn reset(&mut self) {
+        self.window.clear();
+        self.wins = 0;
+        self.count = 0;
+    }
+}
+
+impl Default for RollingWinRate {
+    fn default() -> Self {
+        Self::new(20).unwrap()
+    }
+}
+
+impl fmt::Display for RollingWinRate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WINRATE({})", self.n)
+    }
+}
+
+/// Beta to a benchmark over the last n days, input is (strategy return, benchmark return).
+#[derive(Debug, Clone)]
+pub struct RollingBeta {
+    n: u32,
+    window: VecDeque<(f64, f64)>,
+    sum_x: f64,
+    sum_y: f64,
+    sum_xy: f64,
+    sum_yy: f64,
+}
+
+impl RollingBeta {
+    pub fn new(n: u32) -> Result<Self> {
+        match n {
+            0 => Err(Error::from_kind(ErrorKind::InvalidParameter)),
+            _ => Ok(RollingBeta {
+                n,
+                window: VecDeque::with_capacity(n as usize),
+                sum_x: 0.0,
+                sum_y: 0.0,
+                sum_xy: 0.0,
+                sum_yy: 0.0,
+            }),
+        }
+    }
+}
+
+impl Next<(f64, f64)> for RollingBeta {
+    type Output = f64;
+
+    fn next(&mut self, input: (f64, f64)) -> Self::Output {
+        if self.window.len() == self.n as usize {
+            let (x, y) = self.window.pop_front().unwrap();
+            self.sum_x -= x;
+            self.sum_y -= y;
+            self.sum_xy -= x * y;
+            self.sum_yy -= y * y;
+        }
+        let (x, y) = input;
+        self.window.push_back(input);
+        self.sum_x += x;
+        self.sum_y += y;
+        self.sum_xy += x * y;
+        self.sum_yy += y * y;
+
+        let k = self.window.len() as f64;
+        let var = self.sum_yy - self.sum_y * self.sum_y / k;
+        if k < 2.0 || var <= 1e-18 {
+            0.0
+        } else {
+            (self.sum_xy - self.sum_x * self.sum_y / k) / var
+        }
+    }
+}
+
+impl Reset for RollingBeta {
+    fn reset(&mut self) {
+        self.window.clear();
+        self.sum_x = 0.0;
+        self.sum_y = 0.0;
+        self.sum_xy = 0.0;
+        self.sum_yy = 0.0;
+    }
+}
+
+impl Default for RollingBeta {
+    fn default() -> Self {
+        Self::new(20).unwrap()
+    }
+}
+
+impl fmt::Display for RollingBeta {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "BETA({})", self.n)
+    }
+}
+
+/// one trading day fed into QARollingMetrics
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QARollingInput {
+    pub date: String,
+    pub daily_return: f64,
+    /// None if the benchmark has no price on this day, beta is kept unchanged
+    pub benchmark_return: Option<f64>,
+    /// pnl of the trade pairs closed on this day
+    pub pair_pnls: Vec<f64>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QARollingPoint {
+    pub date: String,
+    pub sharpe: f64,
+    pub volatility: f64,
+    pub drawdown: f64,
+    pub win_rate: f64,
+    pub beta: f64,
+}
+
+/// 滚动窗口绩效: sharpe/波动率/回撤/胜率/beta
+#[derive(Debug, Clone)]
+pub struct QARollingMetrics {
+    pub window: u32,
+    /// gross or net pnl of the pairs for the win rate
+    pub pnl_mode: QAPnlMode,
+    sharpe: RollingSharpe,
+    volatility: RollingVolatility,
+    drawdown: RollingDrawdown,
+    win_rate: RollingWinRate,
+    beta: RollingBeta,
+    last_beta: f64,
+    /// the last settled trading day and balance, used by on_settle
+    last_date: String,
+    last_balance: Option<f64>,
+}
+
+impl QARollingMetrics {
+    pub fn new(window: u32, risk_free: f64, days_per_year: f64) -> Result<Self> {
+        Ok(QARollingMetrics {
+            window,
+            pnl_mode: QAPnlMode::Gross,
+            sharpe: RollingSharpe::new(window, risk_free, days_per_year)?,
+            volatility: RollingVolatility::new(window, days_per_year)?,
+            drawdown: RollingDrawdown::new(window)?,
+            win_rate: RollingWinRate::new(window)?,
+            beta: RollingBeta::new(window)?,
+            last_beta: 0.0,
+            last_date: "".to_string(),
+            last_balance: None,
+        })
+    }
+
+    /// use the same mode as the QAPerformance the pairs come from
+    pub fn set_pnl_mode(&mut self, mode: QAPnlMode) {
+        self.pnl_mode = mode;
+    }
+
+    /// rolling metrics of every day of the curve, the pairs are grouped by the trading day they are closed
+    pub fn over_curve(
+        &mut self,
+        curve: &QAEquityCurve,
+        benchmark: Option<&QABenchmark>,
+        pairs: &[QATradePair],
+    ) -> Vec<QARollingPoint> {
+        let benchmark_returns = benchmark.map(|b| b.returns()).unwrap_or_default();
+        let pnls = pair_pnls_by_day(pairs, self.pnl_mode);
+        curve
+            .points
+            .iter()
+            .map(|p| {
+                self.next(&QARollingInput {
+                    date: p.date.clone(),
+                    daily_return: p.daily_return,
+                    benchmark_return: benchmark_returns.get(&p.date).cloned(),
+                    pair_pnls: pnls.get(&p.date).cloned().unwrap_or_default(),
+                })
+            })
+            .collect()
+    }
+
+    /// 实盘: 在 acc.settle() 之后调用, 用最新的结算切片更新窗口
+    ///
+    /// pairs can be all the pairs of the account, only the ones closed on the settled day are used.
+    /// Returns None if there is no new trading day since the last call.
+    pub fn on_settle(
+        &mut self,
+        acc: &QA_Account,
+        benchmark_return: Option<f64>,
+        pairs: &[QATradePair],
+    ) -> Option<QARollingPoint> {
+        let slice = acc.dailyassets.values().max_by(|a, b| a.datetime.cmp(&b.datetime))?;
+        let date = QATradeDate::new().get_trade_day(slice.datetime.clone());
+        if date <= self.last_date {
+            return None;
+        }
+        let last = self.last_balance.unwrap_or(slice.accounts.pre_balance);
+        let flow = slice.accounts.deposit - slice.accounts.withdraw;
+        let daily_return = if last > 0.0 {
+            (slice.accounts.balance - flow - last) / last
+        } else {
+            0.0
+        };
+        self.last_balance = Some(slice.accounts.balance);
+        self.last_date = date.clone();
+        let pair_pnls = pair_pnls_by_day(pairs, self.pnl_mode).remove(&date).unwrap_or_default();
+        Some(self.next(&QARollingInput {
+            date,
+            daily_return,
+            benchmark_return,
+            pair_pnls,
+        }))
+    }
+}
+
+fn pair_pnls_by_day(pairs: &[QATradePair], mode: QAPnlMode) -> HashMap<String, Vec<f64>> {
+    let mut td = QATradeDate::new();
+    let mut res: HashMap<String, Vec<f64>> = HashMap::new();
+    for pair in pairs {
+        res.entry(td.get_trade_day(pair.closedate.clone()))
+            .or_default()
+            .push(pair.pnl(mode));
+    }
+    res
+}
+
+impl<'a> Next<&'a QARollingInput> for QARollingMetrics {
+    type Output = QARollingPoint;
+
+    fn next(&mut self, input: &'a QARollingInput) -> Self::Output {
+        if let Some(b) = input.benchmark_return {
+            self.last_beta = self.beta.next((input.daily_return, b));
+        }
+        QARollingPoint {
+            date: input.date.clone(),
+            sharpe: self.sharpe.next(input.daily_return),
+            volatility: self.volatility.next(input.daily_return),
+            drawdown: self.drawdown.next(input.daily_return),
+            win_rate: self.win_rate.next(&input.pair_pnls[..]),
+            beta: self.last_beta,
+        }
+    }
+}
+
+impl Reset for QARollingMetrics {
+    fn reset(&mut self) {
+        self.sharpe.reset();
+        self.volatility.reset();
+        self.drawdown.reset();
+        self.win_rate.reset();
+        self.beta.reset();
+        self.last_beta = 0.0;
+        self.last_date = "".to_string();
+        self.last_balance = None;
+    }
+}
+
+impl fmt::Display for QARollingMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ROLLING({})", self.window)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qaperformance::QAPerformance;
+    use crate::qarisk::{cov, std};
+
+    const RETURNS: [f64; 8] = [0.01, -0.02, 0.015, 0.003, -0.01, 0.02, -0.005, 0.012];
+
+    #[test]
+    fn test_new() {
+        assert!(RollingVolatility::new(0, 250.0).is_err());
+        assert!(RollingDrawdown::new(0).is_err());
+        assert!(QARollingMetrics::new(0, 0.0, 250.0).is_err());
+        assert_eq!(format!("{}", RollingSharpe::default()), "SHARPE(20)");
+    }
+
+    #[test]
+    fn test_matches_batch() {
+        let bench: Vec<f64> = RETURNS.iter().map(|r| r * 0.5 + 0.001).collect();
+        let mut vol = RollingVolatility::new(4, 250.0).unwrap();
+        let mut sharpe = RollingSharpe::new(4, 0.0, 250.0).unwrap();
+        let mut beta = RollingBeta::new(4).unwrap();
+        for i in 0..RETURNS.len() {
+            let v = vol.next(RETURNS[i]);
+            let s = sharpe.next(RETURNS[i]);
+            let b = beta.next((RETURNS[i], bench[i]));
+            if i >= 3 {
+                let w = &RETURNS[i - 3..=i];
+                let bw = &bench[i - 3..=i];
+                assert!((v - std(w) * 250f64.sqrt()).abs() < 1e-9);
+                let m = w.iter().sum::<f64>() / 4.0;
+                assert!((s - m / std(w) * 250f64.sqrt()).abs() < 1e-9);
+                assert!((b - cov(w, bw) / cov(bw, bw)).abs() < 1e-9);
+                assert!((b - 2.0).abs() < 1e-9);
+            }
+        }
+        vol.reset();
+        assert_eq!(vol.next(0.01), 0.0);
+    }
+
+    #[test]
+    fn test_drawdown_and_win_rate() {
+        let mut dd = RollingDrawdown::new(3).unwrap();
+        assert_eq!(dd.next(0.1), 0.0);
+        assert!((dd.next(-0.1) + 0.1).abs() < 1e-12);
+        assert!((dd.next(-0.1) + 0.19).abs() < 1e-12);
+        // the +10% day left the window, the drawdown starts from the window's own nav
+        assert!((dd.next(0.5) + 0.19).abs() < 1e-12);
+        assert!((dd.next(0.0) + 0.1).abs() < 1e-12);
+
+        let mut wr = RollingWinRate::new(2).unwrap();
+        assert_eq!(wr.next(&[1.0, -1.0][..]), 0.5);
+        assert_eq!(wr.next(&[][..]), 0.5);
+        assert_eq!(wr.next(&[2.0][..]), 1.0);
+        assert_eq!(wr.next(&[][..]), 1.0);
+        assert_eq!(wr.next(&[][..]), 0.0);
+    }
+
+    #[test]
+    fn test_on_settle() {
+        let mut acc = QA_Account::new("RustT01B2_RBL8", "test", "admin", 1000000.0, false, "backtest");
+        let mut rolling = QARollingMetrics::new(3, 0.0, 250.0).unwrap();
+        let mut live = vec![];
+        let code = "rb2005";
+        acc.buy_open(code, 10.0, "2020-03-31 09:30:00", 3500.0);
+        acc.on_price_change(code.to_string(), 3520.0, "2020-03-31 15:00:00".to_string());
+        acc.settle();
+        live.push(rolling.on_settle(&acc, Some(0.01), &[]).unwrap());
+        assert!(rolling.on_settle(&acc, Some(0.01), &[]).is_none());
+        acc.sell_close(code, 10.0, "2020-04-01 10:00:00", 3480.0);
+        acc.settle();
+        live.push(rolling.on_settle(&acc, Some(-0.01), &[]).unwrap());
+        acc.buy_open(code, 10.0, "2020-04-02 10:00:00", 3480.0);
+        acc.on_price_change(code.to_string(), 3490.0, "2020-04-02 15:00:00".to_string());
+        acc.settle();
+        live.push(rolling.on_settle(&acc, Some(0.005), &[]).unwrap());
+
+        let curve = QAEquityCurve::from_account(&acc);
+        let benchmark = QABenchmark::new(
+            "bench",
+            &["2020-03-30", "2020-03-31", "2020-04-01", "2020-04-02"]
+                .iter()
+                .map(|d| d.to_string())
+                .collect::<Vec<String>>(),
+            &[100.0, 101.0, 99.99, 100.48995],
+        );
+        let batch = QARollingMetrics::new(3, 0.0, 250.0)
+            .unwrap()
+            .over_curve(&curve, Some(&benchmark), &[]);
+        assert_eq!(batch.len(), 3);
+        for (a, b) in live.iter().zip(batch.iter()) {
+            assert_eq!(a.date, b.date);
+            assert!((a.sharpe - b.sharpe).abs() < 1e-9);
+            assert!((a.drawdown - b.drawdown).abs() < 1e-9);
+            assert!((a.beta - b.beta).abs() < 1e-6);
+        }
+        assert!(batch[1].drawdown < 0.0);
+    }
+
+    #[test]
+    fn test_win_rate_pnl_mode() {
+        let mut acc = QA_Account::new("RustT01B2_RBL8", "test", "admin", 1000000.0, false, "backtest");
+        let code = "rb2005";
+        // half a tick on 10 lots is less than the commission
+        acc.buy_open(code, 10.0, "2020-04-01 10:00:00", 3500.0);
+        acc.sell_close(code, 10.0, "2020-04-01 14:00:00", 3500.5);
+        acc.settle();
+        let pairs = QAPerformance::from_account(&mut acc).pair();
+        assert_eq!(pairs.len(), 1);
+        assert!(pairs[0].pnl_money > 0.0 && pairs[0].net_pnl_money < 0.0);
+
+        let curve = QAEquityCurve::from_account(&acc);
+        let mut rolling = QARollingMetrics::new(3, 0.0, 250.0).unwrap();
+        assert_eq!(rolling.over_curve(&curve, None, &pairs)[0].win_rate, 1.0);
+        let mut rolling = QARollingMetrics::new(3, 0.0, 250.0).unwrap();
+        rolling.set_pnl_mode(QAPnlMode::Net);
+        assert_eq!(rolling.over_curve(&curve, None, &pairs)[0].win_rate, 0.0);
+    }
+}
@@ -0,0 +1,294 @@
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::*;
+use crate::qafetch::BAR;
+use crate::qaexecution::QAStrategy;
+use crate::qaoptimizer::{run_backtest, QAOptimizer, QAParams};
+use crate::qaperformance::QARiskMessage;
+use crate::qarisk::{mean, std, QAEquityCurve, QARiskReport};
+use crate::trade_date::QATradeDate;
+
+/// one in-sample optimization followed by an out-of-sample run of the best parameters
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QAWalkForwardWindow {
+    pub index: usize,
+    pub in_sample_start: String,
+    pub in_sample_end: String,
+    pub out_of_sample_start: String,
+    pub out_of_sample_end: String,
+    pub params: QAParams,
+    pub in_sample_score: f64,
+    pub out_of_sample_score: f64,
+    pub out_of_sample_risk: QARiskReport,
+    pub out_of_sample_message: QARiskMessage,
+}
+
+/// how much the chosen value of one parameter moves across the windows
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct QAParamStability {
+    /// the best value of every window
+    pub values: Vec<f64>,
+    pub mean: f64,
+    pub std: f64,
+    pub min: f64,
+    pub max: f64,
+    /// std / |mean|, 0 if the mean is 0
+    pub cv: f64,
+    /// times the value differs from the previous window
+    pub changes: usize,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct QAWalkForwardReport {
+    pub windows: Vec<QAWalkForwardWindow>,
+    /// out-of-sample daily returns chained together, starting from init_cash, every day once
+    pub curve: QAEquityCurve,
+    pub risk: QARiskReport,
+    pub stability: BTreeMap<String, QAParamStability>,
+    /// mean out-of-sample score / mean in-sample score, the windows have different lengths
+    /// so it is only meaningful for metrics that do not grow with time (sharpe, win rate ...)
+    pub efficiency: f64,
+}
+
+/// 滚动样本内优化/样本外检验, 窗口按交易日划分
+#[derive(Debug, Clone)]
+pub struct QAWalkForward {
+    pub optimizer: QAOptimizer,
+    /// trading days
+    pub in_sample: usize,
+    pub out_of_sample: usize,
+    /// trading days between two windows, defaults to out_of_sample so the
+    /// out-of-sample windows are back to back
+    pub step: usize,
+    /// keep the first day of the in-sample window, the in-sample window grows by step
+    pub anchored: bool,
+}
+
+impl QAParamStability {
+    pub fn from_values(values: Vec<f64>) -> Self {
+        if values.is_empty() {
+            return QAParamStability::default();
+        }
+        let m = mean(&values);
+        let s = std(&values);
+        QAParamStability {
+            mean: m,
+            std: s,
+            min: values.iter().cloned().fold(std::f64::INFINITY, f64::min),
+            max: values.iter().cloned().fold(std::f64::NEG_INFINITY, f64::max),
+            cv: if m != 0.0 { s / m.abs() } else { 0.0 },
+            changes: values.windows(2).filter(|w| w[0] != w[1]).count(),
+            values,
+        }
+    }
+}
+
+impl QAWalkForward {
+    pub fn new(optimizer: QAOptimizer, in_sample: usize, out_of_sample: usize) -> Result<Self> {
+        if in_sample == 0 || out_of_sample == 0 {
+            return Err(Error::from_kind(ErrorKind::InvalidParameter));
+        }
+        Ok(QAWalkForward {
+            optimizer,
+            in_sample,
+            out_of_sample,
+            step: out_of_sample,
+            anchored: false,
+        })
+    }
+
+    pub fn with_step(mut self, step: usize) -> Result<Self> {
+        if step == 0 {
+            return Err(Error::from_kind(ErrorKind::InvalidParameter));
+        }
+        self.step = step;
+        Ok(self)
+    }
+
+    pub fn with_anchored(mut self, anchored: bool) -> Self {
+        self.anchored = anchored;
+        self
+    }
+
+    /// (in-sample start, out-of-sample start, out-of-sample end) as trading day indexes,
+    /// the last out-of-sample window may be shorter
+    pub fn windows(&self, days: usize) -> Vec<(usize, usize, usize)> {
+        let mut res = vec![];
+        let mut k = 0;
+        loop {
+            let start = if self.anchored { 0 } else { k * self.step };
+            let split = if self.anchored {
+                self.in_sample + k * self.step
+            } else {
+                start + self.in_sample
+            };
+            if split >= days {
+                break;
+            }
+            res.push((start, split, (split + self.out_of_sample).min(days)));
+            k += 1;
+        }
+        res
+    }
+
+    /// bars must be sorted by datetime, every out-of-sample run starts flat from init_cash
+    pub fn run<F, S>(&self, bars: &[BAR], factory: F) -> QAWalkForwardReport
+    where
+        F: Fn(&QAParams) -> S + Sync,
+        S: QAStrategy,
+    {
+        let mut td = QATradeDate::new();
+        let mut days: Vec<String> = vec![];
+        // trading day index of every bar
+        let mut day_index: Vec<usize> = Vec::with_capacity(bars.len());
+        for bar in bars {
+            let day = td.get_trade_day(bar.datetime.clone());
+            if days.last() != Some(&day) {
+                days.push(day);
+            }
+            day_index.push(days.len() - 1);
+        }
+        let range = |from: usize, to: usize| -> &[BAR] {
+            // first bar on or after the day, the index is sorted
+            let first = |day: usize| {
+                day_index
+                    .binary_search_by(|d| if *d < day { Ordering::Less } else { Ordering::Greater })
+                    .unwrap_err()
+            };
+            let a = first(from);
+            let b = first(to);
+            &bars[a..b]
+        };
+
+        let init_cash = self.optimizer.init_cash;
+        let mut windows = vec![];
+        let mut dates = vec![];
+        let mut balances = vec![];
+        let mut balance = init_cash;
+        for (i, (start, split, end)) in self.windows(days.len()).into_iter().enumerate() {
+            let optimized = self.optimizer.run(range(start, split), &factory);
+            let best = match optimized.best() {
+                Some(best) => best.clone(),
+                None => break,
+            };
+            let mut strategy = factory(&best.params);
+            let mut acc = run_backtest(
+                &mut strategy,
+                range(split, end),
+                "walkforward",
+                init_cash,
+                self.optimizer.execution,
+            );
+            for point in QAEquityCurve::from_account(&acc).points {
+                // with step < out_of_sample the windows overlap, the days already
+                // stitched from the previous window are kept
+                if dates.last().map_or(false, |last| point.date <= *last) {
+                    continue;
+                }
+                balance *= 1.0 + point.daily_return;
+                dates.push(point.date);
+                balances.push(balance);
+            }
+            let oos = self.optimizer.score_account(best.params.clone(), &mut acc);
+            windows.push(QAWalkForwardWindow {
+                index: i,
+                in_sample_start: days[start].clone(),
+                in_sample_end: days[split - 1].clone(),
+                out_of_sample_start: days[split].clone(),
+                out_of_sample_end: days[end - 1].clone(),
+                params: best.params,
+                in_sample_score: best.score,
+                out_of_sample_score: oos.score,
+                out_of_sample_risk: oos.risk,
+                out_of_sample_message: oos.message,
+            });
+        }
+
+        let curve = QAEquityCurve::from_balances(init_cash, &dates, &balances, &vec![0.0; dates.len()]);
+        let mut stability = BTreeMap::new();
+        for name in self.optimizer.space.params.keys() {
+            let values = windows.iter().filter_map(|w| w.params.get(name).cloned()).collect();
+            stability.insert(name.clone(), QAParamStability::from_values(values));
+        }
+        let in_sample = mean(&windows.iter().map(|w| w.in_sample_score).collect::<Vec<f64>>());
+        let out_of_sample = mean(&windows.iter().map(|w| w.out_of_sample_score).collect::<Vec<f64>>());
+        QAWalkForwardReport {
+            risk: curve.risk_report(self.optimizer.risk_free),
+            curve,
+            windows,
+            stability,
+            efficiency: if in_sample != 0.0 { out_of_sample / in_sample } else { 0.0 },
+        }
+    }
+}
+
+impl QAWalkForwardReport {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qaoptimizer::{QAOptimizeMetric, QAParamSpace};
+    use crate::test_helper::{band, sine_bars};
+
+    fn optimizer() -> QAOptimizer {
+        let space = QAParamSpace::new()
+            .add_values("entry", &[3460.0, 3480.0])
+            .add_values("exit", &[3520.0, 3540.0]);
+        QAOptimizer::new(space, QAOptimizeMetric::TotalProfit)
+    }
+
+    #[test]
+    fn test_windows() {
+        assert!(QAWalkForward::new(optimizer(), 0, 10).is_err());
+        let wf = QAWalkForward::new(optimizer(), 20, 10).unwrap();
+        assert_eq!(wf.windows(55), vec![(0, 20, 30), (10, 30, 40), (20, 40, 50), (30, 50, 55)]);
+        let anchored = wf.clone().with_anchored(true);
+        assert_eq!(anchored.windows(40), vec![(0, 20, 30), (0, 30, 40)]);
+        let overlapping = wf.with_step(5).unwrap();
+        assert_eq!(overlapping.windows(30).len(), 2);
+    }
+
+    #[test]
+    fn test_walk_forward() {
+        let report = QAWalkForward::new(optimizer(), 20, 10)
+            .unwrap()
+            .run(&sine_bars(), band);
+        println!("{}", report.to_json());
+        assert_eq!(report.windows.len(), 4);
+        assert_eq!(report.curve.points.len(), 40);
+        for w in report.curve.points.windows(2) {
+            assert!(w[0].date < w[1].date);
+        }
+        assert_eq!(report.windows[1].out_of_sample_start, report.curve.points[10].date);
+        assert!(report.windows[0].in_sample_end < report.windows[0].out_of_sample_start);
+
+        let entry = &report.stability["entry"];
+        assert_eq!(entry.values.len(), 4);
+        assert!(entry.min >= 3460.0 && entry.max <= 3480.0);
+        assert!(entry.changes <= 3);
+    }
+
+    #[test]
+    fn test_walk_forward_overlapping() {
+        let report = QAWalkForward::new(optimizer(), 20, 10)
+            .unwrap()
+            .with_step(5)
+            .unwrap()
+            .run(&sine_bars(), band);
+        assert_eq!(report.windows.len(), 8);
+        // days 20..60 once each, though the windows cover most of them twice
+        assert_eq!(report.curve.points.len(), 40);
+        for w in report.curve.points.windows(2) {
+            assert!(w[0].date < w[1].date);
+        }
+        assert_eq!(report.curve.points[0].date, report.windows[0].out_of_sample_start);
+        assert_eq!(report.curve.points[39].date, report.windows[7].out_of_sample_end);
+    }
+}
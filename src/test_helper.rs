@@ -1,4 +1,8 @@
 use super::{Close, High, Low, Open, Volume};
+use crate::qaexecution::{QABarView, QAExecutionContext, QAStrategy};
+use crate::qafetch::BAR;
+use crate::qaoptimizer::QAParams;
+use crate::trade_date::QATradeDate;
 
 #[derive(Debug, PartialEq)]
 pub struct Bar {
@@ -76,6 +80,104 @@ impl Volume for Bar {
     }
 }
 
+/// qafetch::BAR for the backtest tests, flat at `price` with a volume of 100
+/// until the setters move it
+#[derive(Debug, Clone)]
+pub(crate) struct BarBuilder {
+    bar: BAR,
+}
+
+pub(crate) fn bar(code: &str, datetime: &str, price: f64) -> BarBuilder {
+    BarBuilder {
+        bar: BAR {
+            code: code.to_string(),
+            datetime: datetime.to_string(),
+            open: price,
+            high: price,
+            low: price,
+            close: price,
+            volume: 100.0,
+        },
+    }
+}
+
+impl BarBuilder {
+    /// high and low are widened to keep the open inside the bar
+    pub(crate) fn open(mut self, open: f64) -> Self {
+        self.bar.open = open;
+        self.widen(open)
+    }
+
+    /// high and low are widened to keep the close inside the bar
+    pub(crate) fn close(mut self, close: f64) -> Self {
+        self.bar.close = close;
+        self.widen(close)
+    }
+
+    pub(crate) fn high(mut self, high: f64) -> Self {
+        self.bar.high = high;
+        self
+    }
+
+    pub(crate) fn low(mut self, low: f64) -> Self {
+        self.bar.low = low;
+        self
+    }
+
+    pub(crate) fn volume(mut self, volume: f64) -> Self {
+        self.bar.volume = volume;
+        self
+    }
+
+    pub(crate) fn build(self) -> BAR {
+        self.bar
+    }
+
+    fn widen(mut self, price: f64) -> Self {
+        self.bar.high = self.bar.high.max(price);
+        self.bar.low = self.bar.low.min(price);
+        self
+    }
+}
+
+/// 60 daily bars of rb2005 on a sine wave around 3500
+pub(crate) fn sine_bars() -> Vec<BAR> {
+    let mut td = QATradeDate::new();
+    (0..60)
+        .map(|i| {
+            let close = 3500.0 + 50.0 * (i as f64 / 3.0).sin();
+            let datetime = format!("{} 14:00:00", td.get_next_n_day("2020-01-02", i));
+            bar("rb2005", &datetime, close).volume(1000.0).build()
+        })
+        .collect()
+}
+
+/// buy below entry, sell above exit
+pub(crate) struct Band {
+    pub(crate) entry: f64,
+    pub(crate) exit: f64,
+}
+
+impl QAStrategy for Band {
+    fn on_bar(&mut self, ctx: &mut QAExecutionContext, bar: &QABarView) {
+        let long = ctx.get_volume_long(bar.code());
+        let close = bar.close();
+        if long == 0.0 && close < self.entry {
+            ctx.buy_open(bar.code(), 1.0, close);
+        } else if long > 0.0 && close > self.exit {
+            ctx.sell_close(bar.code(), 1.0, close);
+        }
+    }
+}
+
+/// Band from the entry and exit parameters
+pub(crate) fn band(params: &QAParams) -> Band {
+    Band {
+        entry: params["entry"],
+        exit: params["exit"],
+    }
+}
+
 pub fn round(num: f64) -> f64 {
     (num * 1000.0).round() / 1000.00
 }
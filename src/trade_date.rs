@@ -1,10 +1,19 @@
 use std::borrow::Borrow;
 use std::ops::Index;
 
+use chrono::{Datelike, NaiveDate};
+
+#[derive(Debug, Clone)]
 pub struct QATradeDate {
     trade_date: Vec<i32>,
 }
 
+impl Default for QATradeDate {
+    fn default() -> Self {
+        QATradeDate::new()
+    }
+}
+
 impl QATradeDate {
     pub fn new() -> Self {
         let trade_date = vec![
@@ -1015,6 +1024,28 @@ impl QATradeDate {
         res = self.trade_date.get_mut(u - n as usize).unwrap().to_owned();
         self.to_string(res)
     }
+    /// 获取 [start, end] 区间内的交易日 (包含首尾)
+    pub fn get_trade_range(&mut self, start: &str, end: &str) -> Vec<String> {
+        let start = self.to_i32(start);
+        let end = self.to_i32(end);
+        let dates: Vec<i32> = self
+            .trade_date
+            .iter()
+            .filter(|x| **x >= start && **x <= end)
+            .cloned()
+            .collect();
+        dates.into_iter().map(|x| self.to_string(x)).collect()
+    }
+    /// 交易日所在的周, ISO 周, 如 "2020-W14"
+    pub fn get_trade_week(&mut self, datetime: &str) -> String {
+        let day = self.get_trade_day(datetime.to_string());
+        let week = NaiveDate::parse_from_str(&day, "%Y-%m-%d").unwrap().iso_week();
+        format!("{}-W{:02}", week.year(), week.week())
+    }
+    /// 交易日所在的月, 如 "2020-04"
+    pub fn get_trade_month(&mut self, datetime: &str) -> String {
+        self.get_trade_day(datetime.to_string())[0..7].to_string()
+    }
     pub fn get_trade_day(&mut self, datetime: String) -> String {
         if datetime.len() == 10 {
             if self.if_trade_date(&datetime) {
@@ -1099,6 +1130,27 @@ mod tests {
         assert_eq!(x, "2020-04-09".to_string());
     }
 
+    #[test]
+    fn test_get_trade_range() {
+        let mut u = QATradeDate::new();
+        let x = u.get_trade_range("2020-04-01", "2020-04-08");
+        println!("{:#?}", x);
+        assert_eq!(
+            x,
+            vec!["2020-04-01", "2020-04-02", "2020-04-03", "2020-04-07", "2020-04-08"]
+        );
+    }
+
+    #[test]
+    fn test_get_trade_week() {
+        let mut u = QATradeDate::new();
+        assert_eq!(u.get_trade_week("2020-04-03"), "2020-W14");
+        // 周五夜盘属于下周一
+        assert_eq!(u.get_trade_week("2020-04-03 21:00:00"), "2020-W15");
+        assert_eq!(u.get_trade_week("2019-12-31"), "2020-W01");
+        assert_eq!(u.get_trade_month("2020-04-30 21:00:00"), "2020-05");
+    }
+
     #[test]
     fn test_get_real_date() {
         let mut u = QATradeDate::new();